@@ -0,0 +1,269 @@
+//! Auditing of access decisions. `AuditedACL` wraps an engine and reports every decision it makes to an `AuditSink`.
+use futures::prelude::*;
+use std::cell::Cell;
+use std::fmt;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+use super::{AclEngine, ExplainedVerdict, Explanation, UnauthorizedError, Verdict};
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct AuditRecord {
+    pub timestamp: SystemTime,
+    /// Summary of the ACL context, e.g. caller and resource ids
+    pub context: String,
+    /// `None` if the engine has failed to make a decision
+    pub allowed: Option<bool>,
+    pub explanation: Option<Explanation>,
+    pub error: Option<String>,
+    pub latency: Duration,
+}
+
+impl fmt::Display for AuditRecord {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let latency_ms = self.latency.as_secs() * 1000 + u64::from(self.latency.subsec_millis());
+        match (self.allowed, &self.error) {
+            (Some(true), _) => write!(f, "Access allowed: {}", self.context)?,
+            (Some(false), _) => write!(f, "Access denied: {}", self.context)?,
+            (None, error) => write!(
+                f,
+                "Access check failed: {}. Error: {}",
+                self.context,
+                error.clone().unwrap_or_default()
+            )?,
+        };
+        if let Some(ref explanation) = self.explanation {
+            write!(f, ". Explanation: {}", explanation)?;
+        }
+        write!(f, ". Latency: {} ms", latency_ms)
+    }
+}
+
+/// Destination of audit records.
+pub trait AuditSink {
+    fn record(&self, record: AuditRecord);
+}
+
+impl<S> AuditSink for Rc<S>
+where
+    S: AuditSink + ?Sized,
+{
+    fn record(&self, record: AuditRecord) {
+        (**self).record(record)
+    }
+}
+
+impl<S> AuditSink for Arc<S>
+where
+    S: AuditSink + ?Sized,
+{
+    fn record(&self, record: AuditRecord) {
+        (**self).record(record)
+    }
+}
+
+/// Writes audit records to the log with `acl_audit` target.
+#[derive(Clone, Debug, Default)]
+pub struct LogAuditSink;
+
+impl AuditSink for LogAuditSink {
+    fn record(&self, record: AuditRecord) {
+        if record.allowed.is_some() {
+            info!(target: "acl_audit", "{}", record);
+        } else {
+            warn!(target: "acl_audit", "{}", record);
+        }
+    }
+}
+
+/// Keeps audit records in memory. Mostly useful in tests.
+#[derive(Clone, Debug, Default)]
+pub struct MemoryAuditSink(Arc<Mutex<Vec<AuditRecord>>>);
+
+impl MemoryAuditSink {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Removes and returns all collected records
+    pub fn take(&self) -> Vec<AuditRecord> {
+        self.0.lock().map(|mut records| records.drain(..).collect()).unwrap_or_default()
+    }
+}
+
+impl AuditSink for MemoryAuditSink {
+    fn record(&self, record: AuditRecord) {
+        if let Ok(mut records) = self.0.lock() {
+            records.push(record);
+        }
+    }
+}
+
+/// Tells which decisions are recorded. Every n-th decision of a kind is recorded, starting with the first one.
+/// 1 records every decision, 0 records none. Failures are always recorded.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Sampling {
+    pub allowed_every: usize,
+    pub denied_every: usize,
+}
+
+impl Default for Sampling {
+    fn default() -> Self {
+        Self {
+            allowed_every: 1,
+            denied_every: 1,
+        }
+    }
+}
+
+fn is_sampled(counter: &Cell<usize>, every: usize) -> bool {
+    let n = counter.get();
+    counter.set(n.wrapping_add(1));
+    n.checked_rem(every) == Some(0)
+}
+
+/// ACL engine that forwards to the inner engine and reports its decisions to the sink.
+pub struct AuditedACL<A, S, Context> {
+    inner: A,
+    sink: Rc<S>,
+    summary: Rc<Fn(&Context) -> String>,
+    sampling: Sampling,
+    allowed_counter: Rc<Cell<usize>>,
+    denied_counter: Rc<Cell<usize>>,
+}
+
+impl<A, S, Context> AuditedACL<A, S, Context>
+where
+    S: AuditSink,
+{
+    /// `summary` describes the context in audit records. It is only called for the decisions that are recorded.
+    pub fn new<F>(inner: A, sink: S, summary: F) -> Self
+    where
+        F: Fn(&Context) -> String + 'static,
+    {
+        Self {
+            inner,
+            sink: Rc::new(sink),
+            summary: Rc::new(summary),
+            sampling: Sampling::default(),
+            allowed_counter: Rc::new(Cell::new(0)),
+            denied_counter: Rc::new(Cell::new(0)),
+        }
+    }
+
+    pub fn with_sampling(mut self, sampling: Sampling) -> Self {
+        self.sampling = sampling;
+        self
+    }
+}
+
+impl<A, S, Context, Error> AclEngine<Context, Error> for AuditedACL<A, S, Context>
+where
+    A: AclEngine<Context, Error>,
+    S: AuditSink + 'static,
+    Context: 'static,
+    Error: From<UnauthorizedError> + fmt::Display + 'static,
+{
+    fn allows(&self, ctx: Context) -> Verdict<Context, Error> {
+        Box::new(self.explain(ctx).map(|(decision, ctx)| (decision.allowed, ctx)))
+    }
+
+    fn explain(&self, ctx: Context) -> ExplainedVerdict<Context, Error> {
+        let started = Instant::now();
+        let timestamp = SystemTime::now();
+
+        let sink = self.sink.clone();
+        let summary = self.summary.clone();
+        let sampling = self.sampling;
+        let allowed_counter = self.allowed_counter.clone();
+        let denied_counter = self.denied_counter.clone();
+
+        Box::new(self.inner.explain(ctx).then(move |res| {
+            let latency = started.elapsed();
+            let record = |ctx: &Context| AuditRecord {
+                timestamp,
+                context: summary(ctx),
+                allowed: None,
+                explanation: None,
+                error: None,
+                latency,
+            };
+
+            match res {
+                Ok((decision, ctx)) => {
+                    let sampled = if decision.allowed {
+                        is_sampled(&allowed_counter, sampling.allowed_every)
+                    } else {
+                        is_sampled(&denied_counter, sampling.denied_every)
+                    };
+                    if sampled {
+                        sink.record(AuditRecord {
+                            allowed: Some(decision.allowed),
+                            explanation: decision.explanation.clone(),
+                            ..record(&ctx)
+                        });
+                    }
+                    Ok((decision, ctx))
+                }
+                Err((e, ctx)) => {
+                    sink.record(AuditRecord {
+                        error: Some(e.to_string()),
+                        ..record(&ctx)
+                    });
+                    Err((e, ctx))
+                }
+            }
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use {ForbiddenACL, InfallibleSyncACLFn, SyncACLFn, SystemACL};
+
+    fn audited<A>(inner: A, sink: &MemoryAuditSink) -> AuditedACL<A, MemoryAuditSink, u32> {
+        AuditedACL::new(inner, sink.clone(), |user_id: &u32| format!("user {}", user_id))
+    }
+
+    fn check<E: AclEngine<u32, UnauthorizedError>>(engine: &E, user_id: u32) {
+        let _ = engine.allows(user_id).wait();
+    }
+
+    #[test]
+    fn test_records_decisions() {
+        let sink = MemoryAuditSink::new();
+
+        check(&audited(SystemACL, &sink), 1);
+        check(&audited(ForbiddenACL, &sink), 2);
+        check(&audited(SyncACLFn(|_: &mut u32| Err(UnauthorizedError::default())), &sink), 3);
+
+        let records = sink.take();
+        assert_eq!(records.len(), 3);
+        assert_eq!((records[0].context.as_str(), records[0].allowed), ("user 1", Some(true)));
+        assert_eq!((records[1].context.as_str(), records[1].allowed), ("user 2", Some(false)));
+        assert_eq!(
+            (records[2].allowed, records[2].error.clone()),
+            (None, Some("Unauthorized".to_string()))
+        );
+        assert!(sink.take().is_empty());
+    }
+
+    #[test]
+    fn test_sampling() {
+        let sink = MemoryAuditSink::new();
+        let engine = audited(InfallibleSyncACLFn(|user_id: &mut u32| *user_id > 0), &sink).with_sampling(Sampling {
+            allowed_every: 3,
+            denied_every: 1,
+        });
+
+        for user_id in 1..8 {
+            check(&engine, user_id);
+        }
+        check(&engine, 0);
+
+        let contexts = sink.take().into_iter().map(|record| record.context).collect::<Vec<_>>();
+        assert_eq!(contexts, vec!["user 1", "user 4", "user 7", "user 0"]);
+    }
+}
@@ -0,0 +1,369 @@
+//! Caching of access decisions. `CachingACL` remembers decisions of the inner engine for a while,
+//! keyed by a projection of the context that the user supplies.
+use failure;
+use futures::future;
+use futures::prelude::*;
+use std::fmt;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use stq_cache::cache::{AtomicCache, Cache, InMemoryCache};
+
+use super::{AclEngine, Decision, ExplainedVerdict, Explanation, UnauthorizedError, Verdict};
+
+/// Key of a cached decision. Decisions are grouped by `subject`, usually the caller, so that they can be invalidated together.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct CacheKey {
+    pub subject: String,
+    pub key: String,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CachedDecision {
+    pub allowed: bool,
+    pub explanation: Option<Explanation>,
+    /// Milliseconds since UNIX epoch
+    pub expires_at: u64,
+}
+
+fn now_ms() -> u64 {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    now.as_secs() * 1000 + u64::from(now.subsec_millis())
+}
+
+const GENERATION_KEY: &str = "acl:gen";
+
+fn subject_generation_key(subject: &str) -> String {
+    format!("{}:{}", GENERATION_KEY, subject)
+}
+
+/// Storage of invalidation generations, type-erased so that `AclCacheInvalidator` stays a plain type
+trait GenerationStore {
+    /// Returns the global generation and the generation of `subject`
+    fn generations(&self, subject: &str) -> Result<(i64, i64), failure::Error>;
+
+    fn bump(&self, key: &str) -> Result<(), failure::Error>;
+}
+
+impl<G> GenerationStore for G
+where
+    G: AtomicCache<String>,
+{
+    fn generations(&self, subject: &str) -> Result<(i64, i64), failure::Error> {
+        let subject_key = subject_generation_key(subject);
+        let mut generations = vec![];
+        for value in self.get_many(&[GENERATION_KEY, &subject_key])? {
+            generations.push(match value {
+                None => 0,
+                Some(value) => value
+                    .parse::<i64>()
+                    .map_err(|_| format_err!("Invalid ACL cache generation `{}`", value))?,
+            });
+        }
+
+        match generations.as_slice() {
+            [generation, subject_generation] => Ok((*generation, *subject_generation)),
+            _ => Err(format_err!("Cache returned {} generations instead of 2", generations.len())),
+        }
+    }
+
+    fn bump(&self, key: &str) -> Result<(), failure::Error> {
+        self.incr_by(key, 1, None)?;
+        Ok(())
+    }
+}
+
+/// Invalidates cached decisions. Keys are prefixed with generation numbers, so invalidation is just a generation bump:
+/// entries stored under older generations are no longer looked up and are left to expire in the cache.
+///
+/// Generations are kept in `acl:gen` and `acl:gen:{subject}` keys of a backend. When decisions are cached in a shared
+/// cache such as Redis, generations must be kept in the same shared backend, see `from_backend`, or invalidation will
+/// not reach other instances and will be forgotten on restart. The backend should not expire these keys: a generation
+/// that drops back to zero makes older entries readable again. There is one key per invalidated subject.
+/// Clones share the generations.
+#[derive(Clone)]
+pub struct AclCacheInvalidator {
+    generations: Arc<GenerationStore + Send + Sync>,
+}
+
+impl fmt::Debug for AclCacheInvalidator {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("AclCacheInvalidator").finish()
+    }
+}
+
+/// Keeps generations in process memory, which is only suitable for decisions cached in process memory too
+impl Default for AclCacheInvalidator {
+    fn default() -> Self {
+        Self::from_backend(InMemoryCache::<String>::new())
+    }
+}
+
+impl AclCacheInvalidator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Keeps generations in `backend`, e.g. a `RedisCache` without TTL on the Redis instance that caches decisions
+    pub fn from_backend<G>(backend: G) -> Self
+    where
+        G: AtomicCache<String> + Send + Sync + 'static,
+    {
+        AclCacheInvalidator {
+            generations: Arc::new(backend),
+        }
+    }
+
+    /// Invalidates all decisions about the subject, e.g. when roles of a user change
+    pub fn invalidate_subject(&self, subject: &str) -> Result<(), failure::Error> {
+        self.generations.bump(&subject_generation_key(subject))
+    }
+
+    /// Invalidates all decisions, e.g. when ACL rules change
+    pub fn invalidate_all(&self) -> Result<(), failure::Error> {
+        self.generations.bump(GENERATION_KEY)
+    }
+
+    /// Key of the decision in the current generations. The subject is length-prefixed, so that different subjects and
+    /// keys never make the same storage key.
+    fn storage_key(&self, key: &CacheKey) -> Result<String, failure::Error> {
+        let (generation, subject_generation) = self.generations.generations(&key.subject)?;
+        Ok(format!(
+            "{}:{}:{}:{}:{}",
+            generation,
+            key.subject.len(),
+            key.subject,
+            subject_generation,
+            key.key
+        ))
+    }
+}
+
+type KeyFn<Context> = Rc<Fn(&Context) -> Option<CacheKey>>;
+
+/// ACL engine that caches decisions of the inner engine for `ttl`, which is also passed to the cache as the TTL of the entries.
+/// Contexts without a key are not cached.
+/// Cache failures are logged and fall through to the inner engine, while failures of the inner engine are never cached.
+pub struct CachingACL<A, C, Context> {
+    inner: A,
+    cache: Rc<C>,
+    key: KeyFn<Context>,
+    ttl: Duration,
+    invalidator: AclCacheInvalidator,
+}
+
+impl<A, C, Context> CachingACL<A, C, Context>
+where
+    C: Cache<CachedDecision>,
+{
+    pub fn new<F>(inner: A, cache: C, ttl: Duration, key: F) -> Self
+    where
+        F: Fn(&Context) -> Option<CacheKey> + 'static,
+    {
+        Self {
+            inner,
+            cache: Rc::new(cache),
+            key: Rc::new(key),
+            ttl,
+            invalidator: AclCacheInvalidator::default(),
+        }
+    }
+
+    /// Shares invalidation with other engines, e.g. with engines of other repos caching into the same backend
+    pub fn with_invalidator(mut self, invalidator: AclCacheInvalidator) -> Self {
+        self.invalidator = invalidator;
+        self
+    }
+
+    pub fn invalidator(&self) -> AclCacheInvalidator {
+        self.invalidator.clone()
+    }
+
+    /// Removes a single cached decision
+    pub fn forget(&self, key: &CacheKey) -> Result<bool, failure::Error> {
+        let storage_key = self.invalidator.storage_key(key)?;
+        Ok(self.cache.remove(&storage_key)?)
+    }
+}
+
+impl<A, C, Context, Error> AclEngine<Context, Error> for CachingACL<A, C, Context>
+where
+    A: AclEngine<Context, Error>,
+    C: Cache<CachedDecision> + 'static,
+    Context: 'static,
+    Error: From<UnauthorizedError> + 'static,
+{
+    fn allows(&self, ctx: Context) -> Verdict<Context, Error> {
+        Box::new(self.explain(ctx).map(|(decision, ctx)| (decision.allowed, ctx)))
+    }
+
+    fn explain(&self, ctx: Context) -> ExplainedVerdict<Context, Error> {
+        let storage_key = match (self.key)(&ctx).map(|key| self.invalidator.storage_key(&key)) {
+            None => return self.inner.explain(ctx),
+            Some(Ok(storage_key)) => storage_key,
+            Some(Err(e)) => {
+                warn!("Failed to read ACL cache generations: {}", e);
+                return self.inner.explain(ctx);
+            }
+        };
+
+        match self.cache.get(&storage_key) {
+            Ok(Some(cached)) => {
+                if cached.expires_at > now_ms() {
+                    let decision = Decision {
+                        allowed: cached.allowed,
+                        explanation: cached.explanation,
+                    };
+                    return Box::new(future::ok((decision, ctx)));
+                }
+            }
+            Ok(None) => {}
+            Err(e) => warn!("Failed to read cached ACL decision: {}", e),
+        }
+
+        let cache = self.cache.clone();
+        let ttl = self.ttl;
+        Box::new(self.inner.explain(ctx).map(move |(decision, ctx)| {
+            let cached = CachedDecision {
+                allowed: decision.allowed,
+                explanation: decision.explanation.clone(),
+                expires_at: now_ms() + ttl.as_secs() * 1000 + u64::from(ttl.subsec_millis()),
+            };
+            if let Err(e) = cache.set_with_ttl(&storage_key, cached, ttl) {
+                warn!("Failed to cache ACL decision: {}", e);
+            }
+            (decision, ctx)
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+    use stq_cache::cache::InMemoryCache;
+    use InfallibleSyncACLFn;
+
+    type Context = (u32, &'static str);
+
+    fn key(ctx: &Context) -> Option<CacheKey> {
+        Some(CacheKey {
+            subject: ctx.0.to_string(),
+            key: ctx.1.to_string(),
+        })
+    }
+
+    fn check<E: AclEngine<Context, UnauthorizedError>>(engine: &E, ctx: Context) -> bool {
+        engine.allows(ctx).wait().unwrap().0
+    }
+
+    #[test]
+    fn test_caching_acl() {
+        let calls = Rc::new(Cell::new(0));
+        let inner = InfallibleSyncACLFn({
+            let calls = calls.clone();
+            move |ctx: &mut Context| {
+                calls.set(calls.get() + 1);
+                ctx.1 == "select"
+            }
+        });
+        let engine = CachingACL::new(inner, InMemoryCache::new(), Duration::from_secs(60), key);
+
+        assert!(check(&engine, (1, "select")));
+        assert!(check(&engine, (1, "select")));
+        assert!(!check(&engine, (1, "delete")));
+        assert!(!check(&engine, (1, "delete")));
+        assert_eq!(calls.get(), 2);
+
+        engine.invalidator().invalidate_subject("1").unwrap();
+        assert!(check(&engine, (1, "select")));
+        assert_eq!(calls.get(), 3);
+
+        assert!(check(&engine, (2, "select")));
+        engine.invalidator().invalidate_all().unwrap();
+        assert!(check(&engine, (2, "select")));
+        assert_eq!(calls.get(), 5);
+
+        assert!(engine
+            .forget(&CacheKey {
+                subject: "2".to_string(),
+                key: "select".to_string()
+            })
+            .unwrap());
+        assert!(check(&engine, (2, "select")));
+        assert_eq!(calls.get(), 6);
+    }
+
+    #[test]
+    fn test_shared_generations() {
+        let calls = Rc::new(Cell::new(0));
+        let decisions = InMemoryCache::new();
+        let generations = InMemoryCache::<String>::new();
+        let engine = || {
+            let calls = calls.clone();
+            let inner = InfallibleSyncACLFn(move |_: &mut Context| {
+                calls.set(calls.get() + 1);
+                true
+            });
+            CachingACL::new(inner, decisions.clone(), Duration::from_secs(60), key)
+                .with_invalidator(AclCacheInvalidator::from_backend(generations.clone()))
+        };
+        let (first, second) = (engine(), engine());
+
+        assert!(check(&first, (1, "select")));
+        assert!(check(&second, (1, "select")));
+        assert_eq!(calls.get(), 1);
+
+        first.invalidator().invalidate_subject("1").unwrap();
+        assert!(check(&second, (1, "select")));
+        assert_eq!(calls.get(), 2);
+        assert_eq!(generations.get("acl:gen:1").unwrap(), Some("1".to_string()));
+
+        // A new instance picks up generations from the backend
+        assert!(check(&engine(), (1, "select")));
+        assert_eq!(calls.get(), 2);
+    }
+
+    #[test]
+    fn test_storage_keys_are_unambiguous() {
+        let invalidator = AclCacheInvalidator::new();
+        let storage_key = |subject: &str, key: &str| {
+            invalidator
+                .storage_key(&CacheKey {
+                    subject: subject.to_string(),
+                    key: key.to_string(),
+                })
+                .unwrap()
+        };
+
+        assert_ne!(storage_key("1", "0:k"), storage_key("1:0", "k"));
+    }
+
+    #[test]
+    fn test_decisions_expire_in_the_cache() {
+        let decisions = InMemoryCache::new();
+        let inner = InfallibleSyncACLFn(|_: &mut Context| true);
+        let engine = CachingACL::new(inner, decisions.clone(), Duration::from_millis(0), key);
+
+        assert!(check(&engine, (1, "select")));
+        assert_eq!(decisions.len().unwrap(), 1);
+        assert_eq!(decisions.sweep().unwrap(), 1);
+    }
+
+    #[test]
+    fn test_expired_decisions_are_recomputed() {
+        let calls = Rc::new(Cell::new(0));
+        let inner = InfallibleSyncACLFn({
+            let calls = calls.clone();
+            move |_: &mut Context| {
+                calls.set(calls.get() + 1);
+                true
+            }
+        });
+        let engine = CachingACL::new(inner, InMemoryCache::new(), Duration::from_millis(0), key);
+
+        assert!(check(&engine, (1, "select")));
+        assert!(check(&engine, (1, "select")));
+        assert_eq!(calls.get(), 2);
+    }
+}
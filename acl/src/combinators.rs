@@ -0,0 +1,345 @@
+//! Combinators for building ACL engines out of simpler ones.
+//! Engines are evaluated in the order they were added, and the context is threaded through each of them.
+use futures::future::{self, Loop};
+use futures::prelude::*;
+use std::rc::Rc;
+
+use super::{AclEngine, Decision, ExplainedVerdict, Explanation, UnauthorizedError, Verdict};
+
+/// Runs `engines` one by one until some engine returns `stop_on`.
+/// Returns the decision of that engine, or `!stop_on` with the explanation of the last engine if no engine did.
+fn evaluate_until<Context, Error>(
+    engines: Vec<Rc<AclEngine<Context, Error>>>,
+    ctx: Context,
+    stop_on: bool,
+) -> ExplainedVerdict<Context, Error>
+where
+    Context: 'static,
+    Error: From<UnauthorizedError> + 'static,
+{
+    Box::new(future::loop_fn((ctx, 0, None), move |(ctx, i, last_explanation)| {
+        match engines.get(i) {
+            None => Box::new(future::ok(Loop::Break((
+                Decision {
+                    allowed: !stop_on,
+                    explanation: last_explanation,
+                },
+                ctx,
+            )))) as Box<Future<Item = _, Error = _>>,
+            Some(engine) => Box::new(engine.explain(ctx).map(move |(decision, ctx)| {
+                if decision.allowed == stop_on {
+                    Loop::Break((decision, ctx))
+                } else {
+                    Loop::Continue((ctx, i + 1, decision.explanation))
+                }
+            })),
+        }
+    }))
+}
+
+/// `AllOf` allows access only if every engine allows it. Evaluation stops at the first denial.
+/// `AllOf` without engines allows everything.
+pub struct AllOf<Context, Error> {
+    engines: Vec<Rc<AclEngine<Context, Error>>>,
+}
+
+impl<Context, Error> Default for AllOf<Context, Error> {
+    fn default() -> Self {
+        Self { engines: vec![] }
+    }
+}
+
+impl<Context, Error> AllOf<Context, Error>
+where
+    Context: 'static,
+    Error: From<UnauthorizedError> + 'static,
+{
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_engine<E>(mut self, engine: E) -> Self
+    where
+        E: AclEngine<Context, Error> + 'static,
+    {
+        self.engines.push(Rc::new(engine));
+        self
+    }
+}
+
+impl<Context, Error> AclEngine<Context, Error> for AllOf<Context, Error>
+where
+    Context: 'static,
+    Error: From<UnauthorizedError> + 'static,
+{
+    fn allows(&self, ctx: Context) -> Verdict<Context, Error> {
+        Box::new(self.explain(ctx).map(|(decision, ctx)| (decision.allowed, ctx)))
+    }
+
+    fn explain(&self, ctx: Context) -> ExplainedVerdict<Context, Error> {
+        evaluate_until(self.engines.clone(), ctx, false)
+    }
+}
+
+/// `AnyOf` allows access if at least one engine allows it. Evaluation stops at the first approval.
+/// `AnyOf` without engines denies everything.
+pub struct AnyOf<Context, Error> {
+    engines: Vec<Rc<AclEngine<Context, Error>>>,
+}
+
+impl<Context, Error> Default for AnyOf<Context, Error> {
+    fn default() -> Self {
+        Self { engines: vec![] }
+    }
+}
+
+impl<Context, Error> AnyOf<Context, Error>
+where
+    Context: 'static,
+    Error: From<UnauthorizedError> + 'static,
+{
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_engine<E>(mut self, engine: E) -> Self
+    where
+        E: AclEngine<Context, Error> + 'static,
+    {
+        self.engines.push(Rc::new(engine));
+        self
+    }
+}
+
+impl<Context, Error> AclEngine<Context, Error> for AnyOf<Context, Error>
+where
+    Context: 'static,
+    Error: From<UnauthorizedError> + 'static,
+{
+    fn allows(&self, ctx: Context) -> Verdict<Context, Error> {
+        Box::new(self.explain(ctx).map(|(decision, ctx)| (decision.allowed, ctx)))
+    }
+
+    fn explain(&self, ctx: Context) -> ExplainedVerdict<Context, Error> {
+        evaluate_until(self.engines.clone(), ctx, true)
+    }
+}
+
+/// `Not` inverts the verdict of the inner engine. Errors are passed as is.
+pub struct Not<E>(pub E);
+
+impl<E, Context, Error> AclEngine<Context, Error> for Not<E>
+where
+    E: AclEngine<Context, Error>,
+    Context: 'static,
+    Error: From<UnauthorizedError> + 'static,
+{
+    fn allows(&self, ctx: Context) -> Verdict<Context, Error> {
+        Box::new(self.0.allows(ctx).map(|(allowed, ctx)| (!allowed, ctx)))
+    }
+}
+
+type Rule<Context, Error> = (Box<Fn(&Context) -> bool>, Box<AclEngine<Context, Error>>);
+
+/// `FirstMatch` delegates the decision to the engine of the first rule whose predicate matches the context.
+/// If no rule matches, the default verdict is returned, which is denial unless set otherwise.
+pub struct FirstMatch<Context, Error> {
+    rules: Vec<Rule<Context, Error>>,
+    default: bool,
+}
+
+impl<Context, Error> Default for FirstMatch<Context, Error> {
+    fn default() -> Self {
+        Self {
+            rules: vec![],
+            default: false,
+        }
+    }
+}
+
+impl<Context, Error> FirstMatch<Context, Error>
+where
+    Context: 'static,
+    Error: From<UnauthorizedError> + 'static,
+{
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_rule<P, E>(mut self, predicate: P, engine: E) -> Self
+    where
+        P: Fn(&Context) -> bool + 'static,
+        E: AclEngine<Context, Error> + 'static,
+    {
+        self.rules.push((Box::new(predicate), Box::new(engine)));
+        self
+    }
+
+    /// Verdict to return when no rule matches
+    pub fn with_default(mut self, allowed: bool) -> Self {
+        self.default = allowed;
+        self
+    }
+}
+
+impl<Context, Error> AclEngine<Context, Error> for FirstMatch<Context, Error>
+where
+    Context: 'static,
+    Error: From<UnauthorizedError> + 'static,
+{
+    fn allows(&self, ctx: Context) -> Verdict<Context, Error> {
+        Box::new(self.explain(ctx).map(|(decision, ctx)| (decision.allowed, ctx)))
+    }
+
+    fn explain(&self, ctx: Context) -> ExplainedVerdict<Context, Error> {
+        match self.rules.iter().find(|(predicate, _)| predicate(&ctx)) {
+            Some((_, engine)) => engine.explain(ctx),
+            None => Box::new(future::ok((
+                Decision::from(self.default).with_explanation(Explanation {
+                    reason: Some("No rule matched".to_string()),
+                    ..Default::default()
+                }),
+                ctx,
+            ))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+    use {ForbiddenACL, InfallibleSyncACLFn, SyncACLFn, SystemACL};
+
+    #[derive(Debug)]
+    struct TestError;
+
+    impl From<UnauthorizedError> for TestError {
+        fn from(_: UnauthorizedError) -> Self {
+            TestError
+        }
+    }
+
+    /// Context records the engines it went through
+    type TestContext = Vec<&'static str>;
+
+    fn tracing(name: &'static str, verdict: bool) -> InfallibleSyncACLFn<impl Fn(&mut TestContext) -> bool> {
+        InfallibleSyncACLFn(move |ctx: &mut TestContext| {
+            ctx.push(name);
+            verdict
+        })
+    }
+
+    fn run<E: AclEngine<TestContext, TestError>>(engine: &E) -> (bool, TestContext) {
+        engine.allows(vec![]).wait().unwrap()
+    }
+
+    #[test]
+    fn test_all_of() {
+        assert_eq!(run(&AllOf::new()), (true, vec![]));
+        assert_eq!(
+            run(&AllOf::new().with_engine(tracing("a", true)).with_engine(tracing("b", true))),
+            (true, vec!["a", "b"])
+        );
+        assert_eq!(
+            run(&AllOf::new()
+                .with_engine(tracing("a", true))
+                .with_engine(tracing("b", false))
+                .with_engine(tracing("c", true))),
+            (false, vec!["a", "b"])
+        );
+    }
+
+    #[test]
+    fn test_any_of() {
+        assert_eq!(run(&AnyOf::new()), (false, vec![]));
+        assert_eq!(
+            run(&AnyOf::new()
+                .with_engine(tracing("a", false))
+                .with_engine(tracing("b", true))
+                .with_engine(tracing("c", true))),
+            (true, vec!["a", "b"])
+        );
+        assert_eq!(
+            run(&AnyOf::new().with_engine(tracing("a", false)).with_engine(tracing("b", false))),
+            (false, vec!["a", "b"])
+        );
+    }
+
+    #[test]
+    fn test_not() {
+        assert_eq!(run(&Not(SystemACL)), (false, vec![]));
+        assert_eq!(run(&Not(ForbiddenACL)), (true, vec![]));
+        assert_eq!(run(&Not(tracing("a", false))), (true, vec!["a"]));
+    }
+
+    #[test]
+    fn test_first_match() {
+        let engine = FirstMatch::new()
+            .with_rule(|ctx: &TestContext| ctx.is_empty(), tracing("empty", true))
+            .with_rule(|_: &TestContext| true, tracing("any", false));
+        assert_eq!(run(&engine), (true, vec!["empty"]));
+        assert_eq!(engine.allows(vec!["x"]).wait().unwrap(), (false, vec!["x", "any"]));
+
+        assert_eq!(run(&FirstMatch::new()), (false, vec![]));
+        assert_eq!(run(&FirstMatch::new().with_default(true)), (true, vec![]));
+    }
+
+    /// Engine that tells which rule decided on access
+    struct RuleACL(bool, &'static str);
+
+    impl AclEngine<TestContext, TestError> for RuleACL {
+        fn allows(&self, ctx: TestContext) -> Verdict<TestContext, TestError> {
+            Box::new(future::ok((self.0, ctx)))
+        }
+
+        fn explain(&self, ctx: TestContext) -> ExplainedVerdict<TestContext, TestError> {
+            Box::new(future::ok((
+                Decision::from(self.0).with_explanation(Explanation {
+                    rule: Some(self.1.to_string()),
+                    ..Default::default()
+                }),
+                ctx,
+            )))
+        }
+    }
+
+    fn deciding_rule<E: AclEngine<TestContext, TestError>>(engine: &E) -> (bool, Option<String>) {
+        let (decision, _) = engine.explain(vec![]).wait().unwrap();
+        (decision.allowed, decision.explanation.and_then(|e| e.rule.or(e.reason)))
+    }
+
+    #[test]
+    fn test_explanations() {
+        assert_eq!(
+            deciding_rule(&AllOf::new().with_engine(RuleACL(true, "a")).with_engine(RuleACL(false, "b"))),
+            (false, Some("b".to_string()))
+        );
+        assert_eq!(
+            deciding_rule(&AnyOf::new().with_engine(RuleACL(false, "a")).with_engine(RuleACL(false, "b"))),
+            (false, Some("b".to_string()))
+        );
+        assert_eq!(
+            deciding_rule(&FirstMatch::new().with_rule(|_: &TestContext| false, RuleACL(true, "a"))),
+            (false, Some("No rule matched".to_string()))
+        );
+        assert_eq!(deciding_rule(&Not(RuleACL(true, "a"))), (false, None));
+    }
+
+    #[test]
+    fn test_errors_stop_evaluation() {
+        let calls = Rc::new(Cell::new(0));
+        let engine = AnyOf::new()
+            .with_engine(SyncACLFn(|_: &mut TestContext| Err(TestError)))
+            .with_engine(InfallibleSyncACLFn({
+                let calls = calls.clone();
+                move |_: &mut TestContext| {
+                    calls.set(calls.get() + 1);
+                    true
+                }
+            }));
+
+        assert!(engine.allows(vec![]).wait().is_err());
+        assert_eq!(calls.get(), 0);
+    }
+}
@@ -2,15 +2,99 @@
 #[macro_use]
 extern crate failure;
 extern crate futures;
+#[macro_use]
+extern crate log;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate serde_json;
+extern crate stq_cache;
+extern crate toml;
+
+pub mod audit;
+pub mod caching;
+pub mod combinators;
+pub mod policy;
+
+pub use audit::{AuditSink, AuditedACL};
+pub use caching::{AclCacheInvalidator, CacheKey, CachingACL};
+pub use combinators::*;
+pub use policy::{AccessRequest, Policy, PolicyACL};
 
 use futures::future;
 use futures::prelude::*;
+use std::fmt;
 
 pub type Verdict<Context, E> = Box<Future<Item = (bool, Context), Error = (E, Context)>>;
+pub type ExplainedVerdict<Context, E> = Box<Future<Item = (Decision, Context), Error = (E, Context)>>;
+
+/// Tells why access was granted or denied. All fields are optional since engines know different amounts of detail.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Explanation {
+    /// Rule that decided on access
+    pub rule: Option<String>,
+    pub resource: Option<String>,
+    pub action: Option<String>,
+    /// Free-form reason, e.g. when no rule was matched
+    pub reason: Option<String>,
+}
 
-#[derive(Clone, Debug, Fail)]
-#[fail(display = "Unauthorized")]
-pub struct UnauthorizedError;
+impl fmt::Display for Explanation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut parts = vec![];
+        if let Some(ref action) = self.action {
+            parts.push(format!("action `{}`", action));
+        }
+        if let Some(ref resource) = self.resource {
+            parts.push(format!("resource `{}`", resource));
+        }
+        if let Some(ref rule) = self.rule {
+            parts.push(format!("rule `{}`", rule));
+        }
+        if let Some(ref reason) = self.reason {
+            parts.push(reason.clone());
+        }
+        write!(f, "{}", parts.join(", "))
+    }
+}
+
+/// Verdict of an ACL engine along with its explanation.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Decision {
+    pub allowed: bool,
+    pub explanation: Option<Explanation>,
+}
+
+impl Decision {
+    pub fn with_explanation(mut self, explanation: Explanation) -> Self {
+        self.explanation = Some(explanation);
+        self
+    }
+}
+
+impl From<bool> for Decision {
+    fn from(allowed: bool) -> Self {
+        Self {
+            allowed,
+            explanation: None,
+        }
+    }
+}
+
+/// Denial of access. `UnauthorizedError::default()` is a denial without explanation.
+#[derive(Clone, Debug, Default, Fail)]
+pub struct UnauthorizedError {
+    pub explanation: Option<Explanation>,
+}
+
+impl fmt::Display for UnauthorizedError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.explanation {
+            None => write!(f, "Unauthorized"),
+            Some(ref explanation) => write!(f, "Unauthorized: {}", explanation),
+        }
+    }
+}
 
 /// Access control layer for repos. It tells if a user can do a certain action with
 /// certain resource. All logic for roles and permissions should be hardcoded into implementation
@@ -26,12 +110,22 @@ where
     /// `Owner` (`Scope`) of the store.
     fn allows(&self, ctx: Context) -> Verdict<Context, Error>;
 
+    /// Same as `allows`, but also tells why. Engines that know which rule decided on access should override it.
+    fn explain(&self, ctx: Context) -> ExplainedVerdict<Context, Error> {
+        Box::new(self.allows(ctx).map(|(allowed, ctx)| (Decision::from(allowed), ctx)))
+    }
+
     fn ensure_access(&self, ctx: Context) -> Box<Future<Item = Context, Error = (Error, Context)>> {
-        Box::new(self.allows(ctx).and_then(|(allowed, ctx)| {
-            future::result(if allowed {
+        Box::new(self.explain(ctx).and_then(|(decision, ctx)| {
+            future::result(if decision.allowed {
                 Ok(ctx)
             } else {
-                Err((Error::from(UnauthorizedError), ctx))
+                Err((
+                    Error::from(UnauthorizedError {
+                        explanation: decision.explanation,
+                    }),
+                    ctx,
+                ))
             })
         }))
     }
@@ -0,0 +1,333 @@
+//! Declarative ACL policies. A policy is a table of rules, each of them allowing or denying
+//! an `action` on a `resource` to a user with a certain `role`, optionally only within a `scope`.
+//! Policies can be written in JSON or TOML:
+//!
+//! ```toml
+//! [[rules]]
+//! role = "superuser"
+//! resource = "*"
+//! action = "*"
+//! effect = "allow"
+//!
+//! [[rules]]
+//! role = "user"
+//! resource = "store"
+//! action = "update"
+//! scope = "owner"
+//! effect = "allow"
+//! ```
+//!
+//! Scopes are named predicates over the ACL context, which are registered on `PolicyACL`.
+use futures::future;
+use serde_json;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+use toml;
+
+use super::{AclEngine, Decision, ExplainedVerdict, Explanation, UnauthorizedError, Verdict};
+
+/// Matches any role, resource or action.
+pub const WILDCARD: &str = "*";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Effect {
+    Allow,
+    Deny,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Rule {
+    /// Name to refer to the rule by in denial explanations
+    #[serde(default)]
+    pub name: Option<String>,
+    pub role: String,
+    pub resource: String,
+    pub action: String,
+    #[serde(default)]
+    pub scope: Option<String>,
+    pub effect: Effect,
+}
+
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(ref name) = self.name {
+            return write!(f, "{}", name);
+        }
+
+        write!(f, "{:?} {} on {} to {}", self.effect, self.action, self.resource, self.role)?;
+        if let Some(ref scope) = self.scope {
+            write!(f, " within {}", scope)?;
+        }
+        Ok(())
+    }
+}
+
+/// What is being asked of the policy: can a user with `roles` do `action` on `resource`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AccessRequest {
+    pub roles: Vec<String>,
+    pub resource: String,
+    pub action: String,
+}
+
+#[derive(Debug, Fail)]
+pub enum PolicyError {
+    #[fail(display = "Failed to read policy file: {}", _0)]
+    Io(io::Error),
+    #[fail(display = "Failed to parse JSON policy: {}", _0)]
+    Json(serde_json::Error),
+    #[fail(display = "Failed to parse TOML policy: {}", _0)]
+    Toml(toml::de::Error),
+    #[fail(display = "Unknown policy file format: {}", _0)]
+    UnknownFormat(String),
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Policy {
+    #[serde(default)]
+    pub rules: Vec<Rule>,
+}
+
+fn matches(pattern: &str, value: &str) -> bool {
+    pattern == WILDCARD || pattern == value
+}
+
+impl Policy {
+    pub fn from_json(s: &str) -> Result<Self, PolicyError> {
+        serde_json::from_str(s).map_err(PolicyError::Json)
+    }
+
+    pub fn from_toml(s: &str) -> Result<Self, PolicyError> {
+        toml::from_str(s).map_err(PolicyError::Toml)
+    }
+
+    /// Loads policy from a `.json` or `.toml` file
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, PolicyError> {
+        let path = path.as_ref();
+        let contents = fs::read_to_string(path).map_err(PolicyError::Io)?;
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("json") => Self::from_json(&contents),
+            Some("toml") => Self::from_toml(&contents),
+            _ => Err(PolicyError::UnknownFormat(path.display().to_string())),
+        }
+    }
+
+    pub fn with_rule(mut self, rule: Rule) -> Self {
+        self.rules.push(rule);
+        self
+    }
+
+    /// Finds the rule that decides on the request. Deny rules take precedence over allow rules.
+    /// `in_scope` tells if the request is within the named scope. `None` means that no rule matched.
+    pub fn evaluate<F>(&self, request: &AccessRequest, in_scope: F) -> Option<&Rule>
+    where
+        F: Fn(&str) -> bool,
+    {
+        let mut allowed_by = None;
+        for rule in &self.rules {
+            let role_matches = rule.role == WILDCARD || request.roles.contains(&rule.role);
+            if !role_matches || !matches(&rule.resource, &request.resource) || !matches(&rule.action, &request.action) {
+                continue;
+            }
+
+            if let Some(ref scope) = rule.scope {
+                if !in_scope(scope) {
+                    continue;
+                }
+            }
+
+            match rule.effect {
+                Effect::Deny => return Some(rule),
+                Effect::Allow => {
+                    if allowed_by.is_none() {
+                        allowed_by = Some(rule);
+                    }
+                }
+            }
+        }
+
+        allowed_by
+    }
+}
+
+type ScopePredicate<Context> = Box<Fn(&Context) -> bool>;
+
+/// ACL engine backed by a `Policy`. Access is denied unless some rule allows it.
+pub struct PolicyACL<Context> {
+    policy: Policy,
+    request: Box<Fn(&Context) -> AccessRequest>,
+    scopes: HashMap<String, ScopePredicate<Context>>,
+}
+
+impl<Context> PolicyACL<Context> {
+    /// `request` extracts caller roles, resource and action from the ACL context.
+    pub fn new<F>(policy: Policy, request: F) -> Self
+    where
+        F: Fn(&Context) -> AccessRequest + 'static,
+    {
+        Self {
+            policy,
+            request: Box::new(request),
+            scopes: HashMap::new(),
+        }
+    }
+
+    /// Registers scope that can be referred to by name in policy rules.
+    /// Rules with unregistered scopes never match.
+    pub fn with_scope<F>(mut self, name: &str, predicate: F) -> Self
+    where
+        F: Fn(&Context) -> bool + 'static,
+    {
+        self.scopes.insert(name.to_string(), Box::new(predicate));
+        self
+    }
+
+    fn decide(&self, ctx: &Context) -> Option<&Rule> {
+        let request = (self.request)(ctx);
+        self.policy.evaluate(&request, |scope| {
+            self.scopes.get(scope).map(|in_scope| in_scope(ctx)).unwrap_or(false)
+        })
+    }
+}
+
+impl<Context, Error> AclEngine<Context, Error> for PolicyACL<Context>
+where
+    Context: 'static,
+    Error: From<UnauthorizedError> + 'static,
+{
+    fn allows(&self, ctx: Context) -> Verdict<Context, Error> {
+        let allowed = self.decide(&ctx).map(|rule| rule.effect == Effect::Allow).unwrap_or(false);
+        Box::new(future::ok((allowed, ctx)))
+    }
+
+    fn explain(&self, ctx: Context) -> ExplainedVerdict<Context, Error> {
+        let request = (self.request)(&ctx);
+        let decision = match self.decide(&ctx) {
+            Some(rule) => Decision::from(rule.effect == Effect::Allow).with_explanation(Explanation {
+                rule: Some(rule.to_string()),
+                resource: Some(request.resource),
+                action: Some(request.action),
+                reason: None,
+            }),
+            None => Decision::from(false).with_explanation(Explanation {
+                rule: None,
+                resource: Some(request.resource),
+                action: Some(request.action),
+                reason: Some("No rule allows this action".to_string()),
+            }),
+        };
+        Box::new(future::ok((decision, ctx)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::prelude::*;
+
+    #[derive(Clone, Debug)]
+    struct Context {
+        caller_id: i32,
+        caller_roles: Vec<String>,
+        store_owner_id: i32,
+        action: &'static str,
+    }
+
+    fn engine(policy: Policy) -> PolicyACL<Context> {
+        PolicyACL::new(policy, |ctx: &Context| AccessRequest {
+            roles: ctx.caller_roles.clone(),
+            resource: "store".to_string(),
+            action: ctx.action.to_string(),
+        })
+        .with_scope("owner", |ctx: &Context| ctx.caller_id == ctx.store_owner_id)
+    }
+
+    fn allows(engine: &PolicyACL<Context>, ctx: Context) -> bool {
+        AclEngine::<Context, UnauthorizedError>::allows(engine, ctx).wait().unwrap().0
+    }
+
+    fn ctx(caller_id: i32, role: &str, action: &'static str) -> Context {
+        Context {
+            caller_id,
+            caller_roles: vec![role.to_string()],
+            store_owner_id: 1,
+            action,
+        }
+    }
+
+    const TOML_POLICY: &str = r#"
+        [[rules]]
+        role = "superuser"
+        resource = "*"
+        action = "*"
+        effect = "allow"
+
+        [[rules]]
+        role = "user"
+        resource = "store"
+        action = "select"
+        effect = "allow"
+
+        [[rules]]
+        role = "user"
+        resource = "store"
+        action = "update"
+        scope = "owner"
+        effect = "allow"
+
+        [[rules]]
+        role = "*"
+        resource = "store"
+        action = "delete"
+        effect = "deny"
+    "#;
+
+    #[test]
+    fn test_toml_policy() {
+        let engine = engine(Policy::from_toml(TOML_POLICY).unwrap());
+
+        assert!(allows(&engine, ctx(2, "user", "select")));
+        assert!(allows(&engine, ctx(1, "user", "update")));
+        assert!(!allows(&engine, ctx(2, "user", "update")));
+        assert!(!allows(&engine, ctx(1, "user", "insert")));
+        assert!(allows(&engine, ctx(2, "superuser", "update")));
+        assert!(!allows(&engine, ctx(2, "superuser", "delete")));
+        assert!(!allows(&engine, ctx(2, "moderator", "select")));
+    }
+
+    #[test]
+    fn test_denial_explanation() {
+        let engine = engine(Policy::from_toml(TOML_POLICY).unwrap());
+
+        let (err, _) = AclEngine::<Context, UnauthorizedError>::ensure_access(&engine, ctx(1, "user", "delete"))
+            .wait()
+            .unwrap_err();
+        let explanation = err.explanation.unwrap();
+        assert_eq!(explanation.rule, Some("Deny delete on store to *".to_string()));
+        assert_eq!(explanation.action, Some("delete".to_string()));
+
+        let (err, _) = AclEngine::<Context, UnauthorizedError>::ensure_access(&engine, ctx(1, "user", "insert"))
+            .wait()
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Unauthorized: action `insert`, resource `store`, No rule allows this action"
+        );
+    }
+
+    #[test]
+    fn test_json_policy() {
+        let policy = Policy::from_json(
+            r#"{"rules": [{"role": "user", "resource": "store", "action": "update", "scope": "unknown", "effect": "allow"}]}"#,
+        )
+        .unwrap();
+        assert_eq!(policy.rules[0].scope, Some("unknown".to_string()));
+
+        let engine = engine(policy);
+        assert!(!allows(&engine, ctx(1, "user", "update")));
+    }
+}
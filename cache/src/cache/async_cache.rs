@@ -0,0 +1,247 @@
+//! Asynchronous counterpart of `Cache`. Use it on the reactor thread, where blocking on a cache backend stalls every request.
+use failure::Fail;
+use futures::future;
+use futures::prelude::*;
+use futures_cpupool::CpuPool;
+use serde::{de::DeserializeOwned, Serialize};
+use std::marker::PhantomData;
+use std::sync::Arc;
+
+use super::codec::Codec;
+use super::{Cache, InMemoryCache, InMemoryCacheError, NullCache, TypedCacheError};
+
+pub type CacheFuture<T, E> = Box<Future<Item = T, Error = E>>;
+
+pub trait AsyncCache<T> {
+    type Error: Fail;
+
+    fn get(&self, key: &str) -> CacheFuture<Option<T>, Self::Error>;
+
+    fn set(&self, key: &str, value: T) -> CacheFuture<(), Self::Error>;
+
+    fn remove(&self, key: &str) -> CacheFuture<bool, Self::Error>;
+}
+
+impl<C, T> AsyncCache<T> for Box<C>
+where
+    C: ?Sized + AsyncCache<T>,
+{
+    type Error = C::Error;
+
+    fn get(&self, key: &str) -> CacheFuture<Option<T>, Self::Error> {
+        (**self).get(key)
+    }
+
+    fn set(&self, key: &str, value: T) -> CacheFuture<(), Self::Error> {
+        (**self).set(key, value)
+    }
+
+    fn remove(&self, key: &str) -> CacheFuture<bool, Self::Error> {
+        (**self).remove(key)
+    }
+}
+
+impl<T> AsyncCache<T> for InMemoryCache<T>
+where
+    T: Clone + 'static,
+{
+    type Error = InMemoryCacheError;
+
+    fn get(&self, key: &str) -> CacheFuture<Option<T>, Self::Error> {
+        Box::new(future::result(Cache::get(self, key)))
+    }
+
+    fn set(&self, key: &str, value: T) -> CacheFuture<(), Self::Error> {
+        Box::new(future::result(Cache::set(self, key, value)))
+    }
+
+    fn remove(&self, key: &str) -> CacheFuture<bool, Self::Error> {
+        Box::new(future::result(Cache::remove(self, key)))
+    }
+}
+
+impl<T, E> AsyncCache<T> for NullCache<T, E>
+where
+    T: 'static,
+    E: Fail,
+{
+    type Error = E;
+
+    fn get(&self, _key: &str) -> CacheFuture<Option<T>, Self::Error> {
+        Box::new(future::ok(None))
+    }
+
+    fn set(&self, _key: &str, _value: T) -> CacheFuture<(), Self::Error> {
+        Box::new(future::ok(()))
+    }
+
+    fn remove(&self, _key: &str) -> CacheFuture<bool, Self::Error> {
+        Box::new(future::ok(false))
+    }
+}
+
+/// Runs operations of a synchronous cache on a thread pool, so that they do not block the caller.
+#[derive(Clone)]
+pub struct ThreadPoolCache<C> {
+    cache: Arc<C>,
+    pool: CpuPool,
+}
+
+impl<C> ThreadPoolCache<C> {
+    pub fn new(cache: C, pool: CpuPool) -> Self {
+        ThreadPoolCache {
+            cache: Arc::new(cache),
+            pool,
+        }
+    }
+}
+
+impl<C, T> AsyncCache<T> for ThreadPoolCache<C>
+where
+    C: Cache<T> + Send + Sync + 'static,
+    T: Send + 'static,
+{
+    type Error = C::Error;
+
+    fn get(&self, key: &str) -> CacheFuture<Option<T>, Self::Error> {
+        let cache = self.cache.clone();
+        let key = key.to_string();
+        Box::new(self.pool.spawn_fn(move || cache.get(&key)))
+    }
+
+    fn set(&self, key: &str, value: T) -> CacheFuture<(), Self::Error> {
+        let cache = self.cache.clone();
+        let key = key.to_string();
+        Box::new(self.pool.spawn_fn(move || cache.set(&key, value)))
+    }
+
+    fn remove(&self, key: &str) -> CacheFuture<bool, Self::Error> {
+        let cache = self.cache.clone();
+        let key = key.to_string();
+        Box::new(self.pool.spawn_fn(move || cache.remove(&key)))
+    }
+}
+
+/// Asynchronous counterpart of `TypedCache`.
+#[derive(Clone, Debug)]
+pub struct AsyncTypedCache<C, T> {
+    backend: C,
+    codec: Codec,
+    phantom: PhantomData<T>,
+}
+
+impl<C, T> AsyncTypedCache<C, T>
+where
+    C: AsyncCache<String>,
+    T: DeserializeOwned + Serialize + 'static,
+{
+    pub fn new(backend: C) -> Self {
+        AsyncTypedCache {
+            backend,
+            codec: Codec::default(),
+            phantom: PhantomData,
+        }
+    }
+
+    /// Same as `TypedCache::with_codec`
+    pub fn with_codec(self, codec: Codec) -> Self {
+        AsyncTypedCache { codec, ..self }
+    }
+}
+
+impl<C, T> AsyncCache<T> for AsyncTypedCache<C, T>
+where
+    C: AsyncCache<String>,
+    T: DeserializeOwned + Serialize + 'static,
+{
+    type Error = TypedCacheError<C::Error>;
+
+    fn get(&self, key: &str) -> CacheFuture<Option<T>, Self::Error> {
+        let codec = self.codec;
+        Box::new(
+            self.backend
+                .get(key)
+                .map_err(TypedCacheError::BackendCacheError)
+                .and_then(move |encoded_opt| match encoded_opt {
+                    None => Ok(None),
+                    Some(encoded) => codec.decode(&encoded).map(Some).map_err(From::from),
+                }),
+        )
+    }
+
+    fn set(&self, key: &str, value: T) -> CacheFuture<(), Self::Error> {
+        match self.codec.encode(&value) {
+            Ok(encoded) => Box::new(
+                self.backend
+                    .set(key, encoded)
+                    .map_err(TypedCacheError::BackendCacheError),
+            ),
+            Err(e) => Box::new(future::err(e.into())),
+        }
+    }
+
+    fn remove(&self, key: &str) -> CacheFuture<bool, Self::Error> {
+        Box::new(
+            self.backend
+                .remove(key)
+                .map_err(TypedCacheError::BackendCacheError),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+    struct TestStruct {
+        pub s: String,
+        pub i: i32,
+    }
+
+    fn check_cache<C: AsyncCache<TestStruct>>(cache: C) {
+        let value = TestStruct {
+            s: "string".to_string(),
+            i: 10,
+        };
+
+        cache
+            .set("key", value.clone())
+            .wait()
+            .expect("Failed to set value");
+        assert_eq!(
+            Some(value),
+            cache.get("key").wait().expect("Failed to get value")
+        );
+        assert!(cache.remove("key").wait().expect("Failed to remove value"));
+        assert!(!cache
+            .remove("key")
+            .wait()
+            .expect("Failed to attempt to remove value"));
+        assert_eq!(None, cache.get("key").wait().expect("Failed to get value"));
+    }
+
+    #[test]
+    fn test_in_memory_cache() {
+        check_cache(InMemoryCache::new());
+    }
+
+    #[test]
+    fn test_async_typed_cache() {
+        check_cache(AsyncTypedCache::new(InMemoryCache::<String>::new()));
+    }
+
+    #[test]
+    fn test_thread_pool_cache() {
+        check_cache(ThreadPoolCache::new(InMemoryCache::new(), CpuPool::new(2)));
+    }
+
+    #[test]
+    fn test_null_cache() {
+        let cache = NullCache::<String, InMemoryCacheError>::new();
+        AsyncCache::set(&cache, "key", "value".to_string())
+            .wait()
+            .unwrap();
+        assert_eq!(None, AsyncCache::get(&cache, "key").wait().unwrap());
+    }
+}
@@ -0,0 +1,57 @@
+use std::time::Duration;
+
+use super::Cache;
+
+/// Operations that are atomic with respect to other clients of the cache,
+/// for counters and markers such as "this request has already been processed".
+///
+/// `ttl` arguments override the TTL of the cache.
+pub trait AtomicCache<T>: Cache<T> {
+    /// Adds `delta` to the integer stored at `key` and returns the result.
+    /// Missing keys count as zero and are created with `ttl`, which is not changed by later increments.
+    fn incr_by(&self, key: &str, delta: i64, ttl: Option<Duration>) -> Result<i64, Self::Error>;
+
+    /// Sets the value only if `key` does not exist. Returns whether the value has been set.
+    fn set_if_absent(
+        &self,
+        key: &str,
+        value: T,
+        ttl: Option<Duration>,
+    ) -> Result<bool, Self::Error>;
+
+    /// Sets the value only if the current value equals `expected`, where `None` stands for a missing key.
+    /// Returns whether the value has been set.
+    fn compare_and_swap(
+        &self,
+        key: &str,
+        expected: Option<&T>,
+        value: T,
+    ) -> Result<bool, Self::Error>;
+}
+
+impl<C, T> AtomicCache<T> for Box<C>
+where
+    C: ?Sized + AtomicCache<T>,
+{
+    fn incr_by(&self, key: &str, delta: i64, ttl: Option<Duration>) -> Result<i64, Self::Error> {
+        (**self).incr_by(key, delta, ttl)
+    }
+
+    fn set_if_absent(
+        &self,
+        key: &str,
+        value: T,
+        ttl: Option<Duration>,
+    ) -> Result<bool, Self::Error> {
+        (**self).set_if_absent(key, value, ttl)
+    }
+
+    fn compare_and_swap(
+        &self,
+        key: &str,
+        expected: Option<&T>,
+        value: T,
+    ) -> Result<bool, Self::Error> {
+        (**self).compare_and_swap(key, expected, value)
+    }
+}
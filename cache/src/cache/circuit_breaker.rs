@@ -0,0 +1,396 @@
+//! Circuit breaker for caches backed by remote services.
+//!
+//! After `failure_threshold` consecutive failures the breaker opens, and for the cool-down period
+//! the backend is not called: reads behave like on a `NullCache`, while writes fail with `CircuitBreakerError::Open`,
+//! since dropping them would leave stale values in the backend. After the cool-down a single
+//! trial call is let through: if it succeeds the breaker closes, otherwise it opens again.
+use failure::Fail;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant};
+
+use super::clock::{Clock, SystemClock};
+use super::Cache;
+
+const DEFAULT_FAILURE_THRESHOLD: usize = 5;
+const DEFAULT_COOL_DOWN_SECS: u64 = 30;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum State {
+    Closed {
+        failures: usize,
+    },
+    Open {
+        until: Instant,
+    },
+    /// Trial call is in progress
+    HalfOpen,
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    failures: AtomicUsize,
+    rejections: AtomicUsize,
+    trips: AtomicUsize,
+}
+
+/// Snapshot of `CircuitBreakerCache` counters
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CircuitBreakerStats {
+    /// Failed backend calls
+    pub failures: usize,
+    /// Calls answered without the backend while the breaker was open
+    pub rejections: usize,
+    /// Times the breaker has opened
+    pub trips: usize,
+    pub open: bool,
+}
+
+#[derive(Debug, Fail)]
+pub enum CircuitBreakerError<E>
+where
+    E: Fail,
+{
+    /// Write has not been attempted, since the breaker is open
+    #[fail(display = "Cache circuit breaker is open")]
+    Open,
+    #[fail(display = "An error occurred in backend cache: {}", _0)]
+    BackendCacheError(E),
+}
+
+/// Cache wrapper that stops calling a failing backend for a while. Clones share the breaker state.
+///
+/// By default failures of reads are still returned to the caller while the breaker is closed.
+/// With fallthrough, they are logged and the read behaves as on a `NullCache` instead.
+/// Failures of writes are always returned.
+#[derive(Clone, Debug)]
+pub struct CircuitBreakerCache<C> {
+    cache: C,
+    state: Arc<Mutex<State>>,
+    counters: Arc<Counters>,
+    clock: Arc<Clock>,
+    failure_threshold: usize,
+    cool_down: Duration,
+    fallthrough: bool,
+}
+
+impl<C> CircuitBreakerCache<C> {
+    pub fn new(cache: C) -> Self {
+        CircuitBreakerCache {
+            cache,
+            state: Arc::new(Mutex::new(State::Closed { failures: 0 })),
+            counters: Arc::new(Counters::default()),
+            clock: Arc::new(SystemClock),
+            failure_threshold: DEFAULT_FAILURE_THRESHOLD,
+            cool_down: Duration::from_secs(DEFAULT_COOL_DOWN_SECS),
+            fallthrough: false,
+        }
+    }
+
+    /// Number of consecutive failures that opens the breaker, 5 by default
+    pub fn with_failure_threshold(self, failure_threshold: usize) -> Self {
+        CircuitBreakerCache {
+            failure_threshold: failure_threshold.max(1),
+            ..self
+        }
+    }
+
+    /// How long the breaker stays open, 30 seconds by default
+    pub fn with_cool_down(self, cool_down: Duration) -> Self {
+        CircuitBreakerCache { cool_down, ..self }
+    }
+
+    pub fn with_fallthrough(self) -> Self {
+        CircuitBreakerCache {
+            fallthrough: true,
+            ..self
+        }
+    }
+
+    /// Clock that decides when the cool-down ends, the system clock by default
+    pub fn with_clock<K: Clock + 'static>(self, clock: K) -> Self {
+        CircuitBreakerCache {
+            clock: Arc::new(clock),
+            ..self
+        }
+    }
+
+    pub fn cache(&self) -> &C {
+        &self.cache
+    }
+
+    pub fn is_open(&self) -> bool {
+        match *self.lock_state() {
+            State::Closed { .. } => false,
+            State::Open { .. } | State::HalfOpen => true,
+        }
+    }
+
+    pub fn stats(&self) -> CircuitBreakerStats {
+        CircuitBreakerStats {
+            failures: self.counters.failures.load(Ordering::Relaxed),
+            rejections: self.counters.rejections.load(Ordering::Relaxed),
+            trips: self.counters.trips.load(Ordering::Relaxed),
+            open: self.is_open(),
+        }
+    }
+
+    fn lock_state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Whether the backend may be called
+    fn allows_call(&self) -> bool {
+        let mut state = self.lock_state();
+        match *state {
+            State::Closed { .. } => true,
+            State::Open { until } if self.clock.now() >= until => {
+                *state = State::HalfOpen;
+                true
+            }
+            State::Open { .. } | State::HalfOpen => false,
+        }
+    }
+
+    fn record_success(&self) {
+        *self.lock_state() = State::Closed { failures: 0 };
+    }
+
+    fn record_failure(&self) {
+        self.counters.failures.fetch_add(1, Ordering::Relaxed);
+
+        let mut state = self.lock_state();
+        let failures = match *state {
+            State::Closed { failures } => failures + 1,
+            State::HalfOpen => self.failure_threshold,
+            State::Open { .. } => return,
+        };
+        if failures < self.failure_threshold {
+            *state = State::Closed { failures };
+            return;
+        }
+
+        self.counters.trips.fetch_add(1, Ordering::Relaxed);
+        warn!(
+            "Cache circuit breaker has opened for {} seconds",
+            self.cool_down.as_secs()
+        );
+        *state = State::Open {
+            until: self.clock.now() + self.cool_down,
+        };
+    }
+
+    /// Calls the backend with `f`, recording the outcome. Returns `None` if the breaker is open.
+    fn call<R, E, F>(&self, f: F) -> Option<Result<R, E>>
+    where
+        E: Fail,
+        F: FnOnce(&C) -> Result<R, E>,
+    {
+        if !self.allows_call() {
+            self.counters.rejections.fetch_add(1, Ordering::Relaxed);
+            return None;
+        }
+
+        let res = f(&self.cache);
+        match res {
+            Ok(_) => self.record_success(),
+            Err(_) => self.record_failure(),
+        }
+        Some(res)
+    }
+
+    /// Reads with `f`, or returns `fallback` if the breaker is open
+    fn read<R, E, F>(&self, f: F, fallback: R) -> Result<R, CircuitBreakerError<E>>
+    where
+        E: Fail,
+        F: FnOnce(&C) -> Result<R, E>,
+    {
+        match self.call(f) {
+            None => Ok(fallback),
+            Some(Ok(res)) => Ok(res),
+            Some(Err(ref e)) if self.fallthrough => {
+                warn!("Cache call failed: {}", e);
+                Ok(fallback)
+            }
+            Some(Err(e)) => Err(CircuitBreakerError::BackendCacheError(e)),
+        }
+    }
+
+    /// Writes with `f`, failing if the breaker is open
+    fn write<R, E, F>(&self, f: F) -> Result<R, CircuitBreakerError<E>>
+    where
+        E: Fail,
+        F: FnOnce(&C) -> Result<R, E>,
+    {
+        match self.call(f) {
+            None => Err(CircuitBreakerError::Open),
+            Some(res) => res.map_err(CircuitBreakerError::BackendCacheError),
+        }
+    }
+}
+
+impl<C, T> Cache<T> for CircuitBreakerCache<C>
+where
+    C: Cache<T>,
+{
+    type Error = CircuitBreakerError<C::Error>;
+
+    fn get(&self, key: &str) -> Result<Option<T>, Self::Error> {
+        self.read(|cache| cache.get(key), None)
+    }
+
+    fn set(&self, key: &str, value: T) -> Result<(), Self::Error> {
+        self.write(|cache| cache.set(key, value))
+    }
+
+    fn set_with_ttl(&self, key: &str, value: T, ttl: Duration) -> Result<(), Self::Error> {
+        self.write(|cache| cache.set_with_ttl(key, value, ttl))
+    }
+
+    fn remove(&self, key: &str) -> Result<bool, Self::Error> {
+        self.write(|cache| cache.remove(key))
+    }
+
+    fn clear(&self) -> Result<(), Self::Error> {
+        self.write(|cache| cache.clear())
+    }
+
+    fn get_many(&self, keys: &[&str]) -> Result<Vec<Option<T>>, Self::Error> {
+        self.read(
+            |cache| cache.get_many(keys),
+            keys.iter().map(|_| None).collect(),
+        )
+    }
+
+    fn set_many(&self, items: Vec<(&str, T)>) -> Result<(), Self::Error> {
+        self.write(|cache| cache.set_many(items))
+    }
+
+    fn remove_many(&self, keys: &[&str]) -> Result<usize, Self::Error> {
+        self.write(|cache| cache.remove_many(keys))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cache::ManualClock;
+    use std::cell::Cell;
+
+    #[derive(Debug, Fail)]
+    #[fail(display = "Backend is down")]
+    struct BackendDown;
+
+    /// Backend that fails while `down` is set
+    #[derive(Default)]
+    struct FlakyCache {
+        down: Cell<bool>,
+        calls: Cell<usize>,
+    }
+
+    impl Cache<u32> for FlakyCache {
+        type Error = BackendDown;
+
+        fn get(&self, _key: &str) -> Result<Option<u32>, Self::Error> {
+            self.calls.set(self.calls.get() + 1);
+            if self.down.get() {
+                Err(BackendDown)
+            } else {
+                Ok(Some(1))
+            }
+        }
+
+        fn set(&self, _key: &str, _value: u32) -> Result<(), Self::Error> {
+            self.get("").map(|_| ())
+        }
+
+        fn remove(&self, _key: &str) -> Result<bool, Self::Error> {
+            self.get("").map(|_| true)
+        }
+
+        fn clear(&self) -> Result<(), Self::Error> {
+            self.get("").map(|_| ())
+        }
+    }
+
+    #[test]
+    fn test_opens_after_failures() {
+        let cache = CircuitBreakerCache::new(FlakyCache::default())
+            .with_failure_threshold(2)
+            .with_cool_down(Duration::from_millis(100));
+        cache.cache().down.set(true);
+
+        assert!(cache.get("key").is_err());
+        assert!(!cache.is_open());
+        assert!(cache.get("key").is_err());
+        assert!(cache.is_open());
+
+        assert_eq!(cache.get("key").unwrap(), None);
+        assert_eq!(cache.get_many(&["a", "b"]).unwrap(), vec![None, None]);
+        // Writes are not dropped silently
+        for res in &[
+            cache.set("key", 1),
+            cache.clear(),
+            cache.remove("key").map(|_| ()),
+        ] {
+            match res {
+                Err(CircuitBreakerError::Open) => {}
+                res => panic!("Unexpected result: {:?}", res),
+            }
+        }
+        match cache.remove_many(&["a", "b"]) {
+            Err(CircuitBreakerError::Open) => {}
+            res => panic!("Unexpected result: {:?}", res),
+        }
+        assert_eq!(cache.cache().calls.get(), 2);
+        assert_eq!(
+            cache.stats(),
+            CircuitBreakerStats {
+                failures: 2,
+                rejections: 6,
+                trips: 1,
+                open: true,
+            }
+        );
+    }
+
+    #[test]
+    fn test_trial_call_after_cool_down() {
+        let clock = ManualClock::new();
+        let cache = CircuitBreakerCache::new(FlakyCache::default())
+            .with_failure_threshold(1)
+            .with_cool_down(Duration::from_millis(50))
+            .with_clock(clock.clone());
+        cache.cache().down.set(true);
+
+        assert!(cache.get("key").is_err());
+        clock.advance(Duration::from_millis(49));
+        assert_eq!(cache.get("key").unwrap(), None);
+        clock.advance(Duration::from_millis(1));
+
+        // Failed trial opens the breaker again right away
+        assert!(cache.get("key").is_err());
+        assert!(cache.is_open());
+        assert_eq!(cache.stats().trips, 2);
+
+        cache.cache().down.set(false);
+        assert_eq!(cache.get("key").unwrap(), None);
+        clock.advance(Duration::from_millis(50));
+        assert_eq!(cache.get("key").unwrap(), Some(1));
+        assert!(!cache.is_open());
+    }
+
+    #[test]
+    fn test_fallthrough() {
+        let cache = CircuitBreakerCache::new(FlakyCache::default()).with_fallthrough();
+        cache.cache().down.set(true);
+
+        assert_eq!(cache.get("key").unwrap(), None);
+        // Failed writes are returned regardless
+        match cache.remove("key") {
+            Err(CircuitBreakerError::BackendCacheError(BackendDown)) => {}
+            res => panic!("Unexpected result: {:?}", res),
+        }
+        assert_eq!(cache.stats().failures, 2);
+    }
+}
@@ -0,0 +1,289 @@
+//! Serialization of cached values.
+//!
+//! Values are stored as `~{format}.{version}~{base64 of encoded value}`, with `.z` appended to the version
+//! if the value is compressed. Uncompressed JSON is stored as is, which is also how `TypedCache` stored
+//! everything before codecs existed, so values without a prefix are read as JSON.
+//! Values can be read regardless of the format the codec writes, so changing the format does not break old entries.
+use base64;
+use bincode;
+use flate2::read::ZlibDecoder;
+use flate2::write::ZlibEncoder;
+use flate2::Compression;
+use rmp_serde;
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json;
+use std::io::{self, Read, Write};
+
+/// Version of the prefixed layout
+const VERSION: &str = "1";
+const PREFIX: char = '~';
+const COMPRESSED: &str = "z";
+/// Default limit of decompressed values, so that a small corrupted or malicious value cannot exhaust memory
+const DEFAULT_MAX_DECOMPRESSED_SIZE: usize = 64 * 1024 * 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    Json,
+    MessagePack,
+    Bincode,
+}
+
+impl Format {
+    fn name(self) -> &'static str {
+        match self {
+            Format::Json => "json",
+            Format::MessagePack => "msgpack",
+            Format::Bincode => "bincode",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "json" => Some(Format::Json),
+            "msgpack" => Some(Format::MessagePack),
+            "bincode" => Some(Format::Bincode),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Fail)]
+pub enum CodecError {
+    #[fail(display = "JSON error: {}", _0)]
+    Json(serde_json::Error),
+    #[fail(display = "MessagePack encoding error: {}", _0)]
+    MessagePackEncode(rmp_serde::encode::Error),
+    #[fail(display = "MessagePack decoding error: {}", _0)]
+    MessagePackDecode(rmp_serde::decode::Error),
+    #[fail(display = "Bincode error: {}", _0)]
+    Bincode(bincode::Error),
+    #[fail(display = "Compression error: {}", _0)]
+    Compression(io::Error),
+    #[fail(display = "Decompressed value exceeds {} bytes", _0)]
+    TooLarge(usize),
+    #[fail(display = "Base64 error: {}", _0)]
+    Base64(base64::DecodeError),
+    #[fail(display = "Unknown value format: {}", _0)]
+    UnknownFormat(String),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Codec {
+    format: Format,
+    compression_threshold: Option<usize>,
+    max_decompressed_size: usize,
+}
+
+impl Default for Codec {
+    fn default() -> Self {
+        Codec::new(Format::Json)
+    }
+}
+
+impl Codec {
+    pub fn new(format: Format) -> Self {
+        Codec {
+            format,
+            compression_threshold: None,
+            max_decompressed_size: DEFAULT_MAX_DECOMPRESSED_SIZE,
+        }
+    }
+
+    /// Compresses encoded values of at least `threshold` bytes
+    pub fn with_compression(self, threshold: usize) -> Self {
+        Codec {
+            compression_threshold: Some(threshold),
+            ..self
+        }
+    }
+
+    /// Maximum size of decompressed values, 64 MiB by default. Larger values fail to decode with `TooLarge`.
+    pub fn with_max_decompressed_size(self, max_decompressed_size: usize) -> Self {
+        Codec {
+            max_decompressed_size,
+            ..self
+        }
+    }
+
+    pub fn encode<T: Serialize>(&self, value: &T) -> Result<String, CodecError> {
+        let bytes = match self.format {
+            Format::Json => serde_json::to_vec(value).map_err(CodecError::Json)?,
+            Format::MessagePack => {
+                rmp_serde::to_vec_named(value).map_err(CodecError::MessagePackEncode)?
+            }
+            Format::Bincode => bincode::serialize(value).map_err(CodecError::Bincode)?,
+        };
+
+        let compress = self
+            .compression_threshold
+            .map(|threshold| bytes.len() >= threshold)
+            .unwrap_or(false);
+
+        if self.format == Format::Json && !compress {
+            return String::from_utf8(bytes)
+                .map_err(|_| CodecError::UnknownFormat("JSON is not valid UTF-8".to_string()));
+        }
+
+        let (bytes, suffix) = if compress {
+            let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(&bytes).map_err(CodecError::Compression)?;
+            let compressed = encoder.finish().map_err(CodecError::Compression)?;
+            (compressed, format!(".{}", COMPRESSED))
+        } else {
+            (bytes, String::new())
+        };
+
+        Ok(format!(
+            "{}{}.{}{}{}{}",
+            PREFIX,
+            self.format.name(),
+            VERSION,
+            suffix,
+            PREFIX,
+            base64::encode(&bytes)
+        ))
+    }
+
+    pub fn decode<T: DeserializeOwned>(&self, s: &str) -> Result<T, CodecError> {
+        if !s.starts_with(PREFIX) {
+            return serde_json::from_str(s).map_err(CodecError::Json);
+        }
+
+        let mut parts = s[1..].splitn(2, PREFIX);
+        let (header, body) = match (parts.next(), parts.next()) {
+            (Some(header), Some(body)) => (header, body),
+            _ => return Err(CodecError::UnknownFormat(s.chars().take(32).collect())),
+        };
+
+        let header_parts = header.split('.').collect::<Vec<_>>();
+        let (format, compressed) = match header_parts.as_slice() {
+            [name, version] if *version == VERSION => (Format::from_name(name), false),
+            [name, version, flag] if *version == VERSION && *flag == COMPRESSED => {
+                (Format::from_name(name), true)
+            }
+            _ => (None, false),
+        };
+        let format = format.ok_or_else(|| CodecError::UnknownFormat(header.to_string()))?;
+
+        let mut bytes = base64::decode(body).map_err(CodecError::Base64)?;
+        if compressed {
+            // Reading one byte past the limit tells values of exactly the limit from larger ones
+            let mut decompressed = Vec::new();
+            ZlibDecoder::new(bytes.as_slice())
+                .take((self.max_decompressed_size as u64).saturating_add(1))
+                .read_to_end(&mut decompressed)
+                .map_err(CodecError::Compression)?;
+            if decompressed.len() > self.max_decompressed_size {
+                return Err(CodecError::TooLarge(self.max_decompressed_size));
+            }
+            bytes = decompressed;
+        }
+
+        match format {
+            Format::Json => serde_json::from_slice(&bytes).map_err(CodecError::Json),
+            Format::MessagePack => {
+                rmp_serde::from_slice(&bytes).map_err(CodecError::MessagePackDecode)
+            }
+            Format::Bincode => bincode::deserialize(&bytes).map_err(CodecError::Bincode),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+    struct TestStruct {
+        pub s: String,
+        pub i: i32,
+    }
+
+    fn value() -> TestStruct {
+        TestStruct {
+            s: "string".repeat(20),
+            i: 10,
+        }
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        for format in &[Format::Json, Format::MessagePack, Format::Bincode] {
+            for codec in &[Codec::new(*format), Codec::new(*format).with_compression(0)] {
+                let encoded = codec.encode(&value()).unwrap();
+                assert_eq!(
+                    codec.decode::<TestStruct>(&encoded).unwrap(),
+                    value(),
+                    "{}",
+                    encoded
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_prefixes() {
+        assert!(Codec::default().encode(&value()).unwrap().starts_with('{'));
+        assert!(Codec::new(Format::MessagePack)
+            .encode(&value())
+            .unwrap()
+            .starts_with("~msgpack.1~"));
+        assert!(Codec::new(Format::Json)
+            .with_compression(10)
+            .encode(&value())
+            .unwrap()
+            .starts_with("~json.1.z~"));
+        assert!(Codec::new(Format::Bincode)
+            .with_compression(1000)
+            .encode(&value())
+            .unwrap()
+            .starts_with("~bincode.1~"));
+    }
+
+    #[test]
+    fn test_reads_other_formats() {
+        let encoded = Codec::new(Format::Bincode)
+            .with_compression(0)
+            .encode(&value())
+            .unwrap();
+        assert_eq!(
+            Codec::default().decode::<TestStruct>(&encoded).unwrap(),
+            value()
+        );
+
+        let legacy = r#"{"s": "legacy", "i": 1}"#;
+        assert_eq!(
+            Codec::new(Format::MessagePack)
+                .decode::<TestStruct>(legacy)
+                .unwrap(),
+            TestStruct {
+                s: "legacy".to_string(),
+                i: 1
+            }
+        );
+
+        match Codec::default().decode::<TestStruct>("~msgpack.2~AAAA") {
+            Err(CodecError::UnknownFormat(header)) => assert_eq!(header, "msgpack.2"),
+            res => panic!("Unexpected result: {:?}", res),
+        }
+    }
+
+    #[test]
+    fn test_decompressed_size_limit() {
+        let encoded = Codec::new(Format::Bincode)
+            .with_compression(0)
+            .encode(&value())
+            .unwrap();
+        let size = bincode::serialize(&value()).unwrap().len();
+
+        let codec = Codec::default().with_max_decompressed_size(size);
+        assert_eq!(codec.decode::<TestStruct>(&encoded).unwrap(), value());
+        match Codec::default()
+            .with_max_decompressed_size(size - 1)
+            .decode::<TestStruct>(&encoded)
+        {
+            Err(CodecError::TooLarge(limit)) => assert_eq!(limit, size - 1),
+            res => panic!("Unexpected result: {:?}", res),
+        }
+    }
+}
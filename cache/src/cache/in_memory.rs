@@ -1,14 +1,268 @@
 use std::collections::HashMap;
-use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
 
-use super::Cache;
+use super::clock::{Clock, SystemClock};
+use super::lock::LeaseBackend;
+use super::{AtomicCache, Cache};
 
+#[derive(Debug)]
+struct Entry<T> {
+    value: T,
+    expires_at: Option<Instant>,
+    /// Tick of the last access, for LRU eviction
+    last_used: AtomicUsize,
+}
+
+impl<T> Entry<T> {
+    fn is_expired(&self, now: Instant) -> bool {
+        self.expires_at
+            .map(|expires_at| expires_at <= now)
+            .unwrap_or(false)
+    }
+}
+
+#[derive(Debug)]
+struct State<T> {
+    entries: HashMap<String, Entry<T>>,
+    tick: AtomicUsize,
+    last_sweep: Instant,
+}
+
+impl<T> State<T> {
+    fn next_tick(&self) -> usize {
+        self.tick.fetch_add(1, Ordering::Relaxed)
+    }
+
+    fn remove_expired(&mut self, now: Instant) -> usize {
+        let len = self.entries.len();
+        self.entries.retain(|_, entry| !entry.is_expired(now));
+        self.last_sweep = now;
+        len - self.entries.len()
+    }
+
+    fn evict_least_recently_used(&mut self) -> bool {
+        let key = self
+            .entries
+            .iter()
+            .min_by_key(|(_, entry)| entry.last_used.load(Ordering::Relaxed))
+            .map(|(key, _)| key.clone());
+        match key {
+            Some(key) => self.entries.remove(&key).is_some(),
+            None => false,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Lease {
+    token: String,
+    expires_at: Instant,
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    hits: AtomicUsize,
+    misses: AtomicUsize,
+    evictions: AtomicUsize,
+    expirations: AtomicUsize,
+}
+
+/// Snapshot of `InMemoryCache` counters
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct InMemoryCacheStats {
+    pub hits: usize,
+    pub misses: usize,
+    /// Entries removed to make room for new ones
+    pub evictions: usize,
+    /// Entries removed because their TTL has passed
+    pub expirations: usize,
+}
+
+/// In-memory cache with optional TTL and capacity. Clones share entries and counters.
+///
+/// Expired entries are never returned and are removed on read, on `sweep` or,
+/// if a sweep interval is set, by writes once the interval has passed.
+/// When the cache is full, expired entries are removed first, then the least recently used one.
+/// Eviction scans all entries, so capacity is meant for caches of moderate size.
+///
+/// Leases of `LeaseBackend` are kept apart from entries, so they are not affected by writes, eviction or `clear`.
 #[derive(Clone, Debug)]
-pub struct InMemoryCache<T>(Arc<RwLock<HashMap<String, T>>>);
+pub struct InMemoryCache<T> {
+    state: Arc<RwLock<State<T>>>,
+    counters: Arc<Counters>,
+    leases: Arc<Mutex<HashMap<String, Lease>>>,
+    clock: Arc<Clock>,
+    ttl: Option<Duration>,
+    capacity: Option<usize>,
+    sweep_interval: Option<Duration>,
+}
+
+impl<T> Default for InMemoryCache<T> {
+    fn default() -> Self {
+        InMemoryCache {
+            state: Arc::new(RwLock::new(State {
+                entries: HashMap::default(),
+                tick: AtomicUsize::new(0),
+                last_sweep: Instant::now(),
+            })),
+            counters: Arc::new(Counters::default()),
+            leases: Arc::new(Mutex::new(HashMap::default())),
+            clock: Arc::new(SystemClock),
+            ttl: None,
+            capacity: None,
+            sweep_interval: None,
+        }
+    }
+}
 
 impl<T> InMemoryCache<T> {
     pub fn new() -> InMemoryCache<T> {
-        InMemoryCache(Arc::new(RwLock::new(HashMap::default())))
+        Self::default()
+    }
+
+    /// TTL of entries set with `Cache::set`
+    pub fn with_ttl(self, ttl: Duration) -> Self {
+        InMemoryCache {
+            ttl: Some(ttl),
+            ..self
+        }
+    }
+
+    /// Maximum number of entries
+    pub fn with_capacity(self, capacity: usize) -> Self {
+        InMemoryCache {
+            capacity: Some(capacity),
+            ..self
+        }
+    }
+
+    /// Lets writes remove expired entries once `interval` has passed since the last sweep
+    pub fn with_sweep_interval(self, interval: Duration) -> Self {
+        InMemoryCache {
+            sweep_interval: Some(interval),
+            ..self
+        }
+    }
+
+    /// Clock that decides when entries and leases expire, the system clock by default
+    pub fn with_clock<C: Clock + 'static>(self, clock: C) -> Self {
+        if let Ok(mut state) = self.state.write() {
+            state.last_sweep = clock.now();
+        }
+        InMemoryCache {
+            clock: Arc::new(clock),
+            ..self
+        }
+    }
+
+    /// Removes expired entries and returns their number
+    pub fn sweep(&self) -> Result<usize, InMemoryCacheError> {
+        let mut state = self.state.write().map_err(|_| InMemoryCacheError)?;
+        let expired = state.remove_expired(self.clock.now());
+        self.counters
+            .expirations
+            .fetch_add(expired, Ordering::Relaxed);
+        Ok(expired)
+    }
+
+    /// Number of entries, including the expired ones that have not been removed yet
+    pub fn len(&self) -> Result<usize, InMemoryCacheError> {
+        let state = self.state.read().map_err(|_| InMemoryCacheError)?;
+        Ok(state.entries.len())
+    }
+
+    pub fn is_empty(&self) -> Result<bool, InMemoryCacheError> {
+        self.len().map(|len| len == 0)
+    }
+
+    pub fn stats(&self) -> InMemoryCacheStats {
+        InMemoryCacheStats {
+            hits: self.counters.hits.load(Ordering::Relaxed),
+            misses: self.counters.misses.load(Ordering::Relaxed),
+            evictions: self.counters.evictions.load(Ordering::Relaxed),
+            expirations: self.counters.expirations.load(Ordering::Relaxed),
+        }
+    }
+
+    fn insert(
+        &self,
+        key: &str,
+        value: T,
+        expires_at: Option<Instant>,
+    ) -> Result<(), InMemoryCacheError> {
+        let mut state = self.state.write().map_err(|_| InMemoryCacheError)?;
+        self.insert_locked(&mut state, key, value, expires_at, self.clock.now());
+        Ok(())
+    }
+
+    fn insert_locked(
+        &self,
+        state: &mut State<T>,
+        key: &str,
+        value: T,
+        expires_at: Option<Instant>,
+        now: Instant,
+    ) {
+        if let Some(interval) = self.sweep_interval {
+            if now.duration_since(state.last_sweep) >= interval {
+                let expired = state.remove_expired(now);
+                self.counters
+                    .expirations
+                    .fetch_add(expired, Ordering::Relaxed);
+            }
+        }
+
+        if let Some(capacity) = self.capacity {
+            if !state.entries.contains_key(key) && state.entries.len() >= capacity {
+                let expired = state.remove_expired(now);
+                self.counters
+                    .expirations
+                    .fetch_add(expired, Ordering::Relaxed);
+            }
+            while !state.entries.contains_key(key)
+                && state.entries.len() >= capacity
+                && state.evict_least_recently_used()
+            {
+                self.counters.evictions.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+
+        let last_used = AtomicUsize::new(state.next_tick());
+        state.entries.insert(
+            key.to_string(),
+            Entry {
+                value,
+                expires_at,
+                last_used,
+            },
+        );
+    }
+
+    /// Same as `Cache::get`, but for the caller holding the write lock
+    fn get_locked(&self, state: &mut State<T>, key: &str, now: Instant) -> Option<T>
+    where
+        T: Clone,
+    {
+        let expired = match state.entries.get(key) {
+            None => false,
+            Some(entry) => {
+                if !entry.is_expired(now) {
+                    entry.last_used.store(state.next_tick(), Ordering::Relaxed);
+                    self.counters.hits.fetch_add(1, Ordering::Relaxed);
+                    return Some(entry.value.clone());
+                }
+                true
+            }
+        };
+
+        if expired {
+            state.entries.remove(key);
+            self.counters.expirations.fetch_add(1, Ordering::Relaxed);
+        }
+        self.counters.misses.fetch_add(1, Ordering::Relaxed);
+        None
     }
 }
 
@@ -16,6 +270,12 @@ impl<T> InMemoryCache<T> {
 #[fail(display = "Unexpected error occurred in in-memory cache")]
 pub struct InMemoryCacheError;
 
+/// Counters share the error type of the cache, so the reason is only logged
+fn invalid_counter(key: &str) -> InMemoryCacheError {
+    warn!("Value of {} is not an integer or the result overflows", key);
+    InMemoryCacheError
+}
+
 impl<T> Cache<T> for InMemoryCache<T>
 where
     T: Clone,
@@ -23,24 +283,341 @@ where
     type Error = InMemoryCacheError;
 
     fn get(&self, key: &str) -> Result<Option<T>, Self::Error> {
-        let lock = self.0.clone();
-        let hash_map = lock.read().map_err(|_| InMemoryCacheError)?;
-        Ok(hash_map.get(key).cloned())
+        let now = self.clock.now();
+        {
+            let state = self.state.read().map_err(|_| InMemoryCacheError)?;
+            match state.entries.get(key) {
+                None => {
+                    self.counters.misses.fetch_add(1, Ordering::Relaxed);
+                    return Ok(None);
+                }
+                Some(entry) => {
+                    if !entry.is_expired(now) {
+                        entry.last_used.store(state.next_tick(), Ordering::Relaxed);
+                        self.counters.hits.fetch_add(1, Ordering::Relaxed);
+                        return Ok(Some(entry.value.clone()));
+                    }
+                }
+            }
+        }
+
+        let mut state = self.state.write().map_err(|_| InMemoryCacheError)?;
+        Ok(self.get_locked(&mut state, key, now))
     }
 
     fn set(&self, key: &str, value: T) -> Result<(), Self::Error> {
-        let lock = self.0.clone();
-        let mut hash_map = lock.write().map_err(|_| InMemoryCacheError)?;
-        hash_map.insert(key.to_string(), value);
-        Ok(())
+        let expires_at = self.ttl.map(|ttl| self.clock.now() + ttl);
+        self.insert(key, value, expires_at)
+    }
+
+    fn set_with_ttl(&self, key: &str, value: T, ttl: Duration) -> Result<(), Self::Error> {
+        self.insert(key, value, Some(self.clock.now() + ttl))
     }
 
     fn remove(&self, key: &str) -> Result<bool, Self::Error> {
-        let lock = self.0.clone();
-        let mut hash_map = lock.write().map_err(|_| InMemoryCacheError)?;
-        Ok(match hash_map.remove(key) {
+        let mut state = self.state.write().map_err(|_| InMemoryCacheError)?;
+        Ok(match state.entries.remove(key) {
             None => false,
-            Some(_) => true,
+            Some(entry) => !entry.is_expired(self.clock.now()),
         })
     }
+
+    fn clear(&self) -> Result<(), Self::Error> {
+        let mut state = self.state.write().map_err(|_| InMemoryCacheError)?;
+        state.entries.clear();
+        Ok(())
+    }
+
+    fn get_many(&self, keys: &[&str]) -> Result<Vec<Option<T>>, Self::Error> {
+        let now = self.clock.now();
+        let mut state = self.state.write().map_err(|_| InMemoryCacheError)?;
+        Ok(keys
+            .iter()
+            .map(|key| self.get_locked(&mut state, key, now))
+            .collect())
+    }
+
+    fn set_many(&self, items: Vec<(&str, T)>) -> Result<(), Self::Error> {
+        let now = self.clock.now();
+        let expires_at = self.ttl.map(|ttl| now + ttl);
+        let mut state = self.state.write().map_err(|_| InMemoryCacheError)?;
+        for (key, value) in items {
+            self.insert_locked(&mut state, key, value, expires_at, now);
+        }
+        Ok(())
+    }
+
+    fn remove_many(&self, keys: &[&str]) -> Result<usize, Self::Error> {
+        let now = self.clock.now();
+        let mut state = self.state.write().map_err(|_| InMemoryCacheError)?;
+        Ok(keys
+            .iter()
+            .filter_map(|key| state.entries.remove(*key))
+            .filter(|entry| !entry.is_expired(now))
+            .count())
+    }
+}
+
+impl AtomicCache<String> for InMemoryCache<String> {
+    fn incr_by(&self, key: &str, delta: i64, ttl: Option<Duration>) -> Result<i64, Self::Error> {
+        let now = self.clock.now();
+        let mut state = self.state.write().map_err(|_| InMemoryCacheError)?;
+        let (current, expires_at) = match state.entries.get(key) {
+            Some(entry) if !entry.is_expired(now) => (
+                entry
+                    .value
+                    .parse::<i64>()
+                    .map_err(|_| invalid_counter(key))?,
+                entry.expires_at,
+            ),
+            _ => (0, ttl.or(self.ttl).map(|ttl| now + ttl)),
+        };
+        let value = current
+            .checked_add(delta)
+            .ok_or_else(|| invalid_counter(key))?;
+
+        self.insert_locked(&mut state, key, value.to_string(), expires_at, now);
+        Ok(value)
+    }
+
+    fn set_if_absent(
+        &self,
+        key: &str,
+        value: String,
+        ttl: Option<Duration>,
+    ) -> Result<bool, Self::Error> {
+        let now = self.clock.now();
+        let mut state = self.state.write().map_err(|_| InMemoryCacheError)?;
+        if state
+            .entries
+            .get(key)
+            .map(|entry| !entry.is_expired(now))
+            .unwrap_or(false)
+        {
+            return Ok(false);
+        }
+
+        let expires_at = ttl.or(self.ttl).map(|ttl| now + ttl);
+        self.insert_locked(&mut state, key, value, expires_at, now);
+        Ok(true)
+    }
+
+    fn compare_and_swap(
+        &self,
+        key: &str,
+        expected: Option<&String>,
+        value: String,
+    ) -> Result<bool, Self::Error> {
+        let now = self.clock.now();
+        let mut state = self.state.write().map_err(|_| InMemoryCacheError)?;
+        let matches = {
+            let current = state
+                .entries
+                .get(key)
+                .filter(|entry| !entry.is_expired(now))
+                .map(|entry| &entry.value);
+            current == expected
+        };
+        if !matches {
+            return Ok(false);
+        }
+
+        let expires_at = self.ttl.map(|ttl| now + ttl);
+        self.insert_locked(&mut state, key, value, expires_at, now);
+        Ok(true)
+    }
+}
+
+/// Leases are kept in a map of their own, shared by clones
+impl LeaseBackend for InMemoryCache<String> {
+    type Error = InMemoryCacheError;
+
+    fn try_acquire(&self, name: &str, token: &str, ttl: Duration) -> Result<bool, Self::Error> {
+        let now = self.clock.now();
+        let mut leases = self.leases.lock().map_err(|_| InMemoryCacheError)?;
+        leases.retain(|_, lease| lease.expires_at > now);
+        if leases.contains_key(name) {
+            return Ok(false);
+        }
+
+        leases.insert(
+            name.to_string(),
+            Lease {
+                token: token.to_string(),
+                expires_at: now + ttl,
+            },
+        );
+        Ok(true)
+    }
+
+    fn renew(&self, name: &str, token: &str, ttl: Duration) -> Result<bool, Self::Error> {
+        let now = self.clock.now();
+        let mut leases = self.leases.lock().map_err(|_| InMemoryCacheError)?;
+        match leases.get_mut(name) {
+            Some(ref mut lease) if lease.expires_at > now && lease.token == token => {
+                lease.expires_at = now + ttl;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    fn release(&self, name: &str, token: &str) -> Result<bool, Self::Error> {
+        let now = self.clock.now();
+        let mut leases = self.leases.lock().map_err(|_| InMemoryCacheError)?;
+        let held = match leases.get(name) {
+            Some(lease) => lease.expires_at > now && lease.token == token,
+            None => false,
+        };
+        if held {
+            leases.remove(name);
+        }
+        Ok(held)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cache::ManualClock;
+
+    #[test]
+    fn test_ttl() {
+        let clock = ManualClock::new();
+        let cache = InMemoryCache::new()
+            .with_ttl(Duration::from_millis(50))
+            .with_clock(clock.clone());
+        cache.set("key", 1).unwrap();
+        cache
+            .set_with_ttl("long_lived", 2, Duration::from_secs(60))
+            .unwrap();
+        assert_eq!(cache.get("key").unwrap(), Some(1));
+
+        clock.advance(Duration::from_millis(49));
+        assert_eq!(cache.get("key").unwrap(), Some(1));
+        clock.advance(Duration::from_millis(1));
+        assert_eq!(cache.get("key").unwrap(), None);
+        assert_eq!(cache.get("long_lived").unwrap(), Some(2));
+        assert_eq!(cache.len().unwrap(), 1);
+
+        assert_eq!(
+            cache.stats(),
+            InMemoryCacheStats {
+                hits: 3,
+                misses: 1,
+                evictions: 0,
+                expirations: 1,
+            }
+        );
+    }
+
+    #[test]
+    fn test_sweep() {
+        let clock = ManualClock::new();
+        let cache = InMemoryCache::new().with_clock(clock.clone());
+        cache
+            .set_with_ttl("a", 1, Duration::from_millis(10))
+            .unwrap();
+        cache
+            .set_with_ttl("b", 2, Duration::from_millis(10))
+            .unwrap();
+        cache.set("c", 3).unwrap();
+
+        clock.advance(Duration::from_millis(10));
+        assert_eq!(cache.sweep().unwrap(), 2);
+        assert_eq!(cache.len().unwrap(), 1);
+        assert!(!cache.remove("a").unwrap());
+    }
+
+    #[test]
+    fn test_lru_eviction() {
+        let cache = InMemoryCache::new().with_capacity(2);
+        cache.set("a", 1).unwrap();
+        cache.set("b", 2).unwrap();
+        cache.get("a").unwrap();
+        cache.set("c", 3).unwrap();
+
+        assert_eq!(cache.get("a").unwrap(), Some(1));
+        assert_eq!(cache.get("b").unwrap(), None);
+        assert_eq!(cache.get("c").unwrap(), Some(3));
+
+        // Overwriting does not evict anything
+        cache.set("c", 4).unwrap();
+        assert_eq!(cache.len().unwrap(), 2);
+        assert_eq!(cache.stats().evictions, 1);
+    }
+
+    #[test]
+    fn test_batch_operations() {
+        let cache = InMemoryCache::new().with_capacity(3);
+        cache
+            .set_many(vec![("a", 1), ("b", 2), ("c", 3), ("d", 4)])
+            .unwrap();
+
+        assert_eq!(
+            cache.get_many(&["a", "b", "d", "e"]).unwrap(),
+            vec![None, Some(2), Some(4), None]
+        );
+        assert_eq!(cache.remove_many(&["a", "b", "c"]).unwrap(), 2);
+        assert_eq!(cache.len().unwrap(), 1);
+    }
+
+    #[test]
+    fn test_clear() {
+        let cache = InMemoryCache::new();
+        cache.set_many(vec![("a", 1), ("b", 2)]).unwrap();
+
+        cache.clear().unwrap();
+        assert!(cache.is_empty().unwrap());
+        assert_eq!(cache.get("a").unwrap(), None);
+    }
+
+    #[test]
+    fn test_atomic_operations() {
+        let cache = InMemoryCache::new();
+
+        assert_eq!(cache.incr_by("counter", 2, None).unwrap(), 2);
+        assert_eq!(cache.incr_by("counter", -3, None).unwrap(), -1);
+        cache.set("text", "abc".to_string()).unwrap();
+        match cache.incr_by("text", 1, None) {
+            Err(InMemoryCacheError) => {}
+            res => panic!("Unexpected result: {:?}", res),
+        }
+
+        assert!(cache
+            .set_if_absent("marker", "1".to_string(), None)
+            .unwrap());
+        assert!(!cache
+            .set_if_absent("marker", "2".to_string(), None)
+            .unwrap());
+        assert_eq!(cache.get("marker").unwrap(), Some("1".to_string()));
+
+        assert!(!cache
+            .compare_and_swap("marker", None, "2".to_string())
+            .unwrap());
+        assert!(cache
+            .compare_and_swap("marker", Some(&"1".to_string()), "2".to_string())
+            .unwrap());
+        assert!(cache
+            .compare_and_swap("new", None, "1".to_string())
+            .unwrap());
+        assert_eq!(
+            cache.get_many(&["marker", "new"]).unwrap(),
+            vec![Some("2".to_string()), Some("1".to_string())]
+        );
+    }
+
+    #[test]
+    fn test_counter_ttl() {
+        let clock = ManualClock::new();
+        let cache = InMemoryCache::new().with_clock(clock.clone());
+        let ttl = Some(Duration::from_millis(50));
+
+        assert_eq!(cache.incr_by("counter", 1, ttl).unwrap(), 1);
+        clock.advance(Duration::from_millis(30));
+        // Increments do not extend the TTL
+        assert_eq!(cache.incr_by("counter", 1, ttl).unwrap(), 2);
+        clock.advance(Duration::from_millis(30));
+        assert_eq!(cache.incr_by("counter", 1, ttl).unwrap(), 1);
+    }
 }
@@ -0,0 +1,441 @@
+//! Invalidation of local caches across service instances.
+//!
+//! `InvalidatedCache` publishes every changed key on the Redis channel of the namespace,
+//! `{namespace}:__invalidate`, and `InvalidationSubscriber` removes published keys from the local caches
+//! of other instances. Messages are `{instance_id}:{key}`, so that instances can skip their own invalidations,
+//! or just `{instance_id}` when the whole cache has been cleared. Create the subscriber of an instance with
+//! `InvalidationPublisher::subscriber`, so that both share the instance id.
+use r2d2_redis::{
+    r2d2::{ManageConnection, Pool},
+    redis::{cmd, Client, Connection as RedisConnection, RedisError},
+};
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use super::Cache;
+
+/// How often the subscriber checks if it has been stopped, in seconds
+const POLL_INTERVAL_SECS: u64 = 1;
+const RECONNECT_DELAY_SECS: u64 = 1;
+
+pub fn invalidation_channel(namespace: &str) -> String {
+    format!("{}:__invalidate", namespace)
+}
+
+fn generate_instance_id() -> String {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    format!(
+        "{}-{}{:09}",
+        process::id(),
+        now.as_secs(),
+        now.subsec_nanos()
+    )
+}
+
+#[derive(Debug, Fail)]
+pub enum InvalidationError {
+    #[fail(display = "No available Redis connections left")]
+    NoAvailableConnections,
+    #[fail(display = "{}", _0)]
+    RedisError(RedisError),
+    /// Instance ids are separated from keys with a colon in messages, so they must be non-empty and have no colons
+    #[fail(display = "Invalid cache invalidation instance id: {:?}", _0)]
+    InvalidInstanceId(String),
+    /// Listener thread has panicked before subscribing
+    #[fail(display = "Cache invalidation listener has panicked")]
+    ListenerPanicked,
+}
+
+impl From<RedisError> for InvalidationError {
+    fn from(e: RedisError) -> Self {
+        InvalidationError::RedisError(e)
+    }
+}
+
+fn validate_instance_id(instance_id: String) -> Result<String, InvalidationError> {
+    if instance_id.is_empty() || instance_id.contains(':') {
+        return Err(InvalidationError::InvalidInstanceId(instance_id));
+    }
+    Ok(instance_id)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Invalidation<'a> {
+    Key(&'a str),
+    All,
+}
+
+fn parse_message(payload: &str) -> Option<(&str, Invalidation<'_>)> {
+    let mut parts = payload.splitn(2, ':');
+    match (parts.next(), parts.next()) {
+        (Some(""), _) | (None, _) => None,
+        (Some(instance_id), Some(key)) => Some((instance_id, Invalidation::Key(key))),
+        (Some(instance_id), None) => Some((instance_id, Invalidation::All)),
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct InvalidationPublisher<M>
+where
+    M: ManageConnection<Connection = RedisConnection>,
+{
+    pool: Pool<M>,
+    channel: String,
+    instance_id: String,
+    acquire_timeout: Option<Duration>,
+}
+
+impl<M> InvalidationPublisher<M>
+where
+    M: ManageConnection<Connection = RedisConnection>,
+{
+    /// Publishes on the channel of `namespace`, which should be the namespace of the shared `RedisCache`
+    pub fn new(pool: Pool<M>, namespace: &str) -> Self {
+        InvalidationPublisher {
+            pool,
+            channel: invalidation_channel(namespace),
+            instance_id: generate_instance_id(),
+            acquire_timeout: None,
+        }
+    }
+
+    /// Instance id must be non-empty and must not contain colons. A unique one is generated by default.
+    pub fn with_instance_id(self, instance_id: String) -> Result<Self, InvalidationError> {
+        Ok(InvalidationPublisher {
+            instance_id: validate_instance_id(instance_id)?,
+            ..self
+        })
+    }
+
+    /// Waits for up to `timeout` for a pooled connection instead of failing
+    /// with `NoAvailableConnections` right away when all connections are in use
+    pub fn with_acquire_timeout(self, timeout: Duration) -> Self {
+        InvalidationPublisher {
+            acquire_timeout: Some(timeout),
+            ..self
+        }
+    }
+
+    pub fn instance_id(&self) -> &str {
+        &self.instance_id
+    }
+
+    /// Subscriber to the same channel that skips the invalidations of this publisher
+    pub fn subscriber(&self, client: Client) -> InvalidationSubscriber {
+        InvalidationSubscriber {
+            client,
+            channel: self.channel.clone(),
+            instance_id: Some(self.instance_id.clone()),
+            handlers: vec![],
+        }
+    }
+
+    pub fn publish(&self, key: &str) -> Result<(), InvalidationError> {
+        self.send(format!("{}:{}", self.instance_id, key))
+    }
+
+    /// Tells other instances to clear their caches
+    pub fn publish_clear(&self) -> Result<(), InvalidationError> {
+        self.send(self.instance_id.clone())
+    }
+
+    fn send(&self, message: String) -> Result<(), InvalidationError> {
+        let conn = match self.acquire_timeout {
+            None => self.pool.try_get(),
+            Some(timeout) => self.pool.get_timeout(timeout).ok(),
+        }
+        .ok_or(InvalidationError::NoAvailableConnections)?;
+        cmd("PUBLISH")
+            .arg(&self.channel)
+            .arg(message)
+            .query::<u32>(&*conn)
+            .map(|_| ())
+            .map_err(From::from)
+    }
+}
+
+/// Local cache that tells other instances about every key it changes.
+/// Failures to publish are logged and do not fail cache operations.
+#[derive(Clone, Debug)]
+pub struct InvalidatedCache<C, M>
+where
+    M: ManageConnection<Connection = RedisConnection>,
+{
+    cache: C,
+    publisher: InvalidationPublisher<M>,
+}
+
+impl<C, M> InvalidatedCache<C, M>
+where
+    M: ManageConnection<Connection = RedisConnection>,
+{
+    pub fn new(cache: C, publisher: InvalidationPublisher<M>) -> Self {
+        InvalidatedCache { cache, publisher }
+    }
+
+    pub fn publisher(&self) -> &InvalidationPublisher<M> {
+        &self.publisher
+    }
+
+    fn publish(&self, key: &str) {
+        if let Err(e) = self.publisher.publish(key) {
+            warn!("Failed to publish cache invalidation of {}: {}", key, e);
+        }
+    }
+}
+
+impl<C, M, T> Cache<T> for InvalidatedCache<C, M>
+where
+    C: Cache<T>,
+    M: ManageConnection<Connection = RedisConnection>,
+{
+    type Error = C::Error;
+
+    fn get(&self, key: &str) -> Result<Option<T>, Self::Error> {
+        self.cache.get(key)
+    }
+
+    fn set(&self, key: &str, value: T) -> Result<(), Self::Error> {
+        self.cache.set(key, value)?;
+        self.publish(key);
+        Ok(())
+    }
+
+    fn set_with_ttl(&self, key: &str, value: T, ttl: Duration) -> Result<(), Self::Error> {
+        self.cache.set_with_ttl(key, value, ttl)?;
+        self.publish(key);
+        Ok(())
+    }
+
+    fn remove(&self, key: &str) -> Result<bool, Self::Error> {
+        let removed = self.cache.remove(key)?;
+        self.publish(key);
+        Ok(removed)
+    }
+
+    fn clear(&self) -> Result<(), Self::Error> {
+        self.cache.clear()?;
+        if let Err(e) = self.publisher.publish_clear() {
+            warn!("Failed to publish cache clearing: {}", e);
+        }
+        Ok(())
+    }
+
+    fn get_many(&self, keys: &[&str]) -> Result<Vec<Option<T>>, Self::Error> {
+        self.cache.get_many(keys)
+    }
+
+    fn set_many(&self, items: Vec<(&str, T)>) -> Result<(), Self::Error> {
+        let keys = items.iter().map(|(key, _)| *key).collect::<Vec<_>>();
+        self.cache.set_many(items)?;
+        for key in keys {
+            self.publish(key);
+        }
+        Ok(())
+    }
+
+    fn remove_many(&self, keys: &[&str]) -> Result<usize, Self::Error> {
+        let removed = self.cache.remove_many(keys)?;
+        for key in keys {
+            self.publish(key);
+        }
+        Ok(removed)
+    }
+}
+
+type InvalidationHandler = Box<Fn(Invalidation) + Send>;
+
+/// Listens to invalidations of a namespace and passes them to the registered handlers.
+pub struct InvalidationSubscriber {
+    client: Client,
+    channel: String,
+    instance_id: Option<String>,
+    handlers: Vec<InvalidationHandler>,
+}
+
+impl InvalidationSubscriber {
+    /// Subscriber that handles all invalidations, including those of this instance.
+    /// Use `InvalidationPublisher::subscriber` to skip them.
+    pub fn new(client: Client, namespace: &str) -> Self {
+        InvalidationSubscriber {
+            client,
+            channel: invalidation_channel(namespace),
+            instance_id: None,
+            handlers: vec![],
+        }
+    }
+
+    /// Skips invalidations published by this instance, see `InvalidationPublisher::instance_id`
+    pub fn with_instance_id(self, instance_id: String) -> Result<Self, InvalidationError> {
+        Ok(InvalidationSubscriber {
+            instance_id: Some(validate_instance_id(instance_id)?),
+            ..self
+        })
+    }
+
+    /// Removes invalidated keys from `cache`, or clears it
+    pub fn with_cache<C, T>(self, cache: C) -> Self
+    where
+        C: Cache<T> + Send + 'static,
+    {
+        self.with_handler(move |invalidation| match invalidation {
+            Invalidation::Key(key) => {
+                if let Err(e) = cache.remove(key) {
+                    warn!("Failed to remove invalidated key {} from cache: {}", key, e);
+                }
+            }
+            Invalidation::All => {
+                if let Err(e) = cache.clear() {
+                    warn!("Failed to clear invalidated cache: {}", e);
+                }
+            }
+        })
+    }
+
+    pub fn with_handler<F>(mut self, handler: F) -> Self
+    where
+        F: Fn(Invalidation) + Send + 'static,
+    {
+        self.handlers.push(Box::new(handler));
+        self
+    }
+
+    /// Starts listening in a background thread. Returns once subscribed, or with the error of the first connection attempt.
+    /// Lost connections are reestablished, but the invalidations published in the meantime are lost.
+    pub fn spawn(self) -> Result<InvalidationListener, InvalidationError> {
+        let stop = Arc::new(AtomicBool::new(false));
+        let (subscribed_tx, subscribed_rx) = mpsc::channel();
+
+        let thread = {
+            let stop = stop.clone();
+            thread::spawn(move || self.run(&stop, subscribed_tx))
+        };
+
+        match subscribed_rx.recv() {
+            Ok(Ok(())) => Ok(InvalidationListener {
+                stop,
+                thread: Some(thread),
+            }),
+            Ok(Err(e)) => {
+                stop.store(true, Ordering::SeqCst);
+                Err(e.into())
+            }
+            Err(_) => Err(InvalidationError::ListenerPanicked),
+        }
+    }
+
+    fn run(self, stop: &AtomicBool, subscribed: mpsc::Sender<Result<(), RedisError>>) {
+        let mut subscribed = Some(subscribed);
+        while !stop.load(Ordering::SeqCst) {
+            let res = self
+                .client
+                .get_connection()
+                .and_then(|mut conn| self.listen(&mut conn, stop, &mut subscribed));
+            if let Err(e) = res {
+                match subscribed.take() {
+                    Some(subscribed) => {
+                        let _ = subscribed.send(Err(e));
+                        return;
+                    }
+                    None => {
+                        warn!("Cache invalidation subscriber has lost connection: {}", e);
+                        thread::sleep(Duration::from_secs(RECONNECT_DELAY_SECS));
+                    }
+                }
+            }
+        }
+    }
+
+    fn listen(
+        &self,
+        conn: &mut RedisConnection,
+        stop: &AtomicBool,
+        subscribed: &mut Option<mpsc::Sender<Result<(), RedisError>>>,
+    ) -> Result<(), RedisError> {
+        let mut pubsub = conn.as_pubsub();
+        pubsub.subscribe(&self.channel)?;
+        pubsub.set_read_timeout(Some(Duration::from_secs(POLL_INTERVAL_SECS)))?;
+        if let Some(subscribed) = subscribed.take() {
+            let _ = subscribed.send(Ok(()));
+        }
+
+        while !stop.load(Ordering::SeqCst) {
+            let msg = match pubsub.get_message() {
+                Ok(msg) => msg,
+                Err(ref e) if e.is_timeout() => continue,
+                Err(e) => return Err(e),
+            };
+            let payload = msg.get_payload::<String>()?;
+            match parse_message(&payload) {
+                None => warn!("Malformed cache invalidation message: {}", payload),
+                Some((instance_id, invalidation)) => {
+                    if self.instance_id.iter().any(|own_id| own_id == instance_id) {
+                        continue;
+                    }
+                    for handler in &self.handlers {
+                        handler(invalidation);
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Running subscriber. Dropping it stops the subscriber, waiting for up to a second.
+pub struct InvalidationListener {
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl InvalidationListener {
+    /// Same as dropping the listener
+    pub fn stop(self) {}
+}
+
+impl Drop for InvalidationListener {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_message() {
+        assert_eq!(
+            parse_message("1-2:key"),
+            Some(("1-2", Invalidation::Key("key")))
+        );
+        assert_eq!(
+            parse_message("1-2:key:with:colons"),
+            Some(("1-2", Invalidation::Key("key:with:colons")))
+        );
+        assert_eq!(parse_message("1-2"), Some(("1-2", Invalidation::All)));
+        assert_eq!(parse_message(""), None);
+        assert_eq!(invalidation_channel("products"), "products:__invalidate");
+    }
+
+    #[test]
+    fn test_validate_instance_id() {
+        assert_eq!(validate_instance_id("1-2".to_string()).unwrap(), "1-2");
+        for instance_id in &["", "1:2"] {
+            match validate_instance_id(instance_id.to_string()) {
+                Err(InvalidationError::InvalidInstanceId(ref id)) if id == instance_id => {}
+                res => panic!("Unexpected result: {:?}", res),
+            }
+        }
+    }
+}
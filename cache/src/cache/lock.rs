@@ -0,0 +1,200 @@
+//! Lease locks for serializing work across service instances.
+//!
+//! A lease is held under a random token and expires after its TTL, so a crashed holder cannot block others forever.
+//! Holders doing long work should renew the lease before it expires, since an expired lease can be taken by someone else.
+use failure::Fail;
+use std::thread;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+const DEFAULT_RETRY_INTERVAL_MILLIS: u64 = 50;
+
+/// Storage of leases. All operations must be atomic.
+pub trait LeaseBackend {
+    type Error: Fail;
+
+    /// Takes the lease `name` for `ttl` unless it is held. Returns whether the lease has been taken.
+    fn try_acquire(&self, name: &str, token: &str, ttl: Duration) -> Result<bool, Self::Error>;
+
+    /// Sets the TTL of the lease if it is still held with `token`. Returns whether the lease has been renewed.
+    fn renew(&self, name: &str, token: &str, ttl: Duration) -> Result<bool, Self::Error>;
+
+    /// Releases the lease if it is still held with `token`. Returns whether the lease has been released.
+    fn release(&self, name: &str, token: &str) -> Result<bool, Self::Error>;
+}
+
+#[derive(Clone, Debug)]
+pub struct DistributedLock<B> {
+    backend: B,
+    ttl: Duration,
+    retry_interval: Duration,
+}
+
+impl<B> DistributedLock<B>
+where
+    B: LeaseBackend + Clone,
+{
+    /// Leases are taken for `ttl`
+    pub fn new(backend: B, ttl: Duration) -> Self {
+        DistributedLock {
+            backend,
+            ttl,
+            retry_interval: Duration::from_millis(DEFAULT_RETRY_INTERVAL_MILLIS),
+        }
+    }
+
+    /// How often `lock` retries, 50 ms by default
+    pub fn with_retry_interval(self, retry_interval: Duration) -> Self {
+        DistributedLock {
+            retry_interval,
+            ..self
+        }
+    }
+
+    /// Takes the lease `name` if it is free
+    pub fn try_lock(&self, name: &str) -> Result<Option<LeaseGuard<B>>, B::Error> {
+        let token = Uuid::new_v4().to_string();
+        if !self.backend.try_acquire(name, &token, self.ttl)? {
+            return Ok(None);
+        }
+
+        Ok(Some(LeaseGuard {
+            backend: self.backend.clone(),
+            name: name.to_string(),
+            token,
+            ttl: self.ttl,
+            released: false,
+        }))
+    }
+
+    /// Waits for up to `timeout` for the lease `name`, blocking the current thread
+    pub fn lock(&self, name: &str, timeout: Duration) -> Result<Option<LeaseGuard<B>>, B::Error> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            if let Some(guard) = self.try_lock(name)? {
+                return Ok(Some(guard));
+            }
+
+            let now = Instant::now();
+            if now >= deadline {
+                return Ok(None);
+            }
+            thread::sleep(self.retry_interval.min(deadline - now));
+        }
+    }
+}
+
+/// Held lease, released on drop
+pub struct LeaseGuard<B>
+where
+    B: LeaseBackend,
+{
+    backend: B,
+    name: String,
+    token: String,
+    ttl: Duration,
+    released: bool,
+}
+
+impl<B> LeaseGuard<B>
+where
+    B: LeaseBackend,
+{
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn token(&self) -> &str {
+        &self.token
+    }
+
+    /// Extends the lease by its TTL from now. Returns `false` if the lease has expired and been lost.
+    pub fn renew(&self) -> Result<bool, B::Error> {
+        self.backend.renew(&self.name, &self.token, self.ttl)
+    }
+
+    /// Same as dropping the guard, but returns the result. `false` means that the lease had already been lost.
+    pub fn release(mut self) -> Result<bool, B::Error> {
+        self.released = true;
+        self.backend.release(&self.name, &self.token)
+    }
+}
+
+impl<B> Drop for LeaseGuard<B>
+where
+    B: LeaseBackend,
+{
+    fn drop(&mut self) {
+        if self.released {
+            return;
+        }
+
+        match self.backend.release(&self.name, &self.token) {
+            Ok(true) => {}
+            Ok(false) => warn!("Lease {} had expired before it was released", self.name),
+            Err(e) => warn!("Failed to release lease {}: {}", self.name, e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cache::{Cache, InMemoryCache};
+
+    fn lock(ttl: Duration) -> DistributedLock<InMemoryCache<String>> {
+        DistributedLock::new(InMemoryCache::new(), ttl)
+            .with_retry_interval(Duration::from_millis(10))
+    }
+
+    #[test]
+    fn test_exclusive() {
+        let lock = lock(Duration::from_secs(60));
+
+        let guard = lock.try_lock("order").unwrap().unwrap();
+        assert!(lock.try_lock("order").unwrap().is_none());
+        assert!(lock.try_lock("other_order").unwrap().is_some());
+        assert!(lock
+            .lock("order", Duration::from_millis(30))
+            .unwrap()
+            .is_none());
+
+        drop(guard);
+        let guard = lock.try_lock("order").unwrap().unwrap();
+        assert!(guard.release().unwrap());
+        assert!(lock.try_lock("order").unwrap().is_some());
+    }
+
+    #[test]
+    fn test_leases_are_kept_apart_from_values() {
+        let cache = InMemoryCache::new().with_capacity(1);
+        let lock = DistributedLock::new(cache.clone(), Duration::from_secs(60));
+
+        let guard = lock.try_lock("order").unwrap().unwrap();
+        cache.set("order", "overwritten".to_string()).unwrap();
+        cache.set("other", "evicts".to_string()).unwrap();
+        cache.clear().unwrap();
+
+        assert!(lock.try_lock("order").unwrap().is_none());
+        assert!(guard.renew().unwrap());
+        assert!(guard.release().unwrap());
+    }
+
+    #[test]
+    fn test_expiration() {
+        let lock = lock(Duration::from_millis(50));
+
+        let expired = lock.try_lock("order").unwrap().unwrap();
+        let guard = lock.lock("order", Duration::from_secs(1)).unwrap().unwrap();
+
+        // Expired holder can neither renew nor release somebody else's lease
+        assert!(!expired.renew().unwrap());
+        assert!(!expired.release().unwrap());
+        assert!(lock.try_lock("order").unwrap().is_none());
+
+        thread::sleep(Duration::from_millis(30));
+        assert!(guard.renew().unwrap());
+        thread::sleep(Duration::from_millis(30));
+        assert!(lock.try_lock("order").unwrap().is_none());
+    }
+}
@@ -1,12 +1,30 @@
+pub mod async_cache;
+pub mod atomic;
+pub mod circuit_breaker;
+pub mod clock;
+pub mod codec;
 pub mod in_memory;
+pub mod invalidation;
+pub mod lock;
 pub mod null;
+pub mod read_through;
 pub mod redis;
+pub mod tiered;
 pub mod typed;
 
 use failure::Fail;
-
-pub use self::in_memory::{InMemoryCache, InMemoryCacheError};
+use std::time::Duration;
+
+pub use self::async_cache::{AsyncCache, AsyncTypedCache, CacheFuture, ThreadPoolCache};
+pub use self::atomic::AtomicCache;
+pub use self::circuit_breaker::{CircuitBreakerCache, CircuitBreakerError, CircuitBreakerStats};
+pub use self::clock::{Clock, ManualClock, SystemClock};
+pub use self::codec::{Codec, CodecError, Format};
+pub use self::in_memory::{InMemoryCache, InMemoryCacheError, InMemoryCacheStats};
+pub use self::lock::{DistributedLock, LeaseBackend, LeaseGuard};
 pub use self::null::NullCache;
+pub use self::read_through::{AsyncReadThroughCache, ReadThroughCache};
+pub use self::tiered::{TieredCache, TieredCacheError};
 pub use self::typed::{TypedCache, TypedCacheError};
 
 pub trait Cache<T> {
@@ -17,6 +35,42 @@ pub trait Cache<T> {
     fn set(&self, key: &str, value: T) -> Result<(), Self::Error>;
 
     fn remove(&self, key: &str) -> Result<bool, Self::Error>;
+
+    /// Sets the value that expires after `ttl`, regardless of the TTL of the cache.
+    /// Falls back to `set` by default, so caches that can expire single values override it.
+    fn set_with_ttl(&self, key: &str, value: T, ttl: Duration) -> Result<(), Self::Error> {
+        let _ = ttl;
+        self.set(key, value)
+    }
+
+    /// Removes all values from the cache.
+    /// Does nothing by default, since not every backend can enumerate its keys; caches that can be cleared override it.
+    fn clear(&self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    /// Returns values in the order of `keys`
+    fn get_many(&self, keys: &[&str]) -> Result<Vec<Option<T>>, Self::Error> {
+        keys.iter().map(|key| self.get(key)).collect()
+    }
+
+    fn set_many(&self, items: Vec<(&str, T)>) -> Result<(), Self::Error> {
+        for (key, value) in items {
+            self.set(key, value)?;
+        }
+        Ok(())
+    }
+
+    /// Returns the number of removed values
+    fn remove_many(&self, keys: &[&str]) -> Result<usize, Self::Error> {
+        let mut removed = 0;
+        for key in keys {
+            if self.remove(key)? {
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
 }
 
 impl<C, T> Cache<T> for Box<C>
@@ -36,6 +90,26 @@ where
     fn remove(&self, key: &str) -> Result<bool, Self::Error> {
         (**self).remove(key)
     }
+
+    fn set_with_ttl(&self, key: &str, value: T, ttl: Duration) -> Result<(), Self::Error> {
+        (**self).set_with_ttl(key, value, ttl)
+    }
+
+    fn clear(&self) -> Result<(), Self::Error> {
+        (**self).clear()
+    }
+
+    fn get_many(&self, keys: &[&str]) -> Result<Vec<Option<T>>, Self::Error> {
+        (**self).get_many(keys)
+    }
+
+    fn set_many(&self, items: Vec<(&str, T)>) -> Result<(), Self::Error> {
+        (**self).set_many(items)
+    }
+
+    fn remove_many(&self, keys: &[&str]) -> Result<usize, Self::Error> {
+        (**self).remove_many(keys)
+    }
 }
 
 pub trait CacheSingle<T> {
@@ -32,4 +32,8 @@ impl<T, E: Fail> Cache<T> for NullCache<T, E> {
     fn remove(&self, _key: &str) -> Result<bool, Self::Error> {
         Ok(false)
     }
+
+    fn clear(&self) -> Result<(), Self::Error> {
+        Ok(())
+    }
 }
@@ -0,0 +1,333 @@
+//! Read-through access to caches: values missing from the cache are computed, stored and returned.
+//! Concurrent misses of the same key wait for a single computation instead of all hitting the backend.
+//! Cache failures are logged and do not fail the call, since the value can always be computed.
+use futures::future::{self, Shared};
+use futures::prelude::*;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex, PoisonError, TryLockError};
+use std::time::Duration;
+
+use super::{AsyncCache, Cache, CacheFuture, InMemoryCache};
+
+/// Read-through wrapper over a synchronous cache, safe to share between threads.
+pub struct ReadThroughCache<C, T> {
+    cache: C,
+    locks: Arc<Mutex<HashMap<String, Arc<Mutex<()>>>>>,
+    stale: Option<InMemoryCache<T>>,
+}
+
+impl<C, T> ReadThroughCache<C, T>
+where
+    C: Cache<T>,
+    T: Clone,
+{
+    pub fn new(cache: C) -> Self {
+        ReadThroughCache {
+            cache,
+            locks: Default::default(),
+            stale: None,
+        }
+    }
+
+    /// Remembers computed values for `max_age`. While some caller recomputes a value missing from the cache,
+    /// the other callers get the remembered value instead of waiting.
+    pub fn with_stale_values(self, max_age: Duration) -> Self {
+        ReadThroughCache {
+            stale: Some(InMemoryCache::new().with_ttl(max_age)),
+            ..self
+        }
+    }
+
+    pub fn cache(&self) -> &C {
+        &self.cache
+    }
+
+    /// Returns cached value, or computes it with `compute` and caches it.
+    /// Failed computations are not cached.
+    pub fn get_or_insert_with<F, E>(&self, key: &str, compute: F) -> Result<T, E>
+    where
+        F: FnOnce() -> Result<T, E>,
+    {
+        if let Some(value) = self.cached(key) {
+            return Ok(value);
+        }
+
+        let lock = self.key_lock(key);
+        let res = {
+            let _guard = match lock.try_lock() {
+                Ok(guard) => guard,
+                Err(TryLockError::Poisoned(e)) => e.into_inner(),
+                Err(TryLockError::WouldBlock) => {
+                    if let Some(value) = self.stale_value(key) {
+                        return Ok(value);
+                    }
+                    lock.lock().unwrap_or_else(PoisonError::into_inner)
+                }
+            };
+
+            // The value may have been computed while we were waiting
+            match self.cached(key) {
+                Some(value) => Ok(value),
+                None => {
+                    let res = compute();
+                    if let Ok(ref value) = res {
+                        self.store(key, value.clone());
+                    }
+                    res
+                }
+            }
+        };
+        self.release_key_lock(key, lock);
+        res
+    }
+
+    fn cached(&self, key: &str) -> Option<T> {
+        self.cache.get(key).unwrap_or_else(|e| {
+            warn!("Failed to read value from cache: {}", e);
+            None
+        })
+    }
+
+    fn stale_value(&self, key: &str) -> Option<T> {
+        self.stale
+            .as_ref()
+            .and_then(|stale| Cache::get(stale, key).unwrap_or(None))
+    }
+
+    fn store(&self, key: &str, value: T) {
+        if let Some(ref stale) = self.stale {
+            let _ = Cache::set(stale, key, value.clone());
+        }
+        if let Err(e) = self.cache.set(key, value) {
+            warn!("Failed to write value to cache: {}", e);
+        }
+    }
+
+    fn key_lock(&self, key: &str) -> Arc<Mutex<()>> {
+        let mut locks = self.locks.lock().unwrap_or_else(PoisonError::into_inner);
+        locks.entry(key.to_string()).or_default().clone()
+    }
+
+    fn release_key_lock(&self, key: &str, lock: Arc<Mutex<()>>) {
+        let mut locks = self.locks.lock().unwrap_or_else(PoisonError::into_inner);
+        // Lock is cloned only under `locks`, so nobody else can be waiting on it
+        if Arc::strong_count(&lock) <= 2 {
+            locks.remove(key);
+        }
+    }
+}
+
+type InFlight<T, E> = Rc<RefCell<HashMap<String, Shared<CacheFuture<T, E>>>>>;
+
+/// Read-through wrapper over an asynchronous cache. Computations are futures with error `E`,
+/// which has to be `Clone` so that every caller waiting on a failed computation gets the error.
+pub struct AsyncReadThroughCache<C, T, E> {
+    cache: Rc<C>,
+    in_flight: InFlight<T, E>,
+    stale: Option<InMemoryCache<T>>,
+}
+
+impl<C, T, E> AsyncReadThroughCache<C, T, E>
+where
+    C: AsyncCache<T> + 'static,
+    T: Clone + 'static,
+    E: Clone + 'static,
+{
+    pub fn new(cache: C) -> Self {
+        AsyncReadThroughCache {
+            cache: Rc::new(cache),
+            in_flight: Default::default(),
+            stale: None,
+        }
+    }
+
+    /// Same as `ReadThroughCache::with_stale_values`
+    pub fn with_stale_values(self, max_age: Duration) -> Self {
+        AsyncReadThroughCache {
+            stale: Some(InMemoryCache::new().with_ttl(max_age)),
+            ..self
+        }
+    }
+
+    /// Returns cached value, or computes it with `compute` and caches it.
+    /// Failed computations are not cached.
+    pub fn get_or_insert_with_future<F, Fut>(&self, key: &str, compute: F) -> CacheFuture<T, E>
+    where
+        F: FnOnce() -> Fut + 'static,
+        Fut: IntoFuture<Item = T, Error = E> + 'static,
+    {
+        let key = key.to_string();
+        let cache = self.cache.clone();
+        let in_flight = self.in_flight.clone();
+        let stale = self.stale.clone();
+
+        Box::new(self.cache.get(&key).then(move |res| -> CacheFuture<T, E> {
+            match res {
+                Ok(Some(value)) => return Box::new(future::ok(value)),
+                Ok(None) => {}
+                Err(e) => warn!("Failed to read value from cache: {}", e),
+            }
+
+            let computation = in_flight.borrow().get(&key).cloned();
+            let computation = match computation {
+                Some(computation) => {
+                    let stale_value = stale
+                        .as_ref()
+                        .and_then(|stale| Cache::get(stale, &key).unwrap_or(None));
+                    if let Some(value) = stale_value {
+                        return Box::new(future::ok(value));
+                    }
+                    computation
+                }
+                None => {
+                    let computation = {
+                        let stored_key = key.clone();
+                        let computed_key = key.clone();
+                        let in_flight = in_flight.clone();
+                        let computed = compute().into_future().and_then(move |value| {
+                            if let Some(ref stale) = stale {
+                                let _ = Cache::set(stale, &stored_key, value.clone());
+                            }
+                            cache.set(&stored_key, value.clone()).then(move |res| {
+                                if let Err(e) = res {
+                                    warn!("Failed to write value to cache: {}", e);
+                                }
+                                Ok(value)
+                            })
+                        });
+                        Box::new(computed.then(move |res| {
+                            in_flight.borrow_mut().remove(&computed_key);
+                            res
+                        })) as CacheFuture<T, E>
+                    }
+                    .shared();
+                    in_flight.borrow_mut().insert(key, computation.clone());
+                    computation
+                }
+            };
+
+            Box::new(
+                computation
+                    .map(|value| (*value).clone())
+                    .map_err(|e| (*e).clone()),
+            )
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::{self, Notify};
+    use futures::sync::oneshot;
+    use std::cell::Cell;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+
+    #[test]
+    fn test_get_or_insert_with() {
+        let cache = ReadThroughCache::new(InMemoryCache::new());
+
+        assert_eq!(cache.get_or_insert_with("key", || Ok::<_, ()>(1)), Ok(1));
+        assert_eq!(cache.get_or_insert_with("key", || Ok::<_, ()>(2)), Ok(1));
+        assert_eq!(
+            cache.get_or_insert_with("other_key", || Err("failure")),
+            Err("failure")
+        );
+        assert_eq!(Cache::get(cache.cache(), "other_key").unwrap(), None);
+    }
+
+    #[test]
+    fn test_concurrent_misses_compute_once() {
+        let cache = Arc::new(ReadThroughCache::new(InMemoryCache::new()));
+        let calls = Arc::new(AtomicUsize::new(0));
+
+        let threads = (0..8)
+            .map(|_| {
+                let cache = cache.clone();
+                let calls = calls.clone();
+                thread::spawn(move || {
+                    cache.get_or_insert_with("key", || {
+                        calls.fetch_add(1, Ordering::SeqCst);
+                        thread::sleep(Duration::from_millis(50));
+                        Ok::<_, ()>(1)
+                    })
+                })
+            })
+            .collect::<Vec<_>>();
+
+        for thread in threads {
+            assert_eq!(thread.join().unwrap(), Ok(1));
+        }
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_stale_values() {
+        let cache = Arc::new(
+            ReadThroughCache::new(InMemoryCache::new().with_ttl(Duration::from_millis(100)))
+                .with_stale_values(Duration::from_secs(60)),
+        );
+        assert_eq!(cache.get_or_insert_with("key", || Ok::<_, ()>(1)), Ok(1));
+        thread::sleep(Duration::from_millis(150));
+
+        let refresh = {
+            let cache = cache.clone();
+            thread::spawn(move || {
+                cache.get_or_insert_with("key", || {
+                    thread::sleep(Duration::from_millis(200));
+                    Ok::<_, ()>(2)
+                })
+            })
+        };
+        thread::sleep(Duration::from_millis(50));
+
+        assert_eq!(cache.get_or_insert_with("key", || Ok::<_, ()>(3)), Ok(1));
+        assert_eq!(refresh.join().unwrap(), Ok(2));
+        assert_eq!(cache.get_or_insert_with("key", || Ok::<_, ()>(3)), Ok(2));
+    }
+
+    struct NoopNotify;
+
+    impl Notify for NoopNotify {
+        fn notify(&self, _id: usize) {}
+    }
+
+    #[test]
+    fn test_concurrent_futures_compute_once() {
+        let cache = AsyncReadThroughCache::new(InMemoryCache::new());
+        let calls = Rc::new(Cell::new(0));
+        let (tx, rx) = oneshot::channel();
+
+        let first = cache.get_or_insert_with_future("key", {
+            let calls = calls.clone();
+            move || {
+                calls.set(calls.get() + 1);
+                rx.map_err(|_| ())
+            }
+        });
+        let second = cache.get_or_insert_with_future("key", {
+            let calls = calls.clone();
+            move || {
+                calls.set(calls.get() + 1);
+                Ok(2)
+            }
+        });
+
+        let notify = Arc::new(NoopNotify);
+        let mut task = executor::spawn(first.join(second));
+        assert_eq!(task.poll_future_notify(&notify, 0), Ok(Async::NotReady));
+
+        tx.send(1).unwrap();
+        assert_eq!(
+            task.poll_future_notify(&notify, 0),
+            Ok(Async::Ready((1, 1)))
+        );
+        assert_eq!(calls.get(), 1);
+
+        let third = cache.get_or_insert_with_future("key", || Ok(3));
+        assert_eq!(third.wait(), Ok(1));
+    }
+}
@@ -1,12 +1,126 @@
+use futures::future;
+use futures::prelude::*;
 use r2d2_redis::{
     r2d2::{ManageConnection, Pool},
-    redis::{cmd, Connection as RedisConnection, RedisError},
+    redis::{
+        async::SharedConnection, cmd, pipe, Connection as RedisConnection, Pipeline, RedisError,
+        RedisResult, Script,
+    },
 };
 use std::time::Duration;
 
-use cache::Cache;
+use cache::lock::LeaseBackend;
+use cache::{AsyncCache, AtomicCache, Cache, CacheFuture};
 
-#[derive(Clone, Debug)]
+/// Separates the namespace from values
+const VALUE_SEPARATOR: char = ':';
+/// Separates the namespace from the generation, tag sets, leases and versioned values
+const RESERVED_SEPARATOR: char = '#';
+const GENERATION_KEY: &str = "generation";
+const TAG_KEY_PREFIX: &str = "tag:";
+const LOCK_KEY_PREFIX: &str = "lock:";
+/// Number of keys requested per `SCAN` on `clear`
+const SCAN_BATCH_SIZE: usize = 1000;
+
+/// Deletes the members of the tag set `KEYS[1]` and the set itself, returning the number of deleted members.
+/// Runs atomically, so that keys tagged concurrently are either deleted or stay in the set.
+const INVALIDATE_TAG_SCRIPT: &str = r"
+local keys = redis.call('SMEMBERS', KEYS[1])
+local removed = 0
+for i = 1, #keys, 1000 do
+    removed = removed + redis.call('DEL', unpack(keys, i, math.min(i + 999, #keys)))
+end
+redis.call('DEL', KEYS[1])
+return removed
+";
+
+/// Sets `KEYS[1]` to `ARGV[3]` if its value is `ARGV[2]`, or if it is missing when `ARGV[1]` is 0.
+/// `ARGV[4]` is the TTL in milliseconds, or 0 for no TTL. Returns 1 if the value has been set.
+const COMPARE_AND_SWAP_SCRIPT: &str = r"
+local current = redis.call('GET', KEYS[1])
+local matches
+if ARGV[1] == '0' then
+    matches = current == false
+else
+    matches = current == ARGV[2]
+end
+if not matches then
+    return 0
+end
+if ARGV[4] == '0' then
+    redis.call('SET', KEYS[1], ARGV[3])
+else
+    redis.call('SET', KEYS[1], ARGV[3], 'PX', ARGV[4])
+end
+return 1
+";
+
+/// Sets the TTL of `KEYS[1]` to `ARGV[2]` milliseconds if its value is `ARGV[1]`
+const RENEW_LEASE_SCRIPT: &str = r"
+if redis.call('GET', KEYS[1]) == ARGV[1] then
+    return redis.call('PEXPIRE', KEYS[1], ARGV[2])
+end
+return 0
+";
+
+/// Deletes `KEYS[1]` if its value is `ARGV[1]`
+const RELEASE_LEASE_SCRIPT: &str = r"
+if redis.call('GET', KEYS[1]) == ARGV[1] then
+    return redis.call('DEL', KEYS[1])
+end
+return 0
+";
+
+/// TTL in milliseconds for `PSETEX`, `PEXPIRE` and `PX`, rounded up, since Redis rejects a TTL of 0
+fn ttl_millis(ttl: Duration) -> u64 {
+    let rounded = ttl + Duration::new(0, 999_999);
+    (rounded.as_secs() * 1000 + u64::from(rounded.subsec_millis())).max(1)
+}
+
+/// Escapes the separators in `namespace`, so that the keys of a namespace never start with the prefix of another one
+fn escape_namespace(namespace: &str) -> String {
+    let mut escaped = String::with_capacity(namespace.len());
+    for c in namespace.chars() {
+        if c == '\\' || c == VALUE_SEPARATOR || c == RESERVED_SEPARATOR {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Key of an entry of `namespace` that values cannot collide with
+fn make_reserved_key(namespace: &str, name: &str) -> String {
+    format!(
+        "{}{}{}",
+        escape_namespace(namespace),
+        RESERVED_SEPARATOR,
+        name
+    )
+}
+
+fn make_generation_key(namespace: &str) -> String {
+    make_reserved_key(namespace, GENERATION_KEY)
+}
+
+/// Prefix of the keys of `namespace`. `generation` is the current generation with versioned keys, and `None` without.
+fn make_key_prefix(namespace: &str, generation: Option<u64>) -> String {
+    match generation {
+        None => format!("{}{}", escape_namespace(namespace), VALUE_SEPARATOR),
+        Some(generation) => {
+            make_reserved_key(namespace, &format!("v{}{}", generation, VALUE_SEPARATOR))
+        }
+    }
+}
+
+/// Redis cache storing values under `{namespace}:{key}`, with `\`, `:` and `#` in the namespace escaped with `\`.
+///
+/// Everything else is stored under `{namespace}#`, which values cannot collide with.
+/// With versioned keys, values are stored under `{namespace}#v{generation}:{key}` instead,
+/// where the generation is a counter stored in `{namespace}#generation`.
+/// Tag sets are stored in `{namespace}#tag:{tag}`, regardless of versioning,
+/// and leases in `{namespace}#lock:{name}`, so that clearing the cache does not release them.
+#[derive(Debug)]
 pub struct RedisCache<M>
 where
     M: ManageConnection<Connection = RedisConnection>,
@@ -14,6 +128,24 @@ where
     namespace: String,
     pool: Pool<M>,
     ttl: Option<Duration>,
+    versioned: bool,
+    acquire_timeout: Option<Duration>,
+}
+
+// Derived `Clone` would require `M: Clone`, which connection managers are not
+impl<M> Clone for RedisCache<M>
+where
+    M: ManageConnection<Connection = RedisConnection>,
+{
+    fn clone(&self) -> Self {
+        RedisCache {
+            namespace: self.namespace.clone(),
+            pool: self.pool.clone(),
+            ttl: self.ttl,
+            versioned: self.versioned,
+            acquire_timeout: self.acquire_timeout,
+        }
+    }
 }
 
 #[derive(Debug, Fail)]
@@ -30,6 +162,19 @@ impl From<RedisError> for RedisCacheError {
     }
 }
 
+/// Escapes glob special characters for `SCAN MATCH`
+fn escape_pattern(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '*' | '?' | '[' | ']' | '\\' => escaped.push('\\'),
+            _ => {}
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
 impl<M> RedisCache<M>
 where
     M: ManageConnection<Connection = RedisConnection>,
@@ -39,6 +184,8 @@ where
             namespace: String::from(namespace),
             pool,
             ttl: None,
+            versioned: false,
+            acquire_timeout: None,
         }
     }
 
@@ -49,19 +196,123 @@ where
         }
     }
 
-    fn make_redis_key(&self, key: &str) -> String {
-        format!("{}:{}", self.namespace, key)
+    /// Stores values under the current generation of the namespace, so that `clear` only has to bump the generation.
+    /// Every operation reads the generation first, which costs an extra round trip.
+    /// Values of older generations are not deleted, so this should be combined with a TTL.
+    pub fn with_versioned_keys(self) -> Self {
+        RedisCache {
+            versioned: true,
+            ..self
+        }
+    }
+
+    /// Waits for up to `timeout` for a pooled connection instead of failing
+    /// with `NoAvailableConnections` right away when all connections are in use
+    pub fn with_acquire_timeout(self, timeout: Duration) -> Self {
+        RedisCache {
+            acquire_timeout: Some(timeout),
+            ..self
+        }
+    }
+
+    pub fn namespace(&self) -> &str {
+        &self.namespace
+    }
+
+    /// Sets the value and adds its key to the groups `tags`, see `invalidate_tag`.
+    /// With a TTL, tag sets expire after the last value added to them.
+    pub fn set_with_tags(
+        &self,
+        key: &str,
+        value: String,
+        tags: &[&str],
+    ) -> Result<(), RedisCacheError> {
+        self.with_key_prefix(|conn, prefix| {
+            let redis_key = format!("{}{}", prefix, key);
+            let mut pipeline = pipe();
+            pipeline.atomic();
+            self.add_set(&mut pipeline, &redis_key, &value);
+            for tag in tags {
+                let tag_key = self.make_tag_key(tag);
+                pipeline.cmd("SADD").arg(&tag_key).arg(&redis_key).ignore();
+                if let Some(ttl) = self.ttl {
+                    pipeline
+                        .cmd("PEXPIRE")
+                        .arg(&tag_key)
+                        .arg(ttl_millis(ttl))
+                        .ignore();
+                }
+            }
+            pipeline.query(conn)
+        })
+    }
+
+    /// Removes all values tagged with `tag`. Returns the number of removed values.
+    pub fn invalidate_tag(&self, tag: &str) -> Result<usize, RedisCacheError> {
+        self.using_connection(|conn| {
+            Script::new(INVALIDATE_TAG_SCRIPT)
+                .key(self.make_tag_key(tag))
+                .invoke(conn)
+        })
+        .and_then(|res| res.map_err(From::from))
+    }
+
+    fn make_generation_key(&self) -> String {
+        make_generation_key(&self.namespace)
+    }
+
+    fn make_lock_key(&self, name: &str) -> String {
+        make_reserved_key(&self.namespace, &format!("{}{}", LOCK_KEY_PREFIX, name))
+    }
+
+    fn make_tag_key(&self, tag: &str) -> String {
+        make_reserved_key(&self.namespace, &format!("{}{}", TAG_KEY_PREFIX, tag))
+    }
+
+    /// Prefix of the keys in the current generation
+    fn key_prefix(&self, conn: &RedisConnection) -> RedisResult<String> {
+        if !self.versioned {
+            return Ok(make_key_prefix(&self.namespace, None));
+        }
+
+        let generation: Option<u64> = cmd("GET").arg(self.make_generation_key()).query(conn)?;
+        Ok(make_key_prefix(
+            &self.namespace,
+            Some(generation.unwrap_or(0)),
+        ))
+    }
+
+    fn add_set(&self, pipeline: &mut Pipeline, redis_key: &str, value: &str) {
+        match self.ttl {
+            None => pipeline.cmd("SET").arg(redis_key).arg(value).ignore(),
+            Some(ttl) => pipeline
+                .cmd("PSETEX")
+                .arg(redis_key)
+                .arg(ttl_millis(ttl))
+                .arg(value)
+                .ignore(),
+        };
     }
 
     fn using_connection<T, F>(&self, f: F) -> Result<T, RedisCacheError>
     where
-        F: Fn(&RedisConnection) -> T,
+        F: FnOnce(&RedisConnection) -> T,
     {
-        self.pool
-            .try_get()
-            .map(|conn| f(&conn))
+        let conn = match self.acquire_timeout {
+            None => self.pool.try_get(),
+            Some(timeout) => self.pool.get_timeout(timeout).ok(),
+        };
+        conn.map(|conn| f(&conn))
             .ok_or(RedisCacheError::NoAvailableConnections)
     }
+
+    fn with_key_prefix<T, F>(&self, f: F) -> Result<T, RedisCacheError>
+    where
+        F: FnOnce(&RedisConnection, &str) -> RedisResult<T>,
+    {
+        self.using_connection(|conn| self.key_prefix(conn).and_then(|prefix| f(conn, &prefix)))
+            .and_then(|res| res.map_err(From::from))
+    }
 }
 
 impl<M> Cache<String> for RedisCache<M>
@@ -71,32 +322,341 @@ where
     type Error = RedisCacheError;
 
     fn get(&self, key: &str) -> Result<Option<String>, Self::Error> {
-        self.using_connection(|conn| cmd("GET").arg(self.make_redis_key(key)).query(conn))
-            .and_then(|res| res.map_err(From::from))
+        self.with_key_prefix(|conn, prefix| {
+            cmd("GET").arg(format!("{}{}", prefix, key)).query(conn)
+        })
     }
 
     fn set(&self, key: &str, value: String) -> Result<(), Self::Error> {
-        self.using_connection(|conn| match self.ttl {
-            None => cmd("SET")
-                .arg(self.make_redis_key(key))
-                .arg(&value)
-                .query(conn),
-            Some(ttl) => cmd("SETEX")
-                .arg(self.make_redis_key(key))
-                .arg(ttl.as_secs())
+        self.with_key_prefix(|conn, prefix| {
+            let mut pipeline = pipe();
+            self.add_set(&mut pipeline, &format!("{}{}", prefix, key), &value);
+            pipeline.query(conn)
+        })
+    }
+
+    fn set_with_ttl(&self, key: &str, value: String, ttl: Duration) -> Result<(), Self::Error> {
+        self.with_key_prefix(|conn, prefix| {
+            cmd("PSETEX")
+                .arg(format!("{}{}", prefix, key))
+                .arg(ttl_millis(ttl))
                 .arg(&value)
-                .query(conn),
+                .query(conn)
         })
-        .and_then(|res| res.map_err(From::from))
     }
 
     fn remove(&self, key: &str) -> Result<bool, Self::Error> {
-        self.using_connection(|conn| {
+        self.with_key_prefix(|conn, prefix| {
             cmd("DEL")
-                .arg(self.make_redis_key(key))
+                .arg(format!("{}{}", prefix, key))
                 .query(conn)
                 .map(|keys_removed: u32| if keys_removed > 0 { true } else { false })
         })
+    }
+
+    /// Bumps the generation with versioned keys, otherwise deletes the values of the namespace with `SCAN` and `UNLINK`.
+    /// Values set while the keys are being scanned may survive.
+    ///
+    /// Only values of this namespace are deleted: tag sets, leases, versioned values
+    /// and the values of other namespaces, including nested ones such as `{namespace}:nested`, are kept.
+    fn clear(&self) -> Result<(), Self::Error> {
+        if self.versioned {
+            return self
+                .using_connection(|conn| {
+                    cmd("INCR")
+                        .arg(self.make_generation_key())
+                        .query::<u64>(conn)
+                        .map(|_| ())
+                })
+                .and_then(|res| res.map_err(From::from));
+        }
+
+        let prefix = make_key_prefix(&self.namespace, None);
+        let pattern = format!("{}*", escape_pattern(&prefix));
+        self.using_connection(|conn| -> RedisResult<()> {
+            let mut cursor = 0u64;
+            loop {
+                let (next_cursor, keys): (u64, Vec<String>) = cmd("SCAN")
+                    .arg(cursor)
+                    .arg("MATCH")
+                    .arg(&pattern)
+                    .arg("COUNT")
+                    .arg(SCAN_BATCH_SIZE)
+                    .query(conn)?;
+                if !keys.is_empty() {
+                    cmd("UNLINK").arg(&keys[..]).query::<u64>(conn)?;
+                }
+                if next_cursor == 0 {
+                    return Ok(());
+                }
+                cursor = next_cursor;
+            }
+        })
         .and_then(|res| res.map_err(From::from))
     }
+
+    fn get_many(&self, keys: &[&str]) -> Result<Vec<Option<String>>, Self::Error> {
+        if keys.is_empty() {
+            return Ok(vec![]);
+        }
+
+        self.with_key_prefix(|conn, prefix| {
+            let redis_keys = keys
+                .iter()
+                .map(|key| format!("{}{}", prefix, key))
+                .collect::<Vec<_>>();
+            cmd("MGET").arg(&redis_keys[..]).query(conn)
+        })
+    }
+
+    fn set_many(&self, items: Vec<(&str, String)>) -> Result<(), Self::Error> {
+        if items.is_empty() {
+            return Ok(());
+        }
+
+        self.with_key_prefix(|conn, prefix| {
+            let mut pipeline = pipe();
+            for (key, value) in &items {
+                self.add_set(&mut pipeline, &format!("{}{}", prefix, key), value);
+            }
+            pipeline.query(conn)
+        })
+    }
+
+    fn remove_many(&self, keys: &[&str]) -> Result<usize, Self::Error> {
+        if keys.is_empty() {
+            return Ok(0);
+        }
+
+        self.with_key_prefix(|conn, prefix| {
+            let redis_keys = keys
+                .iter()
+                .map(|key| format!("{}{}", prefix, key))
+                .collect::<Vec<_>>();
+            cmd("DEL").arg(&redis_keys[..]).query(conn)
+        })
+    }
+}
+
+impl<M> AtomicCache<String> for RedisCache<M>
+where
+    M: ManageConnection<Connection = RedisConnection>,
+{
+    fn incr_by(&self, key: &str, delta: i64, ttl: Option<Duration>) -> Result<i64, Self::Error> {
+        self.with_key_prefix(|conn, prefix| {
+            let redis_key = format!("{}{}", prefix, key);
+            match ttl.or(self.ttl) {
+                None => cmd("INCRBY").arg(&redis_key).arg(delta).query(conn),
+                Some(ttl) => {
+                    // Creates the counter with TTL unless it exists
+                    let (value,): (i64,) = pipe()
+                        .atomic()
+                        .cmd("SET")
+                        .arg(&redis_key)
+                        .arg(0)
+                        .arg("PX")
+                        .arg(ttl_millis(ttl))
+                        .arg("NX")
+                        .ignore()
+                        .cmd("INCRBY")
+                        .arg(&redis_key)
+                        .arg(delta)
+                        .query(conn)?;
+                    Ok(value)
+                }
+            }
+        })
+    }
+
+    fn set_if_absent(
+        &self,
+        key: &str,
+        value: String,
+        ttl: Option<Duration>,
+    ) -> Result<bool, Self::Error> {
+        self.with_key_prefix(|conn, prefix| {
+            let mut command = cmd("SET");
+            command.arg(format!("{}{}", prefix, key)).arg(&value);
+            if let Some(ttl) = ttl.or(self.ttl) {
+                command.arg("PX").arg(ttl_millis(ttl));
+            }
+            command
+                .arg("NX")
+                .query(conn)
+                .map(|res: Option<String>| res.is_some())
+        })
+    }
+
+    fn compare_and_swap(
+        &self,
+        key: &str,
+        expected: Option<&String>,
+        value: String,
+    ) -> Result<bool, Self::Error> {
+        self.with_key_prefix(|conn, prefix| {
+            Script::new(COMPARE_AND_SWAP_SCRIPT)
+                .key(format!("{}{}", prefix, key))
+                .arg(if expected.is_some() { 1 } else { 0 })
+                .arg(expected.map(String::as_str).unwrap_or(""))
+                .arg(&value)
+                .arg(self.ttl.map(ttl_millis).unwrap_or(0))
+                .invoke(conn)
+                .map(|set: u32| set == 1)
+        })
+    }
+}
+
+impl<M> LeaseBackend for RedisCache<M>
+where
+    M: ManageConnection<Connection = RedisConnection>,
+{
+    type Error = RedisCacheError;
+
+    fn try_acquire(&self, name: &str, token: &str, ttl: Duration) -> Result<bool, Self::Error> {
+        self.using_connection(|conn| {
+            cmd("SET")
+                .arg(self.make_lock_key(name))
+                .arg(token)
+                .arg("PX")
+                .arg(ttl_millis(ttl))
+                .arg("NX")
+                .query(conn)
+                .map(|res: Option<String>| res.is_some())
+        })
+        .and_then(|res| res.map_err(From::from))
+    }
+
+    fn renew(&self, name: &str, token: &str, ttl: Duration) -> Result<bool, Self::Error> {
+        self.using_connection(|conn| {
+            Script::new(RENEW_LEASE_SCRIPT)
+                .key(self.make_lock_key(name))
+                .arg(token)
+                .arg(ttl_millis(ttl))
+                .invoke(conn)
+                .map(|renewed: u32| renewed == 1)
+        })
+        .and_then(|res| res.map_err(From::from))
+    }
+
+    fn release(&self, name: &str, token: &str) -> Result<bool, Self::Error> {
+        self.using_connection(|conn| {
+            Script::new(RELEASE_LEASE_SCRIPT)
+                .key(self.make_lock_key(name))
+                .arg(token)
+                .invoke(conn)
+                .map(|released: u32| released == 1)
+        })
+        .and_then(|res| res.map_err(From::from))
+    }
+}
+
+/// Redis cache over a non-blocking connection, see `redis::Client::get_shared_async_connection`.
+/// Keys are laid out as in `RedisCache`, so both can be used for the same namespace.
+///
+/// The connection spawns a task onto the default executor, so it has to be created and used within a tokio executor,
+/// e.g. inside `tokio_core::reactor::Core::run`, which sets itself as the default executor since tokio-core 0.1.18.
+#[derive(Clone)]
+pub struct AsyncRedisCache {
+    namespace: String,
+    connection: SharedConnection,
+    ttl: Option<Duration>,
+    versioned: bool,
+}
+
+impl AsyncRedisCache {
+    pub fn new(connection: SharedConnection, namespace: String) -> Self {
+        AsyncRedisCache {
+            namespace,
+            connection,
+            ttl: None,
+            versioned: false,
+        }
+    }
+
+    pub fn with_ttl(self, ttl: Duration) -> Self {
+        AsyncRedisCache {
+            ttl: Some(ttl),
+            ..self
+        }
+    }
+
+    /// Uses the versioned keys of `RedisCache::with_versioned_keys`, which cost an extra round trip per operation
+    pub fn with_versioned_keys(self) -> Self {
+        AsyncRedisCache {
+            versioned: true,
+            ..self
+        }
+    }
+
+    /// Resolves to the Redis key of `key` in the current generation
+    fn redis_key(&self, key: &str) -> CacheFuture<String, RedisCacheError> {
+        if !self.versioned {
+            return Box::new(future::ok(format!(
+                "{}{}",
+                make_key_prefix(&self.namespace, None),
+                key
+            )));
+        }
+
+        let namespace = self.namespace.clone();
+        let key = key.to_string();
+        Box::new(
+            cmd("GET")
+                .arg(make_generation_key(&namespace))
+                .query_async(self.connection.clone())
+                .map(move |(_conn, generation): (_, Option<u64>)| {
+                    format!(
+                        "{}{}",
+                        make_key_prefix(&namespace, Some(generation.unwrap_or(0))),
+                        key
+                    )
+                })
+                .map_err(From::from),
+        )
+    }
+}
+
+impl AsyncCache<String> for AsyncRedisCache {
+    type Error = RedisCacheError;
+
+    fn get(&self, key: &str) -> CacheFuture<Option<String>, Self::Error> {
+        let connection = self.connection.clone();
+        Box::new(self.redis_key(key).and_then(move |redis_key| {
+            cmd("GET")
+                .arg(redis_key)
+                .query_async(connection)
+                .map(|(_conn, value)| value)
+                .map_err(From::from)
+        }))
+    }
+
+    fn set(&self, key: &str, value: String) -> CacheFuture<(), Self::Error> {
+        let connection = self.connection.clone();
+        let ttl = self.ttl;
+        Box::new(self.redis_key(key).and_then(move |redis_key| {
+            let query = match ttl {
+                None => cmd("SET")
+                    .arg(redis_key)
+                    .arg(&value)
+                    .query_async(connection),
+                Some(ttl) => cmd("PSETEX")
+                    .arg(redis_key)
+                    .arg(ttl_millis(ttl))
+                    .arg(&value)
+                    .query_async(connection),
+            };
+            query.map(|(_conn, ())| ()).map_err(From::from)
+        }))
+    }
+
+    fn remove(&self, key: &str) -> CacheFuture<bool, Self::Error> {
+        let connection = self.connection.clone();
+        Box::new(self.redis_key(key).and_then(move |redis_key| {
+            cmd("DEL")
+                .arg(redis_key)
+                .query_async(connection)
+                .map(|(_conn, keys_removed): (_, u32)| keys_removed > 0)
+                .map_err(From::from)
+        }))
+    }
 }
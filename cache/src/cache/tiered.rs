@@ -0,0 +1,295 @@
+use failure::Fail;
+use std::time::Duration;
+
+use super::Cache;
+
+/// Two-level cache, usually a small in-memory cache in front of a shared one.
+/// Reads go to L1 first and populate it from L2 on a miss. L1 failures on reads are logged and fall back to L2.
+/// Writes remove the key from L1, then go to L2 and then to L1, while removals go to L1 first,
+/// so that a failed write or removal does not leave L1 with a value that L2 does not have.
+/// Concurrent reads may still populate L1 with the previous value of a key while it is being written.
+#[derive(Clone, Debug)]
+pub struct TieredCache<L1, L2> {
+    l1: L1,
+    l2: L2,
+}
+
+#[derive(Debug, Fail)]
+pub enum TieredCacheError<E1, E2>
+where
+    E1: Fail,
+    E2: Fail,
+{
+    #[fail(display = "An error occurred in L1 cache: {}", _0)]
+    L1(E1),
+    #[fail(display = "An error occurred in L2 cache: {}", _0)]
+    L2(E2),
+}
+
+impl<L1, L2> TieredCache<L1, L2> {
+    pub fn new(l1: L1, l2: L2) -> Self {
+        TieredCache { l1, l2 }
+    }
+
+    pub fn l1(&self) -> &L1 {
+        &self.l1
+    }
+
+    pub fn l2(&self) -> &L2 {
+        &self.l2
+    }
+}
+
+impl<L1, L2, T> Cache<T> for TieredCache<L1, L2>
+where
+    L1: Cache<T>,
+    L2: Cache<T>,
+    T: Clone,
+{
+    type Error = TieredCacheError<L1::Error, L2::Error>;
+
+    fn get(&self, key: &str) -> Result<Option<T>, Self::Error> {
+        match self.l1.get(key) {
+            Ok(Some(value)) => return Ok(Some(value)),
+            Ok(None) => {}
+            Err(e) => warn!("Failed to read {} from L1 cache: {}", key, e),
+        }
+
+        let value = self.l2.get(key).map_err(TieredCacheError::L2)?;
+        if let Some(ref value) = value {
+            if let Err(e) = self.l1.set(key, value.clone()) {
+                warn!("Failed to populate L1 cache with {}: {}", key, e);
+            }
+        }
+        Ok(value)
+    }
+
+    fn set(&self, key: &str, value: T) -> Result<(), Self::Error> {
+        self.l1.remove(key).map_err(TieredCacheError::L1)?;
+        self.l2
+            .set(key, value.clone())
+            .map_err(TieredCacheError::L2)?;
+        self.l1.set(key, value).map_err(TieredCacheError::L1)
+    }
+
+    fn set_with_ttl(&self, key: &str, value: T, ttl: Duration) -> Result<(), Self::Error> {
+        self.l1.remove(key).map_err(TieredCacheError::L1)?;
+        self.l2
+            .set_with_ttl(key, value.clone(), ttl)
+            .map_err(TieredCacheError::L2)?;
+        self.l1
+            .set_with_ttl(key, value, ttl)
+            .map_err(TieredCacheError::L1)
+    }
+
+    fn remove(&self, key: &str) -> Result<bool, Self::Error> {
+        let removed_from_l1 = self.l1.remove(key).map_err(TieredCacheError::L1)?;
+        let removed_from_l2 = self.l2.remove(key).map_err(TieredCacheError::L2)?;
+        Ok(removed_from_l1 || removed_from_l2)
+    }
+
+    fn clear(&self) -> Result<(), Self::Error> {
+        self.l1.clear().map_err(TieredCacheError::L1)?;
+        self.l2.clear().map_err(TieredCacheError::L2)
+    }
+
+    fn get_many(&self, keys: &[&str]) -> Result<Vec<Option<T>>, Self::Error> {
+        let mut values = match self.l1.get_many(keys) {
+            Ok(values) => values,
+            Err(e) => {
+                warn!("Failed to read from L1 cache: {}", e);
+                keys.iter().map(|_| None).collect()
+            }
+        };
+
+        let missing = values
+            .iter()
+            .enumerate()
+            .filter(|(_, value)| value.is_none())
+            .map(|(i, _)| i)
+            .collect::<Vec<_>>();
+        if missing.is_empty() {
+            return Ok(values);
+        }
+
+        let missing_keys = missing.iter().map(|i| keys[*i]).collect::<Vec<_>>();
+        let l2_values = self
+            .l2
+            .get_many(&missing_keys)
+            .map_err(TieredCacheError::L2)?;
+
+        let mut found = vec![];
+        for (i, value) in missing.into_iter().zip(l2_values) {
+            if let Some(value) = value {
+                found.push((keys[i], value.clone()));
+                values[i] = Some(value);
+            }
+        }
+        if let Err(e) = self.l1.set_many(found) {
+            warn!("Failed to populate L1 cache: {}", e);
+        }
+
+        Ok(values)
+    }
+
+    fn set_many(&self, items: Vec<(&str, T)>) -> Result<(), Self::Error> {
+        let keys = items.iter().map(|(key, _)| *key).collect::<Vec<_>>();
+        self.l1.remove_many(&keys).map_err(TieredCacheError::L1)?;
+        self.l2
+            .set_many(items.clone())
+            .map_err(TieredCacheError::L2)?;
+        self.l1.set_many(items).map_err(TieredCacheError::L1)
+    }
+
+    fn remove_many(&self, keys: &[&str]) -> Result<usize, Self::Error> {
+        let removed_from_l1 = self.l1.remove_many(keys).map_err(TieredCacheError::L1)?;
+        let removed_from_l2 = self.l2.remove_many(keys).map_err(TieredCacheError::L2)?;
+        Ok(removed_from_l1.max(removed_from_l2))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cache::InMemoryCache;
+
+    #[derive(Debug, Fail)]
+    #[fail(display = "Broken cache")]
+    struct Broken;
+
+    /// In-memory cache whose reads or writes fail
+    struct FlakyCache {
+        inner: InMemoryCache<u32>,
+        fail_reads: bool,
+        fail_writes: bool,
+    }
+
+    impl FlakyCache {
+        fn check(&self, fail: bool) -> Result<(), Broken> {
+            if fail {
+                Err(Broken)
+            } else {
+                Ok(())
+            }
+        }
+    }
+
+    impl Cache<u32> for FlakyCache {
+        type Error = Broken;
+
+        fn get(&self, key: &str) -> Result<Option<u32>, Self::Error> {
+            self.check(self.fail_reads)?;
+            Ok(self.inner.get(key).unwrap())
+        }
+
+        fn set(&self, key: &str, value: u32) -> Result<(), Self::Error> {
+            self.check(self.fail_writes)?;
+            self.inner.set(key, value).unwrap();
+            Ok(())
+        }
+
+        fn remove(&self, key: &str) -> Result<bool, Self::Error> {
+            Ok(self.inner.remove(key).unwrap())
+        }
+
+        fn clear(&self) -> Result<(), Self::Error> {
+            self.inner.clear().unwrap();
+            Ok(())
+        }
+    }
+
+    fn flaky(fail_reads: bool, fail_writes: bool) -> TieredCache<FlakyCache, InMemoryCache<u32>> {
+        let l1 = FlakyCache {
+            inner: InMemoryCache::new(),
+            fail_reads,
+            fail_writes,
+        };
+        TieredCache::new(l1, InMemoryCache::new())
+    }
+
+    fn tiered() -> TieredCache<InMemoryCache<u32>, InMemoryCache<u32>> {
+        TieredCache::new(InMemoryCache::new(), InMemoryCache::new())
+    }
+
+    #[test]
+    fn test_reads_populate_l1() {
+        let cache = tiered();
+        cache.l2().set("key", 1).unwrap();
+
+        assert_eq!(cache.get("key").unwrap(), Some(1));
+        assert_eq!(cache.l1().get("key").unwrap(), Some(1));
+        assert_eq!(cache.get("missing").unwrap(), None);
+    }
+
+    #[test]
+    fn test_write_through() {
+        let cache = tiered();
+        cache.set("key", 1).unwrap();
+        assert_eq!(cache.l1().get("key").unwrap(), Some(1));
+        assert_eq!(cache.l2().get("key").unwrap(), Some(1));
+
+        cache.l2().remove("key").unwrap();
+        assert!(cache.remove("key").unwrap());
+        assert_eq!(cache.l1().get("key").unwrap(), None);
+        assert!(!cache.remove("key").unwrap());
+    }
+
+    #[test]
+    fn test_batch_operations() {
+        let cache = tiered();
+        cache.l1().set("a", 1).unwrap();
+        cache.l2().set_many(vec![("a", 10), ("b", 2)]).unwrap();
+
+        assert_eq!(
+            cache.get_many(&["a", "b", "c"]).unwrap(),
+            vec![Some(1), Some(2), None]
+        );
+        assert_eq!(cache.l1().get("b").unwrap(), Some(2));
+
+        cache.set_many(vec![("c", 3)]).unwrap();
+        assert_eq!(cache.l2().get("c").unwrap(), Some(3));
+        assert_eq!(cache.remove_many(&["a", "b", "c"]).unwrap(), 3);
+        assert_eq!(
+            cache.l2().get_many(&["a", "b", "c"]).unwrap(),
+            vec![None, None, None]
+        );
+    }
+
+    #[test]
+    fn test_clear() {
+        let cache = tiered();
+        cache.set("key", 1).unwrap();
+
+        cache.clear().unwrap();
+        assert_eq!(cache.l1().get("key").unwrap(), None);
+        assert_eq!(cache.l2().get("key").unwrap(), None);
+    }
+
+    #[test]
+    fn test_l1_read_failures_fall_back_to_l2() {
+        let cache = flaky(true, false);
+        cache.l2().set("key", 1).unwrap();
+
+        assert_eq!(cache.get("key").unwrap(), Some(1));
+        assert_eq!(
+            cache.get_many(&["key", "missing"]).unwrap(),
+            vec![Some(1), None]
+        );
+    }
+
+    #[test]
+    fn test_failed_l1_write_leaves_no_stale_value() {
+        let cache = flaky(false, true);
+        cache.l1().inner.set("key", 1).unwrap();
+
+        match cache.set("key", 2) {
+            Err(TieredCacheError::L1(_)) => {}
+            res => panic!("Unexpected result: {:?}", res),
+        }
+        assert_eq!(cache.l1().inner.get("key").unwrap(), None);
+        assert_eq!(cache.l2().get("key").unwrap(), Some(2));
+
+        // Values read from L2 are returned even if L1 cannot be populated
+        assert_eq!(cache.get("key").unwrap(), Some(2));
+    }
+}
@@ -1,8 +1,9 @@
 use failure::Fail;
 use serde::{de::DeserializeOwned, Serialize};
-use serde_json;
 use std::marker::PhantomData;
+use std::time::Duration;
 
+use super::codec::{Codec, CodecError};
 use super::Cache;
 
 #[derive(Clone, Debug)]
@@ -13,6 +14,7 @@ where
     T: DeserializeOwned + Serialize,
 {
     backend: C,
+    codec: Codec,
     phantom: PhantomData<T>,
 }
 
@@ -23,8 +25,17 @@ where
 {
     #[fail(display = "An error occurred in backend cache")]
     BackendCacheError(E),
-    #[fail(display = "An error occurred on JSON serialization/deserialization")]
-    JsonError(serde_json::Error),
+    #[fail(display = "An error occurred on serialization/deserialization: {}", _0)]
+    CodecError(CodecError),
+}
+
+impl<E> From<CodecError> for TypedCacheError<E>
+where
+    E: Fail,
+{
+    fn from(e: CodecError) -> Self {
+        TypedCacheError::CodecError(e)
+    }
 }
 
 impl<C, E, T> TypedCache<C, E, T>
@@ -36,9 +47,15 @@ where
     pub fn new(backend: C) -> Self {
         TypedCache {
             backend,
+            codec: Codec::default(),
             phantom: PhantomData,
         }
     }
+
+    /// Sets the codec for writing values. Values written with other codecs can still be read.
+    pub fn with_codec(self, codec: Codec) -> Self {
+        TypedCache { codec, ..self }
+    }
 }
 
 impl<C, E, T> Cache<T> for TypedCache<C, E, T>
@@ -53,34 +70,75 @@ where
         self.backend
             .get(key)
             .map_err(|e| TypedCacheError::BackendCacheError(e))
-            .and_then(|json_opt| match json_opt {
+            .and_then(|encoded_opt| match encoded_opt {
                 None => Ok(None),
-                Some(json) => serde_json::from_str(&json)
-                    .map(Some)
-                    .map_err(|e| TypedCacheError::JsonError(e)),
+                Some(encoded) => self.codec.decode(&encoded).map(Some).map_err(From::from),
             })
     }
 
     fn set(&self, key: &str, value: T) -> Result<(), Self::Error> {
-        serde_json::to_string(&value)
-            .map_err(|e| TypedCacheError::JsonError(e))
-            .and_then(|json| {
+        self.codec
+            .encode(&value)
+            .map_err(From::from)
+            .and_then(|encoded| {
                 self.backend
-                    .set(key, json)
+                    .set(key, encoded)
                     .map_err(|e| TypedCacheError::BackendCacheError(e))
             })
     }
 
+    fn set_with_ttl(&self, key: &str, value: T, ttl: Duration) -> Result<(), Self::Error> {
+        let encoded = self.codec.encode(&value)?;
+        self.backend
+            .set_with_ttl(key, encoded, ttl)
+            .map_err(TypedCacheError::BackendCacheError)
+    }
+
     fn remove(&self, key: &str) -> Result<bool, Self::Error> {
         self.backend
             .remove(key)
             .map_err(|e| TypedCacheError::BackendCacheError(e))
     }
+
+    fn clear(&self) -> Result<(), Self::Error> {
+        self.backend
+            .clear()
+            .map_err(TypedCacheError::BackendCacheError)
+    }
+
+    fn get_many(&self, keys: &[&str]) -> Result<Vec<Option<T>>, Self::Error> {
+        self.backend
+            .get_many(keys)
+            .map_err(TypedCacheError::BackendCacheError)?
+            .into_iter()
+            .map(|encoded_opt| match encoded_opt {
+                None => Ok(None),
+                Some(encoded) => self.codec.decode(&encoded).map(Some).map_err(From::from),
+            })
+            .collect()
+    }
+
+    fn set_many(&self, items: Vec<(&str, T)>) -> Result<(), Self::Error> {
+        let items = items
+            .into_iter()
+            .map(|(key, value)| self.codec.encode(&value).map(|encoded| (key, encoded)))
+            .collect::<Result<Vec<_>, _>>()?;
+        self.backend
+            .set_many(items)
+            .map_err(TypedCacheError::BackendCacheError)
+    }
+
+    fn remove_many(&self, keys: &[&str]) -> Result<usize, Self::Error> {
+        self.backend
+            .remove_many(keys)
+            .map_err(TypedCacheError::BackendCacheError)
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use cache::{in_memory::InMemoryCache, typed::TypedCache, Cache};
+    use cache::codec::{Codec, CodecError, Format};
+    use cache::{in_memory::InMemoryCache, typed::TypedCache, typed::TypedCacheError, Cache};
 
     #[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
     struct TestStruct {
@@ -119,4 +177,32 @@ mod tests {
         let missing_value = typed_cache.get(key).expect("Failed to get value");
         assert_eq!(None, missing_value);
     }
+
+    #[test]
+    fn test_codec_change() {
+        let backend = InMemoryCache::<String>::new();
+        let value = TestStruct {
+            s: "string".to_string(),
+            i: 10,
+        };
+
+        let json_cache = TypedCache::<_, _, TestStruct>::new(backend.clone());
+        json_cache.set("old", value.clone()).unwrap();
+
+        let msgpack_cache = TypedCache::<_, _, TestStruct>::new(backend.clone())
+            .with_codec(Codec::new(Format::MessagePack).with_compression(1024));
+        msgpack_cache.set("new", value.clone()).unwrap();
+
+        assert_eq!(
+            msgpack_cache.get_many(&["old", "new"]).unwrap(),
+            vec![Some(value.clone()), Some(value.clone())]
+        );
+        assert_eq!(json_cache.get("new").unwrap(), Some(value));
+
+        backend.set("broken", "~unknown.1~".to_string()).unwrap();
+        match json_cache.get("broken") {
+            Err(TypedCacheError::CodecError(CodecError::UnknownFormat(_))) => {}
+            res => panic!("Unexpected result: {:?}", res),
+        }
+    }
 }
@@ -1,8 +1,17 @@
+extern crate base64;
+extern crate bincode;
 #[macro_use]
 extern crate failure;
+extern crate flate2;
+extern crate futures;
+extern crate futures_cpupool;
+#[macro_use]
+extern crate log;
 extern crate r2d2_redis;
+extern crate rmp_serde;
 extern crate serde;
 extern crate serde_json;
+extern crate uuid;
 
 #[cfg(test)]
 #[macro_use]
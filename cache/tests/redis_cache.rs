@@ -1,23 +1,27 @@
 extern crate r2d2_redis;
+extern crate sha1;
 extern crate stq_cache;
+extern crate tokio_core;
 
-use r2d2_redis::{r2d2::Pool, RedisConnectionManager};
-use std::time::Duration;
-use stq_cache::cache::{redis::RedisCache, Cache};
+mod support;
 
-#[test]
-fn test_redis_cache() {
-    let redis_url = std::env::vars()
-        .find(|(k, _v)| k == "REDIS_URL")
-        .map(|(_k, v)| v)
-        .unwrap_or("redis://127.0.0.1/".to_string());
+use r2d2_redis::{r2d2::Pool, redis::Client};
+use std::thread;
+use std::time::{Duration, Instant};
+use stq_cache::cache::invalidation::{
+    InvalidatedCache, InvalidationPublisher, InvalidationSubscriber,
+};
+use stq_cache::cache::redis::{AsyncRedisCache, RedisCache, RedisCacheError};
+use stq_cache::cache::{AtomicCache, Cache, DistributedLock, InMemoryCache, LeaseBackend};
+use tokio_core::reactor::Core;
 
-    let manager = RedisConnectionManager::new(redis_url.as_ref())
-        .expect("Failed to create connection manager");
+use support::fake_redis::FakeRedis;
+use support::TestRedis;
 
-    let pool = Pool::builder()
-        .build(manager)
-        .expect("Failed to create connection pool");
+#[test]
+fn test_redis_cache() {
+    let redis = TestRedis::from_env();
+    let pool = redis.pool();
 
     let ttl = Duration::from_secs(3);
     let cache = RedisCache::new(pool.clone(), "base_key".to_string()).with_ttl(ttl);
@@ -47,9 +51,456 @@ fn test_redis_cache() {
         .get("key_2")
         .expect("Failed to get value")
         .expect("Redis did not return a value");
+    cache
+        .set_with_ttl("long_lived", "value".to_string(), Duration::from_secs(60))
+        .expect("Failed to set value");
+
+    // Sub-second TTLs are not truncated to 0
+    let short_lived =
+        RedisCache::new(pool.clone(), "base_key".to_string()).with_ttl(Duration::from_millis(500));
+    short_lived
+        .set("short_lived", "value".to_string())
+        .expect("Failed to set value");
+    assert_eq!(
+        short_lived.get("short_lived").expect("Failed to get value"),
+        Some("value".to_string())
+    );
 
-    std::thread::sleep(ttl + Duration::from_secs(1));
+    redis.advance(ttl + Duration::from_secs(1));
 
+    assert_eq!(
+        short_lived.get("short_lived").expect("Failed to get value"),
+        None
+    );
     let expired_value_2 = cache.get("key_2").expect("Failed to get value");
     assert_eq!(None, expired_value_2);
+    assert_eq!(
+        cache.get("long_lived").expect("Failed to get value"),
+        Some("value".to_string())
+    );
+}
+
+#[test]
+fn test_async_redis_cache() {
+    use stq_cache::cache::AsyncCache;
+
+    let redis = TestRedis::from_env();
+    let mut core = Core::new().unwrap();
+    let client = Client::open(redis.url().as_ref()).unwrap();
+    let connection = core
+        .run(client.get_shared_async_connection())
+        .expect("Failed to connect");
+
+    let cache = AsyncRedisCache::new(connection, "async".to_string()).with_versioned_keys();
+    let sync_cache = RedisCache::new(redis.pool(), "async".to_string()).with_versioned_keys();
+
+    core.run(cache.set("key", "value".to_string())).unwrap();
+    assert_eq!(sync_cache.get("key").unwrap(), Some("value".to_string()));
+
+    // Versioned keys are shared with `RedisCache`
+    sync_cache.clear().unwrap();
+    assert_eq!(core.run(cache.get("key")).unwrap(), None);
+    sync_cache.set("key", "other".to_string()).unwrap();
+    assert_eq!(
+        core.run(cache.get("key")).unwrap(),
+        Some("other".to_string())
+    );
+
+    assert!(core.run(cache.remove("key")).unwrap());
+    assert!(!core.run(cache.remove("key")).unwrap());
+    assert_eq!(sync_cache.get("key").unwrap(), None);
+}
+
+#[test]
+fn test_redis_cache_clear() {
+    let redis = TestRedis::from_env();
+    let pool = redis.pool();
+
+    for cache in &[
+        RedisCache::new(pool.clone(), "clear_key".to_string()),
+        RedisCache::new(pool.clone(), "versioned_clear_key".to_string())
+            .with_ttl(Duration::from_secs(60))
+            .with_versioned_keys(),
+    ] {
+        cache
+            .set_many(vec![("a", "1".to_string()), ("b", "2".to_string())])
+            .expect("Failed to set values");
+        cache.clear().expect("Failed to clear cache");
+        assert_eq!(
+            cache.get_many(&["a", "b"]).expect("Failed to get values"),
+            vec![None, None]
+        );
+
+        cache
+            .set("a", "3".to_string())
+            .expect("Failed to set value");
+        assert_eq!(
+            cache.get("a").expect("Failed to get value"),
+            Some("3".to_string())
+        );
+    }
+
+    // Clearing keeps leases, values of the versioned layout and of nested namespaces,
+    // and values never touch the reserved keys, whatever they look like
+    let cache = RedisCache::new(pool.clone(), "shared_clear_key".to_string());
+    let versioned =
+        RedisCache::new(pool.clone(), "shared_clear_key".to_string()).with_versioned_keys();
+    let nested = RedisCache::new(pool, "shared_clear_key:nested".to_string());
+    versioned
+        .set("a", "1".to_string())
+        .expect("Failed to set value");
+    nested
+        .set("a", "1".to_string())
+        .expect("Failed to set value");
+    assert!(cache
+        .try_acquire("lock", "token", Duration::from_secs(60))
+        .expect("Failed to acquire lease"));
+    let reserved_lookalikes = ["#lock:lock", "#generation", "lock:lock", "v0:a", "nested:a"];
+    for key in &reserved_lookalikes {
+        cache
+            .set(key, "value".to_string())
+            .expect("Failed to set value");
+    }
+    assert_eq!(
+        versioned.get("a").expect("Failed to get value"),
+        Some("1".to_string())
+    );
+
+    cache.clear().expect("Failed to clear cache");
+    assert_eq!(
+        cache
+            .get_many(&reserved_lookalikes)
+            .expect("Failed to get values"),
+        vec![None; reserved_lookalikes.len()]
+    );
+    assert_eq!(
+        versioned.get("a").expect("Failed to get value"),
+        Some("1".to_string())
+    );
+    assert_eq!(
+        nested.get("a").expect("Failed to get value"),
+        Some("1".to_string())
+    );
+    assert!(!cache
+        .try_acquire("lock", "other", Duration::from_secs(60))
+        .expect("Failed to acquire lease"));
+}
+
+#[test]
+fn test_redis_cache_tags() {
+    let redis = TestRedis::from_env();
+    let pool = redis.pool();
+    let cache = RedisCache::new(pool, "tags_key".to_string());
+
+    cache
+        .set_with_tags("a", "1".to_string(), &["odd", "all"])
+        .expect("Failed to set value");
+    cache
+        .set_with_tags("b", "2".to_string(), &["all"])
+        .expect("Failed to set value");
+
+    assert_eq!(
+        cache
+            .invalidate_tag("odd")
+            .expect("Failed to invalidate tag"),
+        1
+    );
+    assert_eq!(
+        cache.get_many(&["a", "b"]).expect("Failed to get values"),
+        vec![None, Some("2".to_string())]
+    );
+    assert_eq!(
+        cache
+            .invalidate_tag("all")
+            .expect("Failed to invalidate tag"),
+        1
+    );
+    assert_eq!(cache.get("b").expect("Failed to get value"), None);
+
+    // Tag sets expire with the values
+    let cache =
+        RedisCache::new(redis.pool(), "tags_key".to_string()).with_ttl(Duration::from_secs(10));
+    cache
+        .set_with_tags("c", "3".to_string(), &["all"])
+        .expect("Failed to set value");
+    redis.advance(Duration::from_secs(11));
+    assert_eq!(
+        cache
+            .invalidate_tag("all")
+            .expect("Failed to invalidate tag"),
+        0
+    );
+}
+
+#[test]
+fn test_redis_cache_atomic_operations() {
+    let redis = TestRedis::from_env();
+    let pool = redis.pool();
+    let cache = RedisCache::new(pool, "atomic_key".to_string());
+    cache.clear().expect("Failed to clear cache");
+
+    let ttl = Some(Duration::from_secs(60));
+    assert_eq!(
+        cache
+            .incr_by("counter", 2, ttl)
+            .expect("Failed to increment"),
+        2
+    );
+    assert_eq!(
+        cache
+            .incr_by("counter", -3, ttl)
+            .expect("Failed to increment"),
+        -1
+    );
+
+    assert!(cache
+        .set_if_absent("marker", "1".to_string(), ttl)
+        .expect("Failed to set value"));
+    assert!(!cache
+        .set_if_absent("marker", "2".to_string(), ttl)
+        .expect("Failed to set value"));
+}
+
+#[test]
+fn test_redis_cache_compare_and_swap() {
+    let redis = TestRedis::from_env();
+    let pool = redis.pool();
+    let cache = RedisCache::new(pool, "cas_key".to_string());
+    cache.clear().expect("Failed to clear cache");
+
+    cache
+        .set("marker", "1".to_string())
+        .expect("Failed to set value");
+    assert!(!cache
+        .compare_and_swap("marker", None, "2".to_string())
+        .expect("Failed to swap value"));
+    assert!(cache
+        .compare_and_swap("marker", Some(&"1".to_string()), "2".to_string())
+        .expect("Failed to swap value"));
+    assert_eq!(
+        cache.get("marker").expect("Failed to get value"),
+        Some("2".to_string())
+    );
+
+    // Swapped values get the TTL of the cache
+    let cache =
+        RedisCache::new(redis.pool(), "cas_key".to_string()).with_ttl(Duration::from_secs(10));
+    assert!(cache
+        .compare_and_swap("new", None, "1".to_string())
+        .expect("Failed to swap value"));
+    redis.advance(Duration::from_secs(11));
+    assert_eq!(cache.get("new").expect("Failed to get value"), None);
+}
+
+#[test]
+fn test_redis_lock() {
+    let redis = TestRedis::from_env();
+    let pool = redis.pool();
+    let lock = DistributedLock::new(
+        RedisCache::new(pool, "lock_key".to_string()),
+        Duration::from_secs(60),
+    );
+
+    let guard = lock
+        .try_lock("conversion")
+        .expect("Failed to take lease")
+        .expect("Lease is held");
+    assert!(lock
+        .try_lock("conversion")
+        .expect("Failed to take lease")
+        .is_none());
+    assert!(guard.renew().expect("Failed to renew lease"));
+    assert!(guard.release().expect("Failed to release lease"));
+
+    let guard = lock
+        .try_lock("conversion")
+        .expect("Failed to take lease")
+        .expect("Lease is held");
+    drop(guard);
+    assert!(lock
+        .try_lock("conversion")
+        .expect("Failed to take lease")
+        .is_some());
+
+    // Expired holder can neither renew nor release the lease of the next one
+    let lock = DistributedLock::new(
+        RedisCache::new(redis.pool(), "lock_key".to_string()),
+        Duration::from_secs(1),
+    );
+    let expired = lock
+        .try_lock("expiring")
+        .expect("Failed to take lease")
+        .expect("Lease is held");
+    redis.advance(Duration::from_secs(2));
+    let guard = lock
+        .try_lock("expiring")
+        .expect("Failed to take lease")
+        .expect("Lease is held");
+    assert!(!expired.renew().expect("Failed to renew lease"));
+    assert!(!expired.release().expect("Failed to release lease"));
+    assert!(guard.renew().expect("Failed to renew lease"));
+    assert!(guard.release().expect("Failed to release lease"));
+}
+
+#[test]
+fn test_redis_cache_batch_operations() {
+    let redis = TestRedis::from_env();
+    let pool = redis.pool();
+    let cache = RedisCache::new(pool, "batch_key".to_string()).with_ttl(Duration::from_secs(10));
+
+    cache
+        .set_many(vec![("a", "1".to_string()), ("b", "2".to_string())])
+        .expect("Failed to set values");
+    assert_eq!(
+        cache
+            .get_many(&["a", "b", "c"])
+            .expect("Failed to get values"),
+        vec![Some("1".to_string()), Some("2".to_string()), None]
+    );
+    assert_eq!(
+        cache
+            .remove_many(&["a", "c"])
+            .expect("Failed to remove values"),
+        1
+    );
+
+    redis.advance(Duration::from_secs(11));
+    assert_eq!(cache.get("b").expect("Failed to get value"), None);
+    assert_eq!(cache.get_many(&[]).expect("Failed to get values"), vec![]);
+}
+
+#[test]
+fn test_redis_cache_counter_ttl() {
+    let redis = TestRedis::from_env();
+    let pool = redis.pool();
+    let cache = RedisCache::new(pool, "counter_ttl_key".to_string());
+    cache.clear().expect("Failed to clear cache");
+
+    let ttl = Some(Duration::from_secs(2));
+    assert_eq!(
+        cache
+            .incr_by("counter", 1, ttl)
+            .expect("Failed to increment"),
+        1
+    );
+    redis.advance(Duration::from_secs(1));
+    assert_eq!(
+        cache
+            .incr_by("counter", 1, ttl)
+            .expect("Failed to increment"),
+        2
+    );
+    redis.advance(Duration::from_millis(1500));
+    assert_eq!(
+        cache
+            .incr_by("counter", 1, ttl)
+            .expect("Failed to increment"),
+        1
+    );
+}
+
+#[test]
+fn test_redis_cache_acquire_timeout() {
+    let redis = TestRedis::from_env();
+    let pool = Pool::builder()
+        .max_size(1)
+        .build(r2d2_redis::RedisConnectionManager::new(redis.url().as_ref()).unwrap())
+        .expect("Failed to create connection pool");
+    let cache = RedisCache::new(pool.clone(), "acquire_key".to_string())
+        .with_acquire_timeout(Duration::from_millis(50));
+
+    {
+        let _conn = pool.get().expect("Failed to get connection");
+        let started = Instant::now();
+        match cache.get("key") {
+            Err(RedisCacheError::NoAvailableConnections) => {}
+            res => panic!("Unexpected result: {:?}", res),
+        }
+        assert!(started.elapsed() >= Duration::from_millis(50));
+    }
+
+    assert_eq!(cache.get("key").expect("Failed to get value"), None);
+}
+
+#[test]
+fn test_redis_invalidation() {
+    let redis = TestRedis::from_env();
+    let pool = redis.pool();
+    let client = || Client::open(redis.url().as_ref()).expect("Failed to create client");
+
+    let local = InMemoryCache::<String>::new();
+    let other_local = InMemoryCache::<String>::new();
+    other_local
+        .set("key", "stale".to_string())
+        .expect("Failed to set value");
+
+    let publisher = InvalidationPublisher::new(pool.clone(), "invalidation_key")
+        .with_acquire_timeout(Duration::from_secs(1));
+    let _own_listener = publisher
+        .subscriber(client())
+        .with_cache(local.clone())
+        .spawn()
+        .expect("Failed to subscribe");
+    let _listener = InvalidationSubscriber::new(client(), "invalidation_key")
+        .with_cache(other_local.clone())
+        .spawn()
+        .expect("Failed to subscribe");
+
+    let cache = InvalidatedCache::new(local.clone(), publisher);
+    cache
+        .set("key", "fresh".to_string())
+        .expect("Failed to set value");
+    wait_for_removal(&other_local, "key");
+
+    // Invalidations of other instances are still received, and after the one of this instance
+    local
+        .set("other_key", "stale".to_string())
+        .expect("Failed to set value");
+    InvalidationPublisher::new(pool, "invalidation_key")
+        .publish("other_key")
+        .expect("Failed to publish");
+    wait_for_removal(&local, "other_key");
+    assert_eq!(
+        cache.get("key").expect("Failed to get value"),
+        Some("fresh".to_string())
+    );
+}
+
+fn wait_for_removal(cache: &InMemoryCache<String>, key: &str) {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while Cache::get(cache, key)
+        .expect("Failed to get value")
+        .is_some()
+    {
+        assert!(Instant::now() < deadline, "Invalidation was not received");
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+#[test]
+fn test_fake_redis_scan_match() {
+    use r2d2_redis::redis::{cmd, Commands};
+
+    let server = FakeRedis::start();
+    let conn = Client::open(server.url().as_ref())
+        .unwrap()
+        .get_connection()
+        .unwrap();
+    for key in &["ns:key", "ns:k", "n*:key", "nn:key"] {
+        let () = conn.set(*key, "value").unwrap();
+    }
+
+    let scan = |pattern: &str| {
+        let (_cursor, mut keys): (u64, Vec<String>) = cmd("SCAN")
+            .arg(0)
+            .arg("MATCH")
+            .arg(pattern)
+            .query(&conn)
+            .unwrap();
+        keys.sort();
+        keys
+    };
+    assert_eq!(scan("ns:*"), vec!["ns:k", "ns:key"]);
+    assert_eq!(scan("ns:?"), vec!["ns:k"]);
+    assert_eq!(scan("n\\*:*"), vec!["n*:key"]);
 }
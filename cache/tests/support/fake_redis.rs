@@ -0,0 +1,638 @@
+//! In-process Redis server implementing the commands used by `stq_cache`.
+//!
+//! Supported commands: PING, GET, SET (with EX, PX, NX and XX), SETEX, PSETEX, MGET, DEL, UNLINK, INCR, INCRBY,
+//! EXPIRE, PEXPIRE, TTL, PTTL, SADD, SMEMBERS, SCAN (in a single batch), MULTI, EXEC, DISCARD, PUBLISH,
+//! SUBSCRIBE, UNSUBSCRIBE, EVAL, EVALSHA and SCRIPT LOAD. Scripts are run by the interpreter of `support::lua`,
+//! which only knows the subset of Lua used by `stq_cache`.
+//!
+//! Time does not pass on its own: keys expire only when the clock is moved with `FakeRedis::advance`.
+use sha1::Sha1;
+use std::collections::{BTreeSet, HashMap};
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::Duration;
+
+use super::lua::{self, Value as LuaValue};
+
+#[derive(Clone, Debug)]
+enum Value {
+    String(Vec<u8>),
+    Set(BTreeSet<Vec<u8>>),
+}
+
+#[derive(Debug)]
+struct Entry {
+    value: Value,
+    /// Clock time in milliseconds
+    expires_at: Option<u64>,
+}
+
+#[derive(Debug, PartialEq)]
+enum Reply {
+    Status(&'static str),
+    Error(String),
+    Integer(i64),
+    Bulk(Option<Vec<u8>>),
+    Array(Vec<Reply>),
+}
+
+impl Reply {
+    fn ok() -> Self {
+        Reply::Status("OK")
+    }
+
+    fn error(message: &str) -> Self {
+        Reply::Error(format!("ERR {}", message))
+    }
+
+    fn wrong_type() -> Self {
+        Reply::Error(
+            "WRONGTYPE Operation against a key holding the wrong kind of value".to_string(),
+        )
+    }
+
+    fn write_to(&self, out: &mut Vec<u8>) {
+        match self {
+            Reply::Status(status) => out.extend(format!("+{}\r\n", status).into_bytes()),
+            Reply::Error(message) => out.extend(format!("-{}\r\n", message).into_bytes()),
+            Reply::Integer(i) => out.extend(format!(":{}\r\n", i).into_bytes()),
+            Reply::Bulk(None) => out.extend(b"$-1\r\n"),
+            Reply::Bulk(Some(bytes)) => {
+                out.extend(format!("${}\r\n", bytes.len()).into_bytes());
+                out.extend(bytes);
+                out.extend(b"\r\n");
+            }
+            Reply::Array(items) => {
+                out.extend(format!("*{}\r\n", items.len()).into_bytes());
+                for item in items {
+                    item.write_to(out);
+                }
+            }
+        }
+    }
+}
+
+fn bulk<T: Into<Vec<u8>>>(bytes: T) -> Reply {
+    Reply::Bulk(Some(bytes.into()))
+}
+
+/// Converts replies of `redis.call` as Redis does, except that status replies become plain strings
+fn to_lua(reply: Reply) -> Result<LuaValue, String> {
+    Ok(match reply {
+        Reply::Status(status) => LuaValue::Str(status.as_bytes().to_vec()),
+        Reply::Error(message) => return Err(message),
+        Reply::Integer(i) => LuaValue::Number(i),
+        Reply::Bulk(None) => LuaValue::Bool(false),
+        Reply::Bulk(Some(bytes)) => LuaValue::Str(bytes),
+        Reply::Array(items) => {
+            LuaValue::Table(items.into_iter().map(to_lua).collect::<Result<_, _>>()?)
+        }
+    })
+}
+
+fn from_lua(value: LuaValue) -> Reply {
+    match value {
+        LuaValue::Nil | LuaValue::Bool(false) => Reply::Bulk(None),
+        LuaValue::Bool(true) => Reply::Integer(1),
+        LuaValue::Number(n) => Reply::Integer(n),
+        LuaValue::Str(s) => bulk(s),
+        LuaValue::Table(items) => Reply::Array(items.into_iter().map(from_lua).collect()),
+    }
+}
+
+fn sha1_hex(source: &[u8]) -> String {
+    let mut hash = Sha1::new();
+    hash.update(source);
+    hash.digest().to_string()
+}
+
+type Writer = Arc<Mutex<TcpStream>>;
+
+#[derive(Default)]
+struct State {
+    now: u64,
+    entries: HashMap<Vec<u8>, Entry>,
+    /// Sources of loaded scripts by SHA1
+    scripts: HashMap<String, Vec<u8>>,
+    subscribers: HashMap<Vec<u8>, Vec<(usize, Writer)>>,
+    next_connection_id: usize,
+}
+
+impl State {
+    fn live_entry(&mut self, key: &[u8]) -> Option<&mut Entry> {
+        let now = self.now;
+        let expired = match self.entries.get(key) {
+            None => return None,
+            Some(entry) => entry.expires_at.map(|at| at <= now).unwrap_or(false),
+        };
+        if expired {
+            self.entries.remove(key);
+            return None;
+        }
+        self.entries.get_mut(key)
+    }
+
+    fn remove(&mut self, key: &[u8]) -> bool {
+        self.live_entry(key).is_some() && self.entries.remove(key).is_some()
+    }
+}
+
+/// Glob matching as in `KEYS` and `SCAN`, supporting `*`, `?` and `\` escapes
+fn glob_matches(pattern: &[u8], s: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => s.is_empty(),
+        Some((b'*', rest)) => (0..=s.len()).any(|i| glob_matches(rest, &s[i..])),
+        Some((b'?', rest)) => !s.is_empty() && glob_matches(rest, &s[1..]),
+        Some((b'\\', rest)) if !rest.is_empty() => {
+            s.first() == Some(&rest[0]) && glob_matches(&rest[1..], &s[1..])
+        }
+        Some((c, rest)) => s.first() == Some(c) && glob_matches(rest, &s[1..]),
+    }
+}
+
+fn parse_int(arg: &[u8]) -> Option<i64> {
+    String::from_utf8_lossy(arg).parse().ok()
+}
+
+fn read_command<R: BufRead>(reader: &mut R) -> io::Result<Option<Vec<Vec<u8>>>> {
+    fn read_line<R: BufRead>(reader: &mut R) -> io::Result<String> {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        Ok(line.trim_end().to_string())
+    }
+
+    fn parse_len(line: &str, prefix: char) -> io::Result<usize> {
+        if !line.starts_with(prefix) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Unexpected line: {}", line),
+            ));
+        }
+        line[1..]
+            .parse()
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, line.to_string()))
+    }
+
+    let header = match read_line(reader) {
+        Ok(header) => header,
+        Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let count = parse_len(&header, '*')?;
+
+    let mut args = Vec::with_capacity(count);
+    for _ in 0..count {
+        let len = parse_len(&read_line(reader)?, '$')?;
+        let mut arg = vec![0; len + 2];
+        reader.read_exact(&mut arg)?;
+        arg.truncate(len);
+        args.push(arg);
+    }
+    Ok(Some(args))
+}
+
+/// Fake Redis listening on a random local port until the test process exits
+#[derive(Clone)]
+pub struct FakeRedis {
+    addr: SocketAddr,
+    state: Arc<Mutex<State>>,
+}
+
+impl FakeRedis {
+    pub fn start() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("Failed to bind fake Redis");
+        let server = FakeRedis {
+            addr: listener
+                .local_addr()
+                .expect("Failed to get fake Redis address"),
+            state: Default::default(),
+        };
+
+        let accepting = server.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(_) => continue,
+                };
+                let server = accepting.clone();
+                thread::spawn(move || {
+                    let _ = server.serve(stream);
+                });
+            }
+        });
+
+        server
+    }
+
+    pub fn url(&self) -> String {
+        format!("redis://{}/", self.addr)
+    }
+
+    /// Moves the clock of the server forward
+    pub fn advance(&self, duration: Duration) {
+        self.lock_state().now += duration.as_secs() * 1000 + u64::from(duration.subsec_millis());
+    }
+
+    fn lock_state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn serve(&self, stream: TcpStream) -> io::Result<()> {
+        let writer = Arc::new(Mutex::new(stream.try_clone()?));
+        let mut reader = BufReader::new(stream);
+        let connection_id = {
+            let mut state = self.lock_state();
+            state.next_connection_id += 1;
+            state.next_connection_id
+        };
+
+        let mut transaction: Option<Vec<Vec<Vec<u8>>>> = None;
+        let res = loop {
+            let args = match read_command(&mut reader) {
+                Ok(Some(ref args)) if args.is_empty() => continue,
+                Ok(Some(args)) => args,
+                Ok(None) => break Ok(()),
+                Err(e) => break Err(e),
+            };
+            let name = String::from_utf8_lossy(&args[0]).to_uppercase();
+
+            let replies = match (name.as_str(), transaction.take()) {
+                ("MULTI", None) => {
+                    transaction = Some(vec![]);
+                    vec![Reply::ok()]
+                }
+                ("EXEC", Some(commands)) => {
+                    let mut state = self.lock_state();
+                    let replies = commands
+                        .iter()
+                        .map(|args| self.execute(&mut state, args))
+                        .collect();
+                    vec![Reply::Array(replies)]
+                }
+                ("DISCARD", Some(_)) => vec![Reply::ok()],
+                ("EXEC", None) | ("DISCARD", None) => {
+                    vec![Reply::error(&format!("{} without MULTI", name))]
+                }
+                (_, Some(mut commands)) => {
+                    commands.push(args);
+                    transaction = Some(commands);
+                    vec![Reply::Status("QUEUED")]
+                }
+                ("SUBSCRIBE", None) => self.subscribe(connection_id, &writer, &args[1..]),
+                ("UNSUBSCRIBE", None) => self.unsubscribe(connection_id, &args[1..]),
+                ("PUNSUBSCRIBE", None) => vec![Reply::Array(vec![
+                    bulk("punsubscribe"),
+                    Reply::Bulk(None),
+                    Reply::Integer(self.subscription_count(connection_id) as i64),
+                ])],
+                ("PUBLISH", None) if args.len() == 3 => {
+                    vec![self.publish(&args[1], &args[2])]
+                }
+                (_, None) => {
+                    let mut state = self.lock_state();
+                    vec![self.execute(&mut state, &args)]
+                }
+            };
+
+            let mut out = vec![];
+            for reply in replies {
+                reply.write_to(&mut out);
+            }
+            let mut writer = writer.lock().unwrap_or_else(|e| e.into_inner());
+            if let Err(e) = writer.write_all(&out) {
+                break Err(e);
+            }
+        };
+
+        self.unsubscribe(connection_id, &[]);
+        res
+    }
+
+    fn subscribe(&self, connection_id: usize, writer: &Writer, channels: &[Vec<u8>]) -> Vec<Reply> {
+        let mut replies = vec![];
+        for channel in channels {
+            self.lock_state()
+                .subscribers
+                .entry(channel.clone())
+                .or_default()
+                .push((connection_id, writer.clone()));
+            replies.push(Reply::Array(vec![
+                bulk("subscribe"),
+                bulk(channel.clone()),
+                Reply::Integer(self.subscription_count(connection_id) as i64),
+            ]));
+        }
+        replies
+    }
+
+    /// Unsubscribes from `channels`, or from all channels if empty
+    fn unsubscribe(&self, connection_id: usize, channels: &[Vec<u8>]) -> Vec<Reply> {
+        let channels = if channels.is_empty() {
+            self.lock_state()
+                .subscribers
+                .iter()
+                .filter(|(_, subscribers)| subscribers.iter().any(|(id, _)| *id == connection_id))
+                .map(|(channel, _)| channel.clone())
+                .collect()
+        } else {
+            channels.to_vec()
+        };
+        if channels.is_empty() {
+            return vec![Reply::Array(vec![
+                bulk("unsubscribe"),
+                Reply::Bulk(None),
+                Reply::Integer(0),
+            ])];
+        }
+
+        let mut replies = vec![];
+        for channel in channels {
+            if let Some(subscribers) = self.lock_state().subscribers.get_mut(&channel) {
+                subscribers.retain(|(id, _)| *id != connection_id);
+            }
+            replies.push(Reply::Array(vec![
+                bulk("unsubscribe"),
+                bulk(channel),
+                Reply::Integer(self.subscription_count(connection_id) as i64),
+            ]));
+        }
+        replies
+    }
+
+    fn subscription_count(&self, connection_id: usize) -> usize {
+        self.lock_state()
+            .subscribers
+            .values()
+            .filter(|subscribers| subscribers.iter().any(|(id, _)| *id == connection_id))
+            .count()
+    }
+
+    fn publish(&self, channel: &[u8], message: &[u8]) -> Reply {
+        let subscribers = self
+            .lock_state()
+            .subscribers
+            .get(channel)
+            .cloned()
+            .unwrap_or_default();
+
+        let mut out = vec![];
+        Reply::Array(vec![
+            bulk("message"),
+            bulk(channel.to_vec()),
+            bulk(message.to_vec()),
+        ])
+        .write_to(&mut out);
+
+        let mut received = 0;
+        for (_, writer) in subscribers {
+            let mut writer = writer.lock().unwrap_or_else(|e| e.into_inner());
+            if writer.write_all(&out).is_ok() {
+                received += 1;
+            }
+        }
+        Reply::Integer(received)
+    }
+
+    fn execute(&self, state: &mut State, args: &[Vec<u8>]) -> Reply {
+        let name = String::from_utf8_lossy(&args[0]).to_uppercase();
+        let args = &args[1..];
+        let now = state.now;
+
+        match (name.as_str(), args.len()) {
+            ("PING", _) => Reply::Status("PONG"),
+            ("GET", 1) => match state.live_entry(&args[0]) {
+                None => Reply::Bulk(None),
+                Some(Entry {
+                    value: Value::String(value),
+                    ..
+                }) => bulk(value.clone()),
+                Some(_) => Reply::wrong_type(),
+            },
+            ("MGET", n) if n > 0 => Reply::Array(
+                args.iter()
+                    .map(|key| match state.live_entry(key) {
+                        Some(Entry {
+                            value: Value::String(value),
+                            ..
+                        }) => bulk(value.clone()),
+                        _ => Reply::Bulk(None),
+                    })
+                    .collect(),
+            ),
+            ("SET", n) if n >= 2 => {
+                let mut ttl = None;
+                let mut nx = false;
+                let mut xx = false;
+                let mut options = args[2..].iter();
+                while let Some(option) = options.next() {
+                    match String::from_utf8_lossy(option).to_uppercase().as_str() {
+                        "NX" => nx = true,
+                        "XX" => xx = true,
+                        unit @ "EX" | unit @ "PX" => {
+                            let multiplier = if unit == "EX" { 1000 } else { 1 };
+                            match options.next().and_then(|arg| parse_int(arg)) {
+                                Some(value) if value > 0 => ttl = Some(value as u64 * multiplier),
+                                _ => return Reply::error("invalid expire time in set"),
+                            }
+                        }
+                        _ => return Reply::error("syntax error"),
+                    }
+                }
+
+                let exists = state.live_entry(&args[0]).is_some();
+                if (nx && exists) || (xx && !exists) {
+                    return Reply::Bulk(None);
+                }
+                state.entries.insert(
+                    args[0].clone(),
+                    Entry {
+                        value: Value::String(args[1].clone()),
+                        expires_at: ttl.map(|ttl| now + ttl),
+                    },
+                );
+                Reply::ok()
+            }
+            ("SETEX", 3) | ("PSETEX", 3) => {
+                let multiplier = if name == "SETEX" { 1000 } else { 1 };
+                match parse_int(&args[1]) {
+                    Some(ttl) if ttl > 0 => {
+                        state.entries.insert(
+                            args[0].clone(),
+                            Entry {
+                                value: Value::String(args[2].clone()),
+                                expires_at: Some(now + ttl as u64 * multiplier),
+                            },
+                        );
+                        Reply::ok()
+                    }
+                    _ => Reply::error(&format!("invalid expire time in {}", name.to_lowercase())),
+                }
+            }
+            ("DEL", n) | ("UNLINK", n) if n > 0 => {
+                Reply::Integer(args.iter().filter(|key| state.remove(key)).count() as i64)
+            }
+            ("INCR", 1) | ("INCRBY", 2) => {
+                let delta = if name == "INCR" {
+                    Some(1)
+                } else {
+                    parse_int(&args[1])
+                };
+                let delta = match delta {
+                    Some(delta) => delta,
+                    None => return Reply::error("value is not an integer or out of range"),
+                };
+                let (current, expires_at) = match state.live_entry(&args[0]) {
+                    None => (Some(0), None),
+                    Some(Entry {
+                        value: Value::String(value),
+                        expires_at,
+                    }) => (parse_int(value), *expires_at),
+                    Some(_) => return Reply::wrong_type(),
+                };
+                match current.and_then(|current| current.checked_add(delta)) {
+                    None => Reply::error("value is not an integer or out of range"),
+                    Some(value) => {
+                        state.entries.insert(
+                            args[0].clone(),
+                            Entry {
+                                value: Value::String(value.to_string().into_bytes()),
+                                expires_at,
+                            },
+                        );
+                        Reply::Integer(value)
+                    }
+                }
+            }
+            ("EXPIRE", 2) | ("PEXPIRE", 2) => {
+                let multiplier = if name == "EXPIRE" { 1000 } else { 1 };
+                let ttl = match parse_int(&args[1]) {
+                    Some(ttl) => ttl,
+                    None => return Reply::error("value is not an integer or out of range"),
+                };
+                if ttl <= 0 {
+                    return Reply::Integer(state.remove(&args[0]) as i64);
+                }
+                match state.live_entry(&args[0]) {
+                    None => Reply::Integer(0),
+                    Some(entry) => {
+                        entry.expires_at = Some(now + ttl as u64 * multiplier);
+                        Reply::Integer(1)
+                    }
+                }
+            }
+            ("TTL", 1) | ("PTTL", 1) => {
+                let divisor = if name == "TTL" { 1000 } else { 1 };
+                match state.live_entry(&args[0]) {
+                    None => Reply::Integer(-2),
+                    Some(Entry {
+                        expires_at: None, ..
+                    }) => Reply::Integer(-1),
+                    Some(Entry {
+                        expires_at: Some(at),
+                        ..
+                    }) => Reply::Integer(((*at - now) / divisor) as i64),
+                }
+            }
+            ("SADD", n) if n >= 2 => {
+                let members = &args[1..];
+                let exists = state.live_entry(&args[0]).is_some();
+                if !exists {
+                    state.entries.insert(
+                        args[0].clone(),
+                        Entry {
+                            value: Value::Set(BTreeSet::new()),
+                            expires_at: None,
+                        },
+                    );
+                }
+                match state.entries.get_mut(&args[0]) {
+                    Some(Entry {
+                        value: Value::Set(set),
+                        ..
+                    }) => Reply::Integer(
+                        members
+                            .iter()
+                            .filter(|member| set.insert(member.to_vec()))
+                            .count() as i64,
+                    ),
+                    _ => Reply::wrong_type(),
+                }
+            }
+            ("SMEMBERS", 1) => match state.live_entry(&args[0]) {
+                None => Reply::Array(vec![]),
+                Some(Entry {
+                    value: Value::Set(set),
+                    ..
+                }) => Reply::Array(set.iter().cloned().map(bulk).collect()),
+                Some(_) => Reply::wrong_type(),
+            },
+            ("SCAN", n) if n >= 1 => {
+                let mut pattern = b"*".to_vec();
+                let mut options = args[1..].iter();
+                while let Some(option) = options.next() {
+                    let value = options.next();
+                    match (
+                        String::from_utf8_lossy(option).to_uppercase().as_str(),
+                        value,
+                    ) {
+                        ("MATCH", Some(value)) => pattern = value.clone(),
+                        ("COUNT", Some(_)) => {}
+                        _ => return Reply::error("syntax error"),
+                    }
+                }
+
+                let keys = state
+                    .entries
+                    .iter()
+                    .filter(|(_, entry)| entry.expires_at.map(|at| at > now).unwrap_or(true))
+                    .map(|(key, _)| key)
+                    .filter(|key| glob_matches(&pattern, key))
+                    .cloned()
+                    .map(bulk)
+                    .collect();
+                Reply::Array(vec![bulk("0"), Reply::Array(keys)])
+            }
+            ("SCRIPT", 2) if args[0].eq_ignore_ascii_case(b"LOAD") => {
+                let sha = sha1_hex(&args[1]);
+                state.scripts.insert(sha.clone(), args[1].clone());
+                bulk(sha)
+            }
+            ("EVAL", n) if n >= 2 => self.run_script(state, &args[0].clone(), &args[1..]),
+            ("EVALSHA", n) if n >= 2 => {
+                let sha = String::from_utf8_lossy(&args[0]).to_lowercase();
+                match state.scripts.get(&sha).cloned() {
+                    Some(source) => self.run_script(state, &source, &args[1..]),
+                    None => {
+                        Reply::Error("NOSCRIPT No matching script. Please use EVAL.".to_string())
+                    }
+                }
+            }
+            _ => Reply::error(&format!(
+                "unknown command '{}' or wrong number of arguments",
+                name
+            )),
+        }
+    }
+
+    /// Runs a script with `args` of `EVAL`, starting with the number of keys. Runs atomically, as the state is locked.
+    fn run_script(&self, state: &mut State, source: &[u8], args: &[Vec<u8>]) -> Reply {
+        let key_count = match parse_int(&args[0]) {
+            Some(n) if n >= 0 && (n as usize) < args.len() => n as usize,
+            _ => return Reply::error("Number of keys can't be greater than number of args"),
+        };
+        let keys = args[1..=key_count].to_vec();
+        let argv = args[key_count + 1..].to_vec();
+
+        let source = String::from_utf8_lossy(source);
+        let mut call = |args: Vec<Vec<u8>>| to_lua(self.execute(state, &args));
+        match lua::run(&source, keys, argv, &mut call) {
+            Ok(value) => from_lua(value),
+            Err(message) => Reply::error(&format!("Error running script: {}", message)),
+        }
+    }
+}
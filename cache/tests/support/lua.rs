@@ -0,0 +1,564 @@
+//! Interpreter of the subset of Lua used by the scripts of `stq_cache`, so that `FakeRedis` runs them as they are.
+//!
+//! Supported: `local` variables, assignments, `if` with `elseif` and `else`, numeric `for`, `return`,
+//! the operators `==`, `~=`, `+`, `-`, `not` and `#`, indexing, and the functions `redis.call`, `unpack`
+//! and `math.min`. Values are `nil`, booleans, integers, strings and arrays.
+//! Blocks do not have scopes of their own: all variables live as long as the script.
+use std::collections::HashMap;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    Nil,
+    Bool(bool),
+    Number(i64),
+    Str(Vec<u8>),
+    Table(Vec<Value>),
+}
+
+impl Value {
+    fn is_truthy(&self) -> bool {
+        *self != Value::Nil && *self != Value::Bool(false)
+    }
+
+    fn to_number(&self) -> Result<i64, String> {
+        match self {
+            Value::Number(n) => Ok(*n),
+            Value::Str(s) => String::from_utf8_lossy(s)
+                .trim()
+                .parse()
+                .map_err(|_| format!("attempt to perform arithmetic on {:?}", self)),
+            _ => Err(format!("attempt to perform arithmetic on {:?}", self)),
+        }
+    }
+
+    fn to_arg(&self) -> Result<Vec<u8>, String> {
+        match self {
+            Value::Str(s) => Ok(s.clone()),
+            Value::Number(n) => Ok(n.to_string().into_bytes()),
+            _ => Err("Lua redis() command arguments must be strings or integers".to_string()),
+        }
+    }
+}
+
+/// Runs Redis commands for `redis.call`, failing with the error reply
+pub type Call<'a> = &'a mut FnMut(Vec<Vec<u8>>) -> Result<Value, String>;
+
+/// Runs `source` with `KEYS` and `ARGV`, returning the value of the script
+pub fn run(
+    source: &str,
+    keys: Vec<Vec<u8>>,
+    argv: Vec<Vec<u8>>,
+    call: Call,
+) -> Result<Value, String> {
+    let block = Parser {
+        tokens: tokenize(source)?,
+        pos: 0,
+    }
+    .parse_script()?;
+
+    let mut vars = HashMap::new();
+    vars.insert(
+        "KEYS".to_string(),
+        Value::Table(keys.into_iter().map(Value::Str).collect()),
+    );
+    vars.insert(
+        "ARGV".to_string(),
+        Value::Table(argv.into_iter().map(Value::Str).collect()),
+    );
+
+    let mut interpreter = Interpreter { vars, call };
+    match interpreter.exec_block(&block)? {
+        Flow::Return(value) => Ok(value),
+        Flow::Next => Ok(Value::Nil),
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Name(String),
+    Number(i64),
+    Str(Vec<u8>),
+    Symbol(&'static str),
+}
+
+const SYMBOLS: &[&str] = &["==", "~=", "=", "(", ")", "[", "]", ",", ".", "#", "+", "-"];
+
+fn tokenize(source: &str) -> Result<Vec<Token>, String> {
+    let bytes = source.as_bytes();
+    let mut tokens = vec![];
+    let mut i = 0;
+    while i < bytes.len() {
+        let c = bytes[i];
+        if c.is_ascii_whitespace() {
+            i += 1;
+        } else if bytes[i..].starts_with(b"--") {
+            while i < bytes.len() && bytes[i] != b'\n' {
+                i += 1;
+            }
+        } else if c.is_ascii_alphabetic() || c == b'_' {
+            let start = i;
+            while i < bytes.len() && (bytes[i].is_ascii_alphanumeric() || bytes[i] == b'_') {
+                i += 1;
+            }
+            tokens.push(Token::Name(source[start..i].to_string()));
+        } else if c.is_ascii_digit() {
+            let start = i;
+            while i < bytes.len() && bytes[i].is_ascii_digit() {
+                i += 1;
+            }
+            let n = source[start..i]
+                .parse()
+                .map_err(|_| format!("Invalid number {}", &source[start..i]))?;
+            tokens.push(Token::Number(n));
+        } else if c == b'\'' || c == b'"' {
+            let start = i + 1;
+            i = start;
+            while i < bytes.len() && bytes[i] != c {
+                i += 1;
+            }
+            if i == bytes.len() {
+                return Err("Unfinished string".to_string());
+            }
+            tokens.push(Token::Str(bytes[start..i].to_vec()));
+            i += 1;
+        } else {
+            let symbol = SYMBOLS
+                .iter()
+                .find(|symbol| bytes[i..].starts_with(symbol.as_bytes()))
+                .ok_or_else(|| format!("Unexpected character {:?}", c as char))?;
+            tokens.push(Token::Symbol(symbol));
+            i += symbol.len();
+        }
+    }
+    Ok(tokens)
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Op {
+    Eq,
+    Ne,
+    Add,
+    Sub,
+}
+
+#[derive(Debug)]
+enum Expr {
+    Literal(Value),
+    Var(String),
+    Index(Box<Expr>, Box<Expr>),
+    Call(String, Vec<Expr>),
+    Not(Box<Expr>),
+    Len(Box<Expr>),
+    Binary(Op, Box<Expr>, Box<Expr>),
+}
+
+#[derive(Debug)]
+enum Stmt {
+    Local(String, Option<Expr>),
+    Assign(String, Expr),
+    Call(Expr),
+    If(Expr, Vec<Stmt>, Vec<Stmt>),
+    For(String, Expr, Expr, Option<Expr>, Vec<Stmt>),
+    Return(Option<Expr>),
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Result<Token, String> {
+        let token = self
+            .tokens
+            .get(self.pos)
+            .cloned()
+            .ok_or_else(|| "Unexpected end of script".to_string())?;
+        self.pos += 1;
+        Ok(token)
+    }
+
+    fn is_keyword(&self, keyword: &str) -> bool {
+        match self.peek() {
+            Some(Token::Name(name)) => name == keyword,
+            _ => false,
+        }
+    }
+
+    fn is_symbol(&self, symbol: &str) -> bool {
+        match self.peek() {
+            Some(Token::Symbol(s)) => *s == symbol,
+            _ => false,
+        }
+    }
+
+    fn expect_keyword(&mut self, keyword: &str) -> Result<(), String> {
+        match self.next()? {
+            Token::Name(ref name) if name == keyword => Ok(()),
+            token => Err(format!("Expected {}, found {:?}", keyword, token)),
+        }
+    }
+
+    fn expect_symbol(&mut self, symbol: &str) -> Result<(), String> {
+        match self.next()? {
+            Token::Symbol(s) if s == symbol => Ok(()),
+            token => Err(format!("Expected {}, found {:?}", symbol, token)),
+        }
+    }
+
+    fn expect_name(&mut self) -> Result<String, String> {
+        match self.next()? {
+            Token::Name(name) => Ok(name),
+            token => Err(format!("Expected a name, found {:?}", token)),
+        }
+    }
+
+    fn parse_script(&mut self) -> Result<Vec<Stmt>, String> {
+        let block = self.parse_block()?;
+        match self.peek() {
+            None => Ok(block),
+            Some(token) => Err(format!("Unexpected {:?}", token)),
+        }
+    }
+
+    /// Parses statements up to `end`, `else`, `elseif` or the end of the script
+    fn parse_block(&mut self) -> Result<Vec<Stmt>, String> {
+        let mut block = vec![];
+        while self.peek().is_some()
+            && !self.is_keyword("end")
+            && !self.is_keyword("else")
+            && !self.is_keyword("elseif")
+        {
+            block.push(self.parse_stmt()?);
+        }
+        Ok(block)
+    }
+
+    fn parse_stmt(&mut self) -> Result<Stmt, String> {
+        if self.is_keyword("local") {
+            self.pos += 1;
+            let name = self.expect_name()?;
+            if !self.is_symbol("=") {
+                return Ok(Stmt::Local(name, None));
+            }
+            self.pos += 1;
+            return Ok(Stmt::Local(name, Some(self.parse_expr()?)));
+        }
+        if self.is_keyword("if") {
+            self.pos += 1;
+            return self.parse_if();
+        }
+        if self.is_keyword("for") {
+            self.pos += 1;
+            let name = self.expect_name()?;
+            self.expect_symbol("=")?;
+            let start = self.parse_expr()?;
+            self.expect_symbol(",")?;
+            let limit = self.parse_expr()?;
+            let step = if self.is_symbol(",") {
+                self.pos += 1;
+                Some(self.parse_expr()?)
+            } else {
+                None
+            };
+            self.expect_keyword("do")?;
+            let body = self.parse_block()?;
+            self.expect_keyword("end")?;
+            return Ok(Stmt::For(name, start, limit, step, body));
+        }
+        if self.is_keyword("return") {
+            self.pos += 1;
+            if self.peek().is_none() || self.is_keyword("end") || self.is_keyword("else") {
+                return Ok(Stmt::Return(None));
+            }
+            return Ok(Stmt::Return(Some(self.parse_expr()?)));
+        }
+
+        match self.parse_expr()? {
+            Expr::Var(name) => {
+                self.expect_symbol("=")?;
+                Ok(Stmt::Assign(name, self.parse_expr()?))
+            }
+            call @ Expr::Call(..) => Ok(Stmt::Call(call)),
+            expr => Err(format!("Unexpected expression {:?}", expr)),
+        }
+    }
+
+    /// Parses the rest of `if` after the keyword, including the closing `end`
+    fn parse_if(&mut self) -> Result<Stmt, String> {
+        let cond = self.parse_expr()?;
+        self.expect_keyword("then")?;
+        let body = self.parse_block()?;
+        let otherwise = match self.next()? {
+            Token::Name(ref name) if name == "elseif" => vec![self.parse_if()?],
+            Token::Name(ref name) if name == "else" => {
+                let block = self.parse_block()?;
+                self.expect_keyword("end")?;
+                block
+            }
+            Token::Name(ref name) if name == "end" => vec![],
+            token => return Err(format!("Expected end, found {:?}", token)),
+        };
+        Ok(Stmt::If(cond, body, otherwise))
+    }
+
+    fn parse_expr(&mut self) -> Result<Expr, String> {
+        let mut expr = self.parse_additive()?;
+        loop {
+            let op = if self.is_symbol("==") {
+                Op::Eq
+            } else if self.is_symbol("~=") {
+                Op::Ne
+            } else {
+                return Ok(expr);
+            };
+            self.pos += 1;
+            expr = Expr::Binary(op, Box::new(expr), Box::new(self.parse_additive()?));
+        }
+    }
+
+    fn parse_additive(&mut self) -> Result<Expr, String> {
+        let mut expr = self.parse_unary()?;
+        loop {
+            let op = if self.is_symbol("+") {
+                Op::Add
+            } else if self.is_symbol("-") {
+                Op::Sub
+            } else {
+                return Ok(expr);
+            };
+            self.pos += 1;
+            expr = Expr::Binary(op, Box::new(expr), Box::new(self.parse_unary()?));
+        }
+    }
+
+    fn parse_unary(&mut self) -> Result<Expr, String> {
+        if self.is_keyword("not") {
+            self.pos += 1;
+            return Ok(Expr::Not(Box::new(self.parse_unary()?)));
+        }
+        if self.is_symbol("#") {
+            self.pos += 1;
+            return Ok(Expr::Len(Box::new(self.parse_unary()?)));
+        }
+        self.parse_primary()
+    }
+
+    fn parse_primary(&mut self) -> Result<Expr, String> {
+        let mut expr = match self.next()? {
+            Token::Number(n) => return Ok(Expr::Literal(Value::Number(n))),
+            Token::Str(s) => return Ok(Expr::Literal(Value::Str(s))),
+            Token::Symbol("(") => {
+                let expr = self.parse_expr()?;
+                self.expect_symbol(")")?;
+                return Ok(expr);
+            }
+            Token::Name(ref name) if name == "nil" => return Ok(Expr::Literal(Value::Nil)),
+            Token::Name(ref name) if name == "true" => return Ok(Expr::Literal(Value::Bool(true))),
+            Token::Name(ref name) if name == "false" => {
+                return Ok(Expr::Literal(Value::Bool(false)))
+            }
+            Token::Name(mut name) => {
+                while self.is_symbol(".") {
+                    self.pos += 1;
+                    name = format!("{}.{}", name, self.expect_name()?);
+                }
+                if self.is_symbol("(") {
+                    self.pos += 1;
+                    let mut args = vec![];
+                    while !self.is_symbol(")") {
+                        if !args.is_empty() {
+                            self.expect_symbol(",")?;
+                        }
+                        args.push(self.parse_expr()?);
+                    }
+                    self.pos += 1;
+                    return Ok(Expr::Call(name, args));
+                }
+                Expr::Var(name)
+            }
+            token => return Err(format!("Unexpected {:?}", token)),
+        };
+
+        while self.is_symbol("[") {
+            self.pos += 1;
+            let index = self.parse_expr()?;
+            self.expect_symbol("]")?;
+            expr = Expr::Index(Box::new(expr), Box::new(index));
+        }
+        Ok(expr)
+    }
+}
+
+enum Flow {
+    Next,
+    Return(Value),
+}
+
+struct Interpreter<'a> {
+    vars: HashMap<String, Value>,
+    call: Call<'a>,
+}
+
+impl<'a> Interpreter<'a> {
+    fn exec_block(&mut self, block: &[Stmt]) -> Result<Flow, String> {
+        for stmt in block {
+            if let Flow::Return(value) = self.exec(stmt)? {
+                return Ok(Flow::Return(value));
+            }
+        }
+        Ok(Flow::Next)
+    }
+
+    fn exec(&mut self, stmt: &Stmt) -> Result<Flow, String> {
+        match stmt {
+            Stmt::Local(name, None) => {
+                self.vars.insert(name.clone(), Value::Nil);
+            }
+            Stmt::Local(name, Some(expr)) | Stmt::Assign(name, expr) => {
+                let value = self.eval(expr)?;
+                self.vars.insert(name.clone(), value);
+            }
+            Stmt::Call(expr) => {
+                self.eval(expr)?;
+            }
+            Stmt::If(cond, body, otherwise) => {
+                let block = if self.eval(cond)?.is_truthy() {
+                    body
+                } else {
+                    otherwise
+                };
+                return self.exec_block(block);
+            }
+            Stmt::For(name, start, limit, step, body) => {
+                let mut i = self.eval(start)?.to_number()?;
+                let limit = self.eval(limit)?.to_number()?;
+                let step = match step {
+                    Some(step) => self.eval(step)?.to_number()?,
+                    None => 1,
+                };
+                if step == 0 {
+                    return Err("'for' step is zero".to_string());
+                }
+                while (step > 0 && i <= limit) || (step < 0 && i >= limit) {
+                    self.vars.insert(name.clone(), Value::Number(i));
+                    if let Flow::Return(value) = self.exec_block(body)? {
+                        return Ok(Flow::Return(value));
+                    }
+                    i += step;
+                }
+            }
+            Stmt::Return(expr) => {
+                let value = match expr {
+                    Some(expr) => self.eval(expr)?,
+                    None => Value::Nil,
+                };
+                return Ok(Flow::Return(value));
+            }
+        }
+        Ok(Flow::Next)
+    }
+
+    fn eval(&mut self, expr: &Expr) -> Result<Value, String> {
+        Ok(self
+            .eval_multi(expr)?
+            .into_iter()
+            .next()
+            .unwrap_or(Value::Nil))
+    }
+
+    /// Evaluates to all values of `expr`, which are several only for calls of `unpack`
+    fn eval_multi(&mut self, expr: &Expr) -> Result<Vec<Value>, String> {
+        let value = match expr {
+            Expr::Literal(value) => value.clone(),
+            Expr::Var(name) => self.vars.get(name).cloned().unwrap_or(Value::Nil),
+            Expr::Index(table, index) => match (self.eval(table)?, self.eval(index)?) {
+                (Value::Table(items), Value::Number(i)) if i >= 1 => {
+                    items.get(i as usize - 1).cloned().unwrap_or(Value::Nil)
+                }
+                (Value::Table(_), _) => Value::Nil,
+                (value, _) => return Err(format!("attempt to index {:?}", value)),
+            },
+            Expr::Call(name, args) => {
+                let mut values = vec![];
+                for (i, arg) in args.iter().enumerate() {
+                    if i + 1 == args.len() {
+                        values.extend(self.eval_multi(arg)?);
+                    } else {
+                        values.push(self.eval(arg)?);
+                    }
+                }
+                return self.call_function(name, values);
+            }
+            Expr::Not(expr) => Value::Bool(!self.eval(expr)?.is_truthy()),
+            Expr::Len(expr) => match self.eval(expr)? {
+                Value::Table(items) => Value::Number(items.len() as i64),
+                Value::Str(s) => Value::Number(s.len() as i64),
+                value => return Err(format!("attempt to get length of {:?}", value)),
+            },
+            Expr::Binary(op, left, right) => {
+                let left = self.eval(left)?;
+                let right = self.eval(right)?;
+                match op {
+                    Op::Eq => Value::Bool(left == right),
+                    Op::Ne => Value::Bool(left != right),
+                    Op::Add => Value::Number(left.to_number()? + right.to_number()?),
+                    Op::Sub => Value::Number(left.to_number()? - right.to_number()?),
+                }
+            }
+        };
+        Ok(vec![value])
+    }
+
+    fn call_function(&mut self, name: &str, args: Vec<Value>) -> Result<Vec<Value>, String> {
+        match name {
+            "redis.call" => {
+                let args = args
+                    .iter()
+                    .map(Value::to_arg)
+                    .collect::<Result<Vec<_>, _>>()?;
+                if args.is_empty() {
+                    return Err("Please specify at least one argument for redis.call()".to_string());
+                }
+                Ok(vec![(self.call)(args)?])
+            }
+            "unpack" => {
+                let items = match args.first() {
+                    Some(Value::Table(items)) => items,
+                    _ => return Err("bad argument #1 to 'unpack' (table expected)".to_string()),
+                };
+                let from = match args.get(1) {
+                    Some(value) => value.to_number()?,
+                    None => 1,
+                };
+                let to = match args.get(2) {
+                    Some(value) => value.to_number()?,
+                    None => items.len() as i64,
+                };
+                Ok((from..=to)
+                    .map(|i| {
+                        if i >= 1 {
+                            items.get(i as usize - 1).cloned().unwrap_or(Value::Nil)
+                        } else {
+                            Value::Nil
+                        }
+                    })
+                    .collect())
+            }
+            "math.min" => {
+                let mut min = None;
+                for arg in &args {
+                    let n = arg.to_number()?;
+                    min = Some(min.map_or(n, |min: i64| min.min(n)));
+                }
+                min.map(|min| vec![Value::Number(min)])
+                    .ok_or_else(|| "bad argument #1 to 'min' (number expected)".to_string())
+            }
+            _ => Err(format!("attempt to call an unknown function {}", name)),
+        }
+    }
+}
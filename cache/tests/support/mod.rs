@@ -0,0 +1,47 @@
+pub mod fake_redis;
+mod lua;
+
+use r2d2_redis::{r2d2::Pool, RedisConnectionManager};
+use std::thread;
+use std::time::Duration;
+
+use self::fake_redis::FakeRedis;
+
+/// Redis to run tests against: the one at `REDIS_URL` if set, otherwise a fresh `FakeRedis`
+pub enum TestRedis {
+    Real(String),
+    Fake(FakeRedis),
+}
+
+impl TestRedis {
+    pub fn from_env() -> Self {
+        match std::env::var("REDIS_URL") {
+            Ok(url) => TestRedis::Real(url),
+            Err(_) => TestRedis::Fake(FakeRedis::start()),
+        }
+    }
+
+    pub fn url(&self) -> String {
+        match self {
+            TestRedis::Real(url) => url.clone(),
+            TestRedis::Fake(server) => server.url(),
+        }
+    }
+
+    pub fn pool(&self) -> Pool<RedisConnectionManager> {
+        let manager = RedisConnectionManager::new(self.url().as_ref())
+            .expect("Failed to create connection manager");
+
+        Pool::builder()
+            .build(manager)
+            .expect("Failed to create connection pool")
+    }
+
+    /// Lets `duration` pass: moves the clock of the fake Redis, or sleeps with a real one
+    pub fn advance(&self, duration: Duration) {
+        match self {
+            TestRedis::Real(_) => thread::sleep(duration),
+            TestRedis::Fake(server) => server.advance(duration),
+        }
+    }
+}
@@ -0,0 +1,212 @@
+//! Audit sink that stores ACL decisions in a table. The table is expected to look like this:
+//!
+//! ```sql
+//! CREATE TABLE acl_audit (
+//!     id BIGSERIAL PRIMARY KEY,
+//!     created_at TIMESTAMP NOT NULL DEFAULT now(),
+//!     context TEXT NOT NULL,
+//!     allowed BOOLEAN,
+//!     explanation TEXT,
+//!     error TEXT,
+//!     latency_ms BIGINT NOT NULL
+//! );
+//! ```
+use futures::future::{self, Loop};
+use futures::prelude::*;
+use futures_state_stream::*;
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::rc::Rc;
+use stq_acl::audit::{AuditRecord, AuditSink};
+use tokio_postgres::types::ToSql;
+
+use super::repo::{RepoConnection, RepoConnectionFuture, RepoError};
+
+const DEFAULT_CAPACITY: usize = 10_000;
+/// Records written per `INSERT`, keeping the number of arguments well below the limit of Postgres
+const BATCH_SIZE: usize = 1000;
+const COLUMNS: &str = "created_at, context, allowed, explanation, error, latency_ms";
+
+/// Builds an `INSERT` of all `records`
+fn insert_query(table: &str, records: &[AuditRecord]) -> (String, Vec<Box<ToSql + 'static>>) {
+    let mut rows = vec![];
+    let mut args: Vec<Box<ToSql + 'static>> = vec![];
+    for record in records {
+        let latency_ms = record.latency.as_secs() * 1000 + u64::from(record.latency.subsec_millis());
+        let row = vec![
+            Box::new(record.timestamp) as Box<ToSql + 'static>,
+            Box::new(record.context.clone()),
+            Box::new(record.allowed),
+            Box::new(record.explanation.as_ref().map(|explanation| explanation.to_string())),
+            Box::new(record.error.clone()),
+            Box::new(latency_ms as i64),
+        ];
+        let placeholders = (args.len() + 1..=args.len() + row.len())
+            .map(|index| format!("${}", index))
+            .collect::<Vec<_>>();
+        rows.push(format!("({})", placeholders.join(", ")));
+        args.extend(row);
+    }
+
+    (format!("INSERT INTO {} ({}) VALUES {};", table, COLUMNS, rows.join(", ")), args)
+}
+
+#[derive(Debug, Default)]
+struct Buffer {
+    records: VecDeque<AuditRecord>,
+    dropped: usize,
+}
+
+/// Buffers audit records until they are written with `flush`, since recording happens outside of any DB connection.
+/// Once the buffer is full, the oldest records are dropped.
+#[derive(Clone, Debug)]
+pub struct DbAuditSink {
+    table: &'static str,
+    capacity: usize,
+    buffer: Rc<RefCell<Buffer>>,
+}
+
+impl DbAuditSink {
+    pub fn new(table: &'static str) -> Self {
+        Self {
+            table,
+            capacity: DEFAULT_CAPACITY,
+            buffer: Default::default(),
+        }
+    }
+
+    /// Maximum number of buffered records, 10000 by default
+    pub fn with_capacity(mut self, capacity: usize) -> Self {
+        self.capacity = capacity;
+        self
+    }
+
+    /// Number of buffered records
+    pub fn len(&self) -> usize {
+        self.buffer.borrow().records.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Puts records that could not be written in front of the ones recorded since
+    fn requeue(&self, records: Vec<AuditRecord>) {
+        let mut buffer = self.buffer.borrow_mut();
+        for record in records.into_iter().rev() {
+            buffer.records.push_front(record);
+        }
+        self.trim(&mut buffer);
+    }
+
+    fn trim(&self, buffer: &mut Buffer) {
+        while buffer.records.len() > self.capacity {
+            buffer.records.pop_front();
+            buffer.dropped += 1;
+        }
+    }
+
+    /// Writes all buffered records with one `INSERT` per 1000 records, e.g. at the end of a transaction.
+    /// Records that have not been written because of an error stay in the buffer for the next flush.
+    pub fn flush(&self, conn: RepoConnection) -> RepoConnectionFuture<()> {
+        let table = self.table;
+        let sink = self.clone();
+        let records = {
+            let mut buffer = self.buffer.borrow_mut();
+            if buffer.dropped > 0 {
+                warn!("Dropped {} ACL audit records because the buffer was full", buffer.dropped);
+                buffer.dropped = 0;
+            }
+            buffer.records.drain(..).collect::<Vec<_>>()
+        };
+
+        Box::new(future::loop_fn((records, conn), move |(mut records, conn)| {
+            if records.is_empty() {
+                return Box::new(future::ok(Loop::Break(((), conn))))
+                    as Box<Future<Item = Loop<_, _>, Error = (RepoError, RepoConnection)>>;
+            }
+
+            let batch_len = records.len().min(BATCH_SIZE);
+            let (query, args) = insert_query(table, &records[..batch_len]);
+
+            let sink = sink.clone();
+            Box::new(
+                conn.prepare2(&query)
+                    .and_then(move |(statement, conn)| conn.query2(&statement, args).collect())
+                    .then(move |res| match res {
+                        Ok((_, conn)) => {
+                            records.drain(..batch_len);
+                            Ok(Loop::Continue((records, conn)))
+                        }
+                        Err((e, conn)) => {
+                            sink.requeue(records);
+                            Err((e.context("Failed to write ACL audit records").into(), conn))
+                        }
+                    }),
+            )
+        }))
+    }
+}
+
+impl AuditSink for DbAuditSink {
+    fn record(&self, record: AuditRecord) {
+        let mut buffer = self.buffer.borrow_mut();
+        buffer.records.push_back(record);
+        self.trim(&mut buffer);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, SystemTime};
+
+    fn record(context: &str) -> AuditRecord {
+        AuditRecord {
+            timestamp: SystemTime::now(),
+            context: context.to_string(),
+            allowed: Some(true),
+            explanation: None,
+            error: None,
+            latency: Duration::from_millis(1),
+        }
+    }
+
+    fn contexts(sink: &DbAuditSink) -> Vec<String> {
+        sink.buffer.borrow().records.iter().map(|record| record.context.clone()).collect()
+    }
+
+    #[test]
+    fn test_capacity() {
+        let sink = DbAuditSink::new("acl_audit").with_capacity(2);
+        sink.record(record("1"));
+        sink.record(record("2"));
+        sink.record(record("3"));
+
+        assert_eq!(contexts(&sink), vec!["2", "3"]);
+        assert_eq!(sink.buffer.borrow().dropped, 1);
+    }
+
+    #[test]
+    fn test_insert_query() {
+        let (query, args) = insert_query("acl_audit", &[record("1"), record("2")]);
+        assert_eq!(
+            query,
+            "INSERT INTO acl_audit (created_at, context, allowed, explanation, error, latency_ms) \
+             VALUES ($1, $2, $3, $4, $5, $6), ($7, $8, $9, $10, $11, $12);"
+        );
+        assert_eq!(args.len(), 12);
+    }
+
+    #[test]
+    fn test_requeue() {
+        let sink = DbAuditSink::new("acl_audit").with_capacity(3);
+        sink.record(record("3"));
+        sink.requeue(vec![record("1"), record("2")]);
+        assert_eq!(contexts(&sink), vec!["1", "2", "3"]);
+
+        // Unwritten records are older than the ones recorded since, so they are dropped first
+        sink.requeue(vec![record("0")]);
+        assert_eq!(contexts(&sink), vec!["1", "2", "3"]);
+    }
+}
@@ -7,12 +7,16 @@ extern crate either;
 extern crate failure;
 extern crate futures;
 extern crate futures_state_stream;
+#[macro_use]
+extern crate log;
 extern crate stq_acl;
 extern crate tokio_postgres;
 
+pub mod acl_audit;
 pub mod connection;
 pub mod diesel_repo;
 pub mod pool;
+pub mod query_log;
 pub mod repo;
 pub mod sequence;
 pub mod statement;
@@ -0,0 +1,175 @@
+//! Opt-in instrumentation for queries issued by repos and sequences.
+use log::Level;
+use std::time::{Duration, Instant};
+use tokio_postgres::types::ToSql;
+
+/// How query arguments are rendered in the log.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ArgsLogging {
+    /// Only the number of arguments is logged.
+    Hidden,
+    /// Every argument is replaced with a placeholder.
+    Redacted,
+    /// Arguments are logged as is. Do not use where queries may carry personal data.
+    Full,
+}
+
+/// Logs SQL, arguments, row count and elapsed time of every successful query.
+/// Queries running longer than the slow query threshold are logged with `Warn` level regardless of the configured one.
+#[derive(Clone, Debug)]
+pub struct QueryLogger {
+    level: Level,
+    args_logging: ArgsLogging,
+    slow_query_threshold: Option<Duration>,
+    correlation_token: Option<String>,
+}
+
+impl Default for QueryLogger {
+    fn default() -> Self {
+        Self {
+            level: Level::Debug,
+            args_logging: ArgsLogging::Redacted,
+            slow_query_threshold: None,
+            correlation_token: None,
+        }
+    }
+}
+
+impl QueryLogger {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Log level for queries that are not slow
+    pub fn with_level(mut self, level: Level) -> Self {
+        self.level = level;
+        self
+    }
+
+    pub fn with_args_logging(mut self, args_logging: ArgsLogging) -> Self {
+        self.args_logging = args_logging;
+        self
+    }
+
+    pub fn with_slow_query_threshold(mut self, threshold: Duration) -> Self {
+        self.slow_query_threshold = Some(threshold);
+        self
+    }
+
+    /// Attaches correlation token of the current request to every log record
+    pub fn with_correlation_token(mut self, correlation_token: String) -> Self {
+        self.correlation_token = Some(correlation_token);
+        self
+    }
+
+    /// Renders query arguments according to the configured `ArgsLogging`
+    pub fn format_args(&self, args: &[Box<ToSql>]) -> String {
+        match self.args_logging {
+            ArgsLogging::Hidden => format!("<{} hidden>", args.len()),
+            ArgsLogging::Redacted => args.iter().enumerate().fold(String::new(), |mut acc, (i, _)| {
+                if i > 0 {
+                    acc += ", ";
+                }
+                acc += &format!("${} = <redacted>", i + 1);
+                acc
+            }),
+            ArgsLogging::Full => args.iter().enumerate().fold(String::new(), |mut acc, (i, arg)| {
+                if i > 0 {
+                    acc += ", ";
+                }
+                acc += &format!("${} = {:?}", i + 1, arg);
+                acc
+            }),
+        }
+    }
+
+    /// Starts timing a query. Arguments are rendered right away since they are consumed by the query itself.
+    pub fn start(&self, query: &str, args: &[Box<ToSql>]) -> QueryTimer {
+        QueryTimer {
+            logger: self.clone(),
+            query: query.to_string(),
+            args: self.format_args(args),
+            started: Instant::now(),
+        }
+    }
+}
+
+/// A query in flight, see `QueryLogger::start`.
+pub struct QueryTimer {
+    logger: QueryLogger,
+    query: String,
+    args: String,
+    started: Instant,
+}
+
+impl QueryTimer {
+    /// Logs the query with the number of rows it returned
+    pub fn finish(self, rows: usize) {
+        let elapsed = self.started.elapsed();
+        let (level, message) = self.record(rows, elapsed);
+        log!(level, "{}", message);
+    }
+
+    /// Level and message of the log record for a query that took `elapsed`
+    fn record(&self, rows: usize, elapsed: Duration) -> (Level, String) {
+        let elapsed_ms = elapsed.as_secs() * 1000 + u64::from(elapsed.subsec_millis());
+        let correlation_token = self.logger.correlation_token.clone().unwrap_or_default();
+
+        let is_slow = self.logger.slow_query_threshold.map(|threshold| elapsed >= threshold).unwrap_or(false);
+        let (level, kind) = if is_slow {
+            (Level::Warn, "Slow query")
+        } else {
+            (self.logger.level, "Query")
+        };
+
+        (
+            level,
+            format!(
+                "{}: {}. Args: {}. Rows: {}, elapsed time = {} ms, correlation token: {}",
+                kind, self.query, self.args, rows, elapsed_ms, correlation_token
+            ),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args() -> Vec<Box<ToSql>> {
+        vec![Box::new(3) as Box<ToSql>, Box::new("secret".to_string())]
+    }
+
+    #[test]
+    fn test_args_logging() {
+        let logger = QueryLogger::new();
+        assert_eq!(logger.format_args(&args()), "$1 = <redacted>, $2 = <redacted>");
+
+        let logger = logger.with_args_logging(ArgsLogging::Hidden);
+        assert_eq!(logger.format_args(&args()), "<2 hidden>");
+
+        let logger = logger.with_args_logging(ArgsLogging::Full);
+        assert_eq!(logger.format_args(&args()), "$1 = 3, $2 = \"secret\"");
+    }
+
+    #[test]
+    fn test_record() {
+        let logger = QueryLogger::new()
+            .with_level(Level::Info)
+            .with_correlation_token("token".to_string());
+        let timer = logger.start("SELECT 1", &args());
+
+        let (level, message) = timer.record(1, Duration::from_millis(1500));
+        assert_eq!(level, Level::Info);
+        assert_eq!(
+            message,
+            "Query: SELECT 1. Args: $1 = <redacted>, $2 = <redacted>. Rows: 1, elapsed time = 1500 ms, correlation token: token"
+        );
+
+        let timer = logger.with_slow_query_threshold(Duration::from_millis(100)).start("SELECT 1", &[]);
+        assert_eq!(timer.record(0, Duration::from_millis(99)).0, Level::Info);
+        let (level, message) = timer.record(0, Duration::from_millis(100));
+        assert_eq!(level, Level::Warn);
+        assert!(message.starts_with("Slow query: SELECT 1."));
+    }
+}
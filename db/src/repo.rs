@@ -1,12 +1,15 @@
 use super::connection::*;
-use super::statement::{Filter, FilteredOperation, Inserter, SelectOperation, Updater};
+use super::query_log::QueryLogger;
+use super::statement::{Filter, FilteredOperation, FilteredOperationBuilder, Inserter, SelectOperation, UpdateBuilder, Updater};
 
 use failure;
 use futures::*;
 use futures_state_stream::*;
+use std::mem;
 use std::rc::Rc;
 use stq_acl as acl;
 use tokio_postgres::rows::Row;
+use tokio_postgres::stmt::Statement;
 use tokio_postgres::types::ToSql;
 
 #[derive(Copy, Clone, Eq, PartialEq, Debug, Fail)]
@@ -137,11 +140,11 @@ pub enum Action {
     Update,
 }
 
-fn bulk_ensure_access<T>(
+fn bulk_ensure_access<T, C>(
     acl_engine: &Rc<acl::AclEngine<(T, Action), RepoError>>,
     context: (Vec<T>, Action),
-    conn: BoxedConnection<RepoError>,
-) -> impl Future<Item = (Vec<T>, BoxedConnection<RepoError>), Error = (RepoError, BoxedConnection<RepoError>)>
+    conn: C,
+) -> impl Future<Item = (Vec<T>, C), Error = (RepoError, C)>
 where
     T: 'static,
 {
@@ -156,6 +159,67 @@ where
     })
 }
 
+/// Drops the items that `acl_engine` does not allow access to
+fn bulk_filter_access<T, C>(
+    acl_engine: &Rc<acl::AclEngine<(T, Action), RepoError>>,
+    context: (Vec<T>, Action),
+    conn: C,
+) -> impl Future<Item = (Vec<T>, C), Error = (RepoError, C)>
+where
+    T: 'static,
+{
+    let (items, action) = context;
+    future::join_all(items.into_iter().map({
+        let acl_engine = acl_engine.clone();
+        move |entity| {
+            acl_engine
+                .allows((entity, action))
+                .map(|(allowed, (entity, _))| if allowed { Some(entity) } else { None })
+        }
+    }))
+    .then(move |res| match res {
+        Ok(items) => Ok((items.into_iter().flatten().collect(), conn)),
+        Err((e, _ctx)) => Err((e, conn)),
+    })
+}
+
+/// Context of row filter ACL engines: the filters of the query about to be run, which the engine may narrow down.
+pub type RowFilterContext = (FilteredOperationBuilder, Action);
+
+fn restrict_rows<C>(
+    acl_engine: &Rc<acl::AclEngine<RowFilterContext, RepoError>>,
+    builder: FilteredOperationBuilder,
+    action: Action,
+    conn: C,
+) -> impl Future<Item = (FilteredOperationBuilder, C), Error = (RepoError, C)> {
+    acl_engine.ensure_access((builder, action)).then(move |res| match res {
+        Ok((builder, _action)) => Ok((builder, conn)),
+        Err((e, _ctx)) => Err((e, conn)),
+    })
+}
+
+/// Lets `acl_engine` narrow the filters selecting the rows to update
+fn restrict_updated_rows<C>(
+    acl_engine: &Rc<acl::AclEngine<RowFilterContext, RepoError>>,
+    mut builder: UpdateBuilder,
+    conn: C,
+) -> impl Future<Item = (UpdateBuilder, C), Error = (RepoError, C)> {
+    let filters = mem::replace(builder.filters_mut(), FilteredOperationBuilder::new(""));
+    restrict_rows(acl_engine, filters, Action::Update, conn).map(move |(filters, conn)| {
+        *builder.filters_mut() = filters;
+        (builder, conn)
+    })
+}
+
+/// What to do with the rows that `afterop_acl_engine` denies access to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AfteropMode {
+    /// Fail the whole operation
+    Strict,
+    /// Leave forbidden rows out of select results. Inserts, updates and deletes still fail, since the rows have already been changed.
+    Lenient,
+}
+
 pub struct DbRepoImpl<T, I, F, U>
 where
     T: From<Row> + 'static,
@@ -169,6 +233,9 @@ where
     pub delete_acl_engine: Rc<acl::AclEngine<F, RepoError>>,
     pub update_acl_engine: Rc<acl::AclEngine<U, RepoError>>,
     pub afterop_acl_engine: Rc<acl::AclEngine<(T, Action), RepoError>>,
+    pub afterop_mode: AfteropMode,
+    pub row_filter_acl_engine: Rc<acl::AclEngine<RowFilterContext, RepoError>>,
+    pub query_logger: Option<QueryLogger>,
 }
 
 impl<T, I, F, U> DbRepoImpl<T, I, F, U>
@@ -186,6 +253,9 @@ where
             delete_acl_engine: Rc::new(acl::SystemACL),
             update_acl_engine: Rc::new(acl::SystemACL),
             afterop_acl_engine: Rc::new(acl::SystemACL),
+            afterop_mode: AfteropMode::Strict,
+            row_filter_acl_engine: Rc::new(acl::SystemACL),
+            query_logger: None,
         }
     }
 
@@ -228,6 +298,27 @@ where
         self.afterop_acl_engine = Rc::new(acl_engine);
         self
     }
+
+    pub fn with_afterop_mode(mut self, afterop_mode: AfteropMode) -> Self {
+        self.afterop_mode = afterop_mode;
+        self
+    }
+
+    /// Sets the engine that can add filters to select, update and delete queries before they are run,
+    /// e.g. to limit them to the rows owned by the caller. Denial fails the operation.
+    pub fn with_row_filter_acl_engine<E>(mut self, acl_engine: E) -> Self
+    where
+        E: acl::AclEngine<RowFilterContext, RepoError> + 'static,
+    {
+        self.row_filter_acl_engine = Rc::new(acl_engine);
+        self
+    }
+
+    /// Enables logging of every query run by this repo
+    pub fn with_query_logger(mut self, query_logger: QueryLogger) -> Self {
+        self.query_logger = Some(query_logger);
+        self
+    }
 }
 
 fn query_debug(q: &str, args: &[Box<ToSql>]) -> String {
@@ -242,6 +333,29 @@ fn query_debug(q: &str, args: &[Box<ToSql>]) -> String {
     format!("Query: {}. Args: {}", q, &args_dbg)
 }
 
+fn run_query(
+    conn: RepoConnection,
+    statement: Statement,
+    query: String,
+    args: Vec<Box<ToSql>>,
+    query_logger: Option<QueryLogger>,
+) -> RepoConnectionFuture<Vec<Row>> {
+    let err_msg = query_debug(&query, &args);
+    let timer = query_logger.map(|query_logger| query_logger.start(&query, &args));
+
+    Box::new(
+        conn.query2(&statement, args)
+            .collect()
+            .map(move |(rows, conn)| {
+                if let Some(timer) = timer {
+                    timer.finish(rows.len());
+                }
+                (rows, conn)
+            })
+            .map_err(move |(e, conn)| (e.context(err_msg).into(), conn)),
+    )
+}
+
 impl<T, I, F, U> DbRepoInsert<T, I, RepoError> for DbRepoImpl<T, I, F, U>
 where
     F: Filter,
@@ -253,6 +367,7 @@ where
         let table = self.table;
 
         let afterop_acl_engine = self.afterop_acl_engine.clone();
+        let query_logger = self.query_logger.clone();
 
         Box::new(
             self.insert_acl_engine
@@ -267,12 +382,7 @@ where
                     })
                 })
                 .and_then(move |(query, args, conn)| conn.prepare2(&query).map(move |(statement, conn)| (statement, query, args, conn)))
-                .and_then(move |(statement, query, args, conn)| {
-                    let err_msg = query_debug(&query, &args);
-                    conn.query2(&statement, args)
-                        .collect()
-                        .map_err(move |(e, conn)| (e.context(err_msg).into(), conn))
-                })
+                .and_then(move |(statement, query, args, conn)| run_query(conn, statement, query, args, query_logger))
                 .map(|(rows, conn)| (rows.into_iter().map(T::from).collect::<Vec<T>>(), conn))
                 .and_then(move |(items, conn)| bulk_ensure_access(&afterop_acl_engine, (items, Action::Insert), conn))
                 .map_err(|(e, conn)| (e.context("Failure while running insert").into(), conn)),
@@ -297,6 +407,9 @@ where
         let table = self.table;
 
         let afterop_acl_engine = self.afterop_acl_engine.clone();
+        let afterop_mode = self.afterop_mode;
+        let row_filter_acl_engine = self.row_filter_acl_engine.clone();
+        let query_logger = self.query_logger.clone();
 
         Box::new(
             self.select_acl_engine
@@ -305,26 +418,26 @@ where
                     Ok(filter) => {
                         if let Some(limit) = limit {
                             if limit < 1 {
-                                return Box::new(future::err((format_err!("Limit cannot be less than 1"), conn)));
+                                return Err((format_err!("Limit cannot be less than 1"), conn));
                             }
                         }
 
-                        let (query, args) = filter
-                            .into_filtered_operation_builder(table)
-                            .build(FilteredOperation::Select { op, limit });
-                        Box::new(future::ok((query, args, conn)))
+                        Ok((filter.into_filtered_operation_builder(table), conn))
                     }
-                    Err((e, _filter)) => Box::new(future::err((e, conn))),
+                    Err((e, _filter)) => Err((e, conn)),
                 })
-                .and_then(move |(query, args, conn)| conn.prepare2(&query).map(move |(statement, conn)| (statement, query, args, conn)))
-                .and_then(move |(statement, query, args, conn)| {
-                    let err_msg = query_debug(&query, &args);
-                    conn.query2(&statement, args)
-                        .collect()
-                        .map_err(move |(e, conn)| (e.context(err_msg).into(), conn))
+                .and_then(move |(builder, conn)| restrict_rows(&row_filter_acl_engine, builder, Action::Select, conn))
+                .map(move |(builder, conn)| {
+                    let (query, args) = builder.build(FilteredOperation::Select { op, limit });
+                    (query, args, conn)
                 })
+                .and_then(move |(query, args, conn)| conn.prepare2(&query).map(move |(statement, conn)| (statement, query, args, conn)))
+                .and_then(move |(statement, query, args, conn)| run_query(conn, statement, query, args, query_logger))
                 .map(|(rows, conn)| (rows.into_iter().map(T::from).collect::<Vec<T>>(), conn))
-                .and_then(move |(items, conn)| bulk_ensure_access(&afterop_acl_engine, (items, Action::Select), conn))
+                .and_then(move |(items, conn)| match afterop_mode {
+                    AfteropMode::Strict => future::Either::A(bulk_ensure_access(&afterop_acl_engine, (items, Action::Select), conn)),
+                    AfteropMode::Lenient => future::Either::B(bulk_filter_access(&afterop_acl_engine, (items, Action::Select), conn)),
+                })
                 .map_err(|(e, conn)| (e.context("Failure while running select").into(), conn)),
         )
     }
@@ -341,26 +454,23 @@ where
         let table = self.table;
 
         let afterop_acl_engine = self.afterop_acl_engine.clone();
+        let row_filter_acl_engine = self.row_filter_acl_engine.clone();
+        let query_logger = self.query_logger.clone();
 
         Box::new(
             self.update_acl_engine
                 .ensure_access(updater)
-                .then(move |res| {
-                    future::result(match res {
-                        Ok(updater) => {
-                            let (query, args) = updater.into_update_builder(table).build();
-                            Ok((query, args, conn))
-                        }
-                        Err((e, _updater)) => Err((e, conn)),
-                    })
+                .then(move |res| match res {
+                    Ok(updater) => Ok((updater.into_update_builder(table), conn)),
+                    Err((e, _updater)) => Err((e, conn)),
                 })
-                .and_then(move |(query, args, conn)| conn.prepare2(&query).map(move |(statement, conn)| (statement, query, args, conn)))
-                .and_then(move |(statement, query, args, conn)| {
-                    let err_msg = query_debug(&query, &args);
-                    conn.query2(&statement, args)
-                        .collect()
-                        .map_err(move |(e, conn)| (e.context(err_msg).into(), conn))
+                .and_then(move |(builder, conn)| restrict_updated_rows(&row_filter_acl_engine, builder, conn))
+                .map(|(builder, conn)| {
+                    let (query, args) = builder.build();
+                    (query, args, conn)
                 })
+                .and_then(move |(query, args, conn)| conn.prepare2(&query).map(move |(statement, conn)| (statement, query, args, conn)))
+                .and_then(move |(statement, query, args, conn)| run_query(conn, statement, query, args, query_logger))
                 .map(|(rows, conn)| (rows.into_iter().map(T::from).collect::<Vec<T>>(), conn))
                 .and_then(move |(items, conn)| bulk_ensure_access(&afterop_acl_engine, (items, Action::Update), conn))
                 .map_err(|(e, conn)| (e.context("Failure while running update").into(), conn)),
@@ -379,26 +489,23 @@ where
         let table = self.table;
 
         let afterop_acl_engine = self.afterop_acl_engine.clone();
+        let row_filter_acl_engine = self.row_filter_acl_engine.clone();
+        let query_logger = self.query_logger.clone();
 
         Box::new(
             self.delete_acl_engine
                 .ensure_access(filter)
-                .then(move |res| {
-                    future::result(match res {
-                        Ok(filter) => {
-                            let (query, args) = filter.into_filtered_operation_builder(table).build(FilteredOperation::Delete);
-                            Ok((query, args, conn))
-                        }
-                        Err((e, _filter)) => Err((e, conn)),
-                    })
+                .then(move |res| match res {
+                    Ok(filter) => Ok((filter.into_filtered_operation_builder(table), conn)),
+                    Err((e, _filter)) => Err((e, conn)),
                 })
-                .and_then(move |(query, args, conn)| conn.prepare2(&query).map(move |(statement, conn)| (statement, query, args, conn)))
-                .and_then(move |(statement, query, args, conn)| {
-                    let err_msg = query_debug(&query, &args);
-                    conn.query2(&statement, args)
-                        .collect()
-                        .map_err(move |(e, conn)| (e.context(err_msg).into(), conn))
+                .and_then(move |(builder, conn)| restrict_rows(&row_filter_acl_engine, builder, Action::Delete, conn))
+                .map(|(builder, conn)| {
+                    let (query, args) = builder.build(FilteredOperation::Delete);
+                    (query, args, conn)
                 })
+                .and_then(move |(query, args, conn)| conn.prepare2(&query).map(move |(statement, conn)| (statement, query, args, conn)))
+                .and_then(move |(statement, query, args, conn)| run_query(conn, statement, query, args, query_logger))
                 .map(|(rows, conn)| (rows.into_iter().map(T::from).collect::<Vec<T>>(), conn))
                 .and_then(move |(items, conn)| bulk_ensure_access(&afterop_acl_engine, (items, Action::Delete), conn))
                 .map_err(|(e, conn)| (e.context("Failure while running delete").into(), conn)),
@@ -414,3 +521,51 @@ where
     U: Updater,
 {
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn owned_rows_only() -> Rc<acl::AclEngine<RowFilterContext, RepoError>> {
+        Rc::new(acl::InfallibleSyncACLFn(|ctx: &mut RowFilterContext| {
+            ctx.0.restrict::<i32, _>("user_id", 5);
+            ctx.1 != Action::Delete
+        }))
+    }
+
+    #[test]
+    fn test_restrict_rows() {
+        let builder = FilteredOperationBuilder::new("orders").with_filter::<i32, _>("id", 1);
+        let (builder, ()) = restrict_rows(&owned_rows_only(), builder, Action::Select, ()).wait().unwrap();
+        let (query, args) = builder.build(FilteredOperation::Select { op: None, limit: None });
+        assert_eq!(query, "SELECT * FROM orders WHERE id = $1 AND user_id = $2;");
+        assert_eq!(format!("{:?}", args), format!("{:?}", vec![Box::new(1) as Box<ToSql>, Box::new(5)]));
+
+        let builder = UpdateBuilder::from(FilteredOperationBuilder::new("orders").with_filter::<i32, _>("id", 1)).with_value("state", 2);
+        let (builder, ()) = restrict_updated_rows(&owned_rows_only(), builder, ()).wait().unwrap();
+        assert_eq!(
+            builder.build().0,
+            "UPDATE orders SET state = $1 WHERE id = $2 AND user_id = $3 RETURNING *;"
+        );
+
+        let builder = FilteredOperationBuilder::new("orders");
+        assert!(restrict_rows(&owned_rows_only(), builder, Action::Delete, ()).wait().is_err());
+    }
+
+    #[test]
+    fn test_afterop_modes() {
+        let even_only: Rc<acl::AclEngine<(i32, Action), RepoError>> =
+            Rc::new(acl::InfallibleSyncACLFn(|ctx: &mut (i32, Action)| ctx.0 % 2 == 0));
+
+        let (items, ()) = bulk_filter_access(&even_only, (vec![1, 2, 3, 4], Action::Select), ())
+            .wait()
+            .unwrap();
+        assert_eq!(items, vec![2, 4]);
+
+        assert!(bulk_ensure_access(&even_only, (vec![1, 2, 3, 4], Action::Select), ())
+            .wait()
+            .is_err());
+        let (items, ()) = bulk_ensure_access(&even_only, (vec![2, 4], Action::Select), ()).wait().unwrap();
+        assert_eq!(items, vec![2, 4]);
+    }
+}
@@ -1,4 +1,5 @@
 use connection::*;
+use query_log::QueryLogger;
 
 use failure;
 use futures::future;
@@ -34,11 +35,21 @@ pub trait Sequence<T: Sequenceable> {
 
 pub struct SequenceImpl {
     pub sequence: &'static str,
+    pub query_logger: Option<QueryLogger>,
 }
 
 impl SequenceImpl {
     pub fn new(sequence: &'static str) -> Self {
-        Self { sequence }
+        Self {
+            sequence,
+            query_logger: None,
+        }
+    }
+
+    /// Enables logging of every query run on this sequence
+    pub fn with_query_logger(mut self, query_logger: QueryLogger) -> Self {
+        self.query_logger = Some(query_logger);
+        self
     }
 }
 
@@ -51,13 +62,21 @@ where
 
         let err_msg = format!("Failed to increment sequence {}", sequence);
 
+        let q = format!("SELECT nextval(\'{}\');", sequence);
+        let query_logger = self.query_logger.clone();
+
         Box::new(
-            conn.prepare2(&format!("SELECT nextval(\'{}\');", sequence))
-                .and_then(|(stmt, conn)| {
+            conn.prepare2(&q)
+                .and_then(move |(stmt, conn)| {
+                    let timer = query_logger.map(|query_logger| query_logger.start(&q, &[]));
                     conn.query2(&stmt, vec![])
                         .collect()
                         .map_err(move |(e, conn)| (e.context(err_msg).into(), conn))
                         .and_then(|(mut rows, conn)| {
+                            if let Some(timer) = timer {
+                                timer.finish(rows.len());
+                            }
+
                             future::result(match rows.pop() {
                                 None => Err((format_err!("No rows returned"), conn)),
                                 Some(row) => Ok((T::unmarshal_sequence_row(row), conn)),
@@ -79,13 +98,20 @@ where
             }
             q.push(';');
 
-            conn.prepare2(&q)
-                .and_then(|(stmt, conn)| {
-                    conn.query2(&stmt, vec![])
-                        .collect()
-                        .map_err(move |(e, conn)| (e.context(err_msg).into(), conn))
-                })
-                .map(|(_, conn)| ((), conn))
+            let query_logger = self.query_logger.clone();
+
+            conn.prepare2(&q).and_then(move |(stmt, conn)| {
+                let timer = query_logger.map(|query_logger| query_logger.start(&q, &[]));
+                conn.query2(&stmt, vec![])
+                    .collect()
+                    .map_err(move |(e, conn)| (e.context(err_msg).into(), conn))
+                    .map(|(rows, conn)| {
+                        if let Some(timer) = timer {
+                            timer.finish(rows.len());
+                        }
+                        ((), conn)
+                    })
+            })
         })
     }
 }
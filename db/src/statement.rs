@@ -205,6 +205,36 @@ impl<T> Range<T> {
     }
 }
 
+fn range_filters<T>(range: Range<T>) -> ColumnFilters
+where
+    T: ToSql + 'static,
+{
+    use self::Range::*;
+
+    match range {
+        Exact(v) => vec![(ComparisonMode::EQ, Box::new(v))],
+        From(from) => vec![(
+            if from.inclusive { ComparisonMode::GTE } else { ComparisonMode::GT },
+            Box::new(from.value),
+        )],
+        To(to) => vec![(
+            if to.inclusive { ComparisonMode::LTE } else { ComparisonMode::LT },
+            Box::new(to.value),
+        )],
+        Between((from, to)) => vec![
+            (
+                if from.inclusive { ComparisonMode::GTE } else { ComparisonMode::GT },
+                Box::new(from.value),
+            ),
+            (
+                if to.inclusive { ComparisonMode::LTE } else { ComparisonMode::LT },
+                Box::new(to.value),
+            ),
+        ],
+        In(values) => vec![(ComparisonMode::IN, Box::new(values))],
+    }
+}
+
 /// Construct a simple select or delete query.
 pub struct FilteredOperationBuilder {
     table: &'static str,
@@ -230,33 +260,22 @@ impl FilteredOperationBuilder {
         T: ToSql + 'static,
         R: Into<Range<T>>,
     {
-        use self::Range::*;
+        self.filters.insert(column, range_filters(range.into()));
+        self
+    }
 
-        let new_filters: Vec<(ComparisonMode, Box<ToSql>)> = match range.into() {
-            Exact(v) => vec![(ComparisonMode::EQ, Box::new(v))],
-            From(from) => vec![(
-                if from.inclusive { ComparisonMode::GTE } else { ComparisonMode::GT },
-                Box::new(from.value),
-            )],
-            To(to) => vec![(
-                if to.inclusive { ComparisonMode::LTE } else { ComparisonMode::LT },
-                Box::new(to.value),
-            )],
-            Between((from, to)) => vec![
-                (
-                    if from.inclusive { ComparisonMode::GTE } else { ComparisonMode::GT },
-                    Box::new(from.value),
-                ),
-                (
-                    if to.inclusive { ComparisonMode::LTE } else { ComparisonMode::LT },
-                    Box::new(to.value),
-                ),
-            ],
-            In(values) => vec![(ComparisonMode::IN, Box::new(values))],
-        };
+    /// Add filtering arguments on top of the ones already set for the column.
+    /// Unlike `with_filter`, this can only narrow the selection, which is what row-level security engines need.
+    pub fn restrict<T, R>(&mut self, column: &'static str, range: R)
+    where
+        T: ToSql + 'static,
+        R: Into<Range<T>>,
+    {
+        self.filters.entry(column).or_default().extend(range_filters(range.into()));
+    }
 
-        self.filters.insert(column, new_filters);
-        self
+    pub fn table(&self) -> &'static str {
+        self.table
     }
 
     pub fn with_limit(mut self, limit: Option<i32>) -> Self {
@@ -387,6 +406,11 @@ impl UpdateBuilder {
         self
     }
 
+    /// Filters selecting the rows to update
+    pub fn filters_mut(&mut self) -> &mut FilteredOperationBuilder {
+        &mut self.filters
+    }
+
     /// Builds an UPDATE query if update values are set and SELECT query otherwise.
     pub fn build(self) -> (String, Vec<Box<ToSql + 'static>>) {
         if self.values.is_empty() {
@@ -517,4 +541,22 @@ mod tests {
         assert_eq!(res.0, expectation.0);
         assert_eq!(format!("{:?}", res.1), format!("{:?}", expectation.1));
     }
+
+    #[test]
+    fn test_restrict() {
+        let mut builder = FilteredOperationBuilder::new("my_table").with_filter::<i32, _>("id", vec![1, 2, 3]);
+        builder.restrict::<i32, _>("user_id", 5);
+        builder.restrict::<i32, _>("id", 2);
+
+        let res = builder.build(FilteredOperation::Delete);
+
+        assert_eq!(
+            res.0,
+            "DELETE FROM my_table WHERE id = any($1) AND id = $2 AND user_id = $3 RETURNING *;"
+        );
+        assert_eq!(
+            format!("{:?}", res.1),
+            format!("{:?}", vec![Box::new(vec![1, 2, 3]) as Box<ToSql>, Box::new(2), Box::new(5)])
+        );
+    }
 }
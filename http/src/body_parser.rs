@@ -0,0 +1,511 @@
+//! Request body parsing with size limits and `Content-Type` checks.
+//!
+//! Errors are `ParseError`s carrying the path of the offending field, e.g. `.items[2].price`, with `.` standing for the whole body.
+use std::error::Error as StdError;
+use std::fmt;
+use std::str::FromStr;
+
+use failure;
+use futures::future;
+use futures::prelude::*;
+use hyper::header::{ContentLength, ContentType};
+use hyper::{mime, Request};
+use serde::de::value::{MapDeserializer, StringDeserializer};
+use serde::de::{self, DeserializeOwned, Deserializer, IntoDeserializer, Visitor};
+use serde_json;
+use url::form_urlencoded;
+
+use request_util::{read_body_limited, BodyTooLarge, ParseError};
+
+pub const DEFAULT_BODY_LIMIT: usize = 1024 * 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BodyFormat {
+    /// `application/json` and `application/*+json`
+    Json,
+    /// `application/x-www-form-urlencoded`. Values are parsed by the type of the field, repeated fields are rejected.
+    Form,
+}
+
+/// Parses request bodies of the accepted formats into entities.
+/// Requests without `Content-Type` are parsed as JSON if it is accepted, unless `Content-Type` is required.
+#[derive(Clone, Debug)]
+pub struct BodyParser {
+    limit: usize,
+    formats: Vec<BodyFormat>,
+    require_content_type: bool,
+    allow_empty: bool,
+}
+
+impl Default for BodyParser {
+    /// JSON bodies of up to `DEFAULT_BODY_LIMIT` bytes
+    fn default() -> Self {
+        BodyParser {
+            limit: DEFAULT_BODY_LIMIT,
+            formats: vec![BodyFormat::Json],
+            require_content_type: false,
+            allow_empty: false,
+        }
+    }
+}
+
+impl BodyParser {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Larger bodies fail with `BodyTooLarge`
+    pub fn with_limit(self, limit: usize) -> Self {
+        BodyParser { limit, ..self }
+    }
+
+    pub fn with_formats(self, formats: Vec<BodyFormat>) -> Self {
+        BodyParser { formats, ..self }
+    }
+
+    pub fn with_required_content_type(self) -> Self {
+        BodyParser {
+            require_content_type: true,
+            ..self
+        }
+    }
+
+    /// Parses empty bodies as JSON `null`, like `parse_body` does, instead of failing with `ParseError::EmptyBody`
+    pub fn with_empty_as_null(self) -> Self {
+        BodyParser { allow_empty: true, ..self }
+    }
+
+    fn format(&self, content_type: Option<&ContentType>) -> Result<BodyFormat, ParseError> {
+        let format = match content_type.map(|content_type| &content_type.0) {
+            None if self.require_content_type => None,
+            None => Some(BodyFormat::Json),
+            Some(mime) if mime.type_() != mime::APPLICATION => None,
+            Some(mime) if mime.subtype() == mime::JSON || mime.suffix() == Some(mime::JSON) => Some(BodyFormat::Json),
+            Some(mime) if mime.subtype() == mime::WWW_FORM_URLENCODED => Some(BodyFormat::Form),
+            Some(_) => None,
+        };
+
+        match format {
+            Some(format) if self.formats.contains(&format) => Ok(format),
+            _ => Err(ParseError::UnsupportedContentType {
+                content_type: content_type.map(|content_type| content_type.to_string()).unwrap_or_default(),
+            }),
+        }
+    }
+
+    pub fn parse<T>(&self, req: Request) -> Box<Future<Item = T, Error = failure::Error>>
+    where
+        T: DeserializeOwned + 'static,
+    {
+        let format = match self.format(req.headers().get::<ContentType>()) {
+            Ok(format) => format,
+            Err(e) => return Box::new(future::err(e.into())),
+        };
+        if let Some(&ContentLength(length)) = req.headers().get::<ContentLength>() {
+            if length > self.limit as u64 {
+                return Box::new(future::err(BodyTooLarge { limit: self.limit }.into()));
+            }
+        }
+
+        let allow_empty = self.allow_empty;
+        Box::new(
+            read_body_limited(req.body(), self.limit)
+                .map_err(|e| {
+                    if e.downcast_ref::<BodyTooLarge>().is_some() {
+                        e
+                    } else {
+                        e.context(ParseError::ReadError).into()
+                    }
+                })
+                .and_then(move |bytes| {
+                    if bytes.is_empty() {
+                        return if allow_empty {
+                            serde_json::from_value(serde_json::Value::Null).map_err(|e| invalid_json(&bytes, &e).into())
+                        } else {
+                            Err(ParseError::EmptyBody.into())
+                        };
+                    }
+
+                    match format {
+                        BodyFormat::Json => serde_json::from_slice(&bytes).map_err(|e| invalid_json(&bytes, &e).into()),
+                        BodyFormat::Form => from_form(&bytes).map_err(|e| {
+                            ParseError::InvalidForm {
+                                path: e.field.map(|field| format!(".{}", field)).unwrap_or_else(|| ".".to_string()),
+                                message: e.message,
+                            }
+                            .into()
+                        }),
+                    }
+                }),
+        )
+    }
+}
+
+fn invalid_json(body: &[u8], e: &serde_json::Error) -> ParseError {
+    ParseError::InvalidJson {
+        path: json_path(body, e.line(), e.column()),
+        line: e.line(),
+        column: e.column(),
+        message: e.to_string(),
+    }
+}
+
+enum Segment {
+    Field(Option<String>),
+    Index(usize),
+}
+
+/// Path of the value being parsed at `line` and `column` of `body`, as reported by serde_json
+fn json_path(body: &[u8], line: usize, column: usize) -> String {
+    let line_start = if line <= 1 {
+        0
+    } else {
+        body.iter()
+            .enumerate()
+            .filter(|&(_, byte)| *byte == b'\n')
+            .nth(line - 2)
+            .map_or(body.len(), |(i, _)| i + 1)
+    };
+    let end = (line_start + column).min(body.len());
+
+    let mut stack = vec![];
+    let mut string = vec![];
+    let (mut in_string, mut escaped, mut expecting_field) = (false, false, false);
+    for &byte in &body[..end] {
+        if in_string {
+            match byte {
+                _ if escaped => {
+                    escaped = false;
+                    string.push(byte);
+                }
+                b'\\' => escaped = true,
+                b'"' => {
+                    in_string = false;
+                    if let (true, Some(Segment::Field(ref mut field))) = (expecting_field, stack.last_mut()) {
+                        *field = Some(String::from_utf8_lossy(&string).into_owned());
+                    }
+                }
+                _ => string.push(byte),
+            }
+            continue;
+        }
+
+        match byte {
+            b'{' => {
+                stack.push(Segment::Field(None));
+                expecting_field = true;
+            }
+            b'[' => stack.push(Segment::Index(0)),
+            b'}' | b']' => {
+                stack.pop();
+            }
+            b',' => match stack.last_mut() {
+                Some(Segment::Index(ref mut i)) => *i += 1,
+                Some(Segment::Field(ref mut field)) => {
+                    *field = None;
+                    expecting_field = true;
+                }
+                None => {}
+            },
+            b':' => expecting_field = false,
+            b'"' => {
+                in_string = true;
+                string.clear();
+            }
+            _ => {}
+        }
+    }
+
+    let path = stack.iter().fold(String::new(), |mut path, segment| {
+        match *segment {
+            Segment::Field(Some(ref field)) => {
+                path.push('.');
+                path.push_str(field);
+            }
+            Segment::Field(None) => {}
+            Segment::Index(i) => path.push_str(&format!("[{}]", i)),
+        }
+        path
+    });
+    if path.is_empty() {
+        ".".to_string()
+    } else {
+        path
+    }
+}
+
+#[derive(Debug)]
+struct FormError {
+    field: Option<String>,
+    message: String,
+}
+
+impl fmt::Display for FormError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl StdError for FormError {
+    fn description(&self) -> &str {
+        &self.message
+    }
+}
+
+impl de::Error for FormError {
+    fn custom<T: fmt::Display>(msg: T) -> Self {
+        FormError {
+            field: None,
+            message: msg.to_string(),
+        }
+    }
+}
+
+fn from_form<T: DeserializeOwned>(body: &[u8]) -> Result<T, FormError> {
+    let fields = form_urlencoded::parse(body).map(|(field, value)| {
+        (
+            field.clone().into_owned(),
+            FormValue {
+                field: field.into_owned(),
+                value: value.into_owned(),
+            },
+        )
+    });
+
+    T::deserialize(MapDeserializer::new(fields))
+}
+
+/// Value of a form field, parsed by the type it is deserialized into
+struct FormValue {
+    field: String,
+    value: String,
+}
+
+impl FormValue {
+    fn visit<V, F>(self, f: F) -> Result<V, FormError>
+    where
+        F: FnOnce(String) -> Result<V, FormError>,
+    {
+        let field = self.field;
+        f(self.value).map_err(|e| FormError {
+            field: e.field.or(Some(field)),
+            message: e.message,
+        })
+    }
+
+    fn visit_parsed<T, V, F>(self, f: F) -> Result<V, FormError>
+    where
+        T: FromStr,
+        T::Err: fmt::Display,
+        F: FnOnce(T) -> Result<V, FormError>,
+    {
+        self.visit(|value| match value.parse::<T>() {
+            Ok(value) => f(value),
+            Err(e) => Err(de::Error::custom(format!("invalid value {:?}: {}", value, e))),
+        })
+    }
+}
+
+macro_rules! deserialize_parsed {
+    ($($method:ident => $visit:ident,)*) => {
+        $(
+            fn $method<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, FormError> {
+                self.visit_parsed(|value| visitor.$visit(value))
+            }
+        )*
+    };
+}
+
+impl<'de> Deserializer<'de> for FormValue {
+    type Error = FormError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, FormError> {
+        self.visit(|value| visitor.visit_string(value))
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, FormError> {
+        visitor.visit_some(self)
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(self, _name: &'static str, visitor: V) -> Result<V::Value, FormError> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, FormError> {
+        self.visit(|value| visitor.visit_enum(IntoDeserializer::<FormError>::into_deserializer(value) as StringDeserializer<FormError>))
+    }
+
+    deserialize_parsed! {
+        deserialize_bool => visit_bool,
+        deserialize_i8 => visit_i8,
+        deserialize_i16 => visit_i16,
+        deserialize_i32 => visit_i32,
+        deserialize_i64 => visit_i64,
+        deserialize_u8 => visit_u8,
+        deserialize_u16 => visit_u16,
+        deserialize_u32 => visit_u32,
+        deserialize_u64 => visit_u64,
+        deserialize_f32 => visit_f32,
+        deserialize_f64 => visit_f64,
+        deserialize_char => visit_char,
+    }
+
+    forward_to_deserialize_any! {
+        str string bytes byte_buf unit unit_struct seq tuple tuple_struct map struct identifier ignored_any
+    }
+}
+
+impl<'de> IntoDeserializer<'de, FormError> for FormValue {
+    type Deserializer = Self;
+
+    fn into_deserializer(self) -> Self {
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use errors::{Codeable, PayloadCarrier};
+    use hyper::{Method, StatusCode};
+
+    #[derive(Debug, Deserialize, PartialEq)]
+    #[serde(rename_all = "lowercase")]
+    enum Kind {
+        Digital,
+        Physical,
+    }
+
+    #[derive(Debug, Deserialize, PartialEq)]
+    struct Item {
+        name: String,
+        price: u32,
+        kind: Option<Kind>,
+    }
+
+    #[derive(Debug, Deserialize, PartialEq)]
+    struct Order {
+        items: Vec<Item>,
+    }
+
+    fn request(content_type: Option<&str>, body: &'static str) -> Request {
+        let mut req = Request::new(Method::Post, "/orders".parse().unwrap());
+        if let Some(content_type) = content_type {
+            req.headers_mut().set_raw("Content-Type", content_type.to_string());
+        }
+        req.set_body(body);
+        req
+    }
+
+    fn parse_error<T>(res: Result<T, failure::Error>) -> ParseError {
+        res.err()
+            .and_then(|e| e.downcast::<ParseError>().ok())
+            .expect("Expected ParseError")
+    }
+
+    #[test]
+    fn test_json_path() {
+        let body = r#"{"items": [{"name": "a", "price": 1}, {"name": "b,\"]", "price": "2"}]}"#;
+        let parser = BodyParser::new();
+
+        match parse_error(parser.parse::<Order>(request(Some("application/json"), body)).wait()) {
+            e @ ParseError::InvalidJson { .. } => {
+                assert_eq!(e.code(), StatusCode::BadRequest);
+                let payload = e.payload().unwrap();
+                assert_eq!(payload["path"], ".items[1].price");
+                assert_eq!(payload["line"], 1);
+            }
+            e => panic!("Unexpected error {:?}", e),
+        }
+
+        assert_eq!(json_path(b"{\n  \"a\": [\n    1,\n    {\"b\": x", 4, 12), ".a[1].b");
+        assert_eq!(json_path(b"[1, 2]", 1, 6), ".");
+    }
+
+    #[test]
+    fn test_content_type_and_limit() {
+        let parser = BodyParser::new().with_limit(16);
+        let body = r#"{"items": []}"#;
+
+        assert_eq!(
+            parser
+                .parse::<Order>(request(Some("application/vnd.api+json"), body))
+                .wait()
+                .unwrap(),
+            Order { items: vec![] }
+        );
+        assert_eq!(parser.parse::<Order>(request(None, body)).wait().unwrap(), Order { items: vec![] });
+
+        match parse_error(
+            parser
+                .clone()
+                .with_required_content_type()
+                .parse::<Order>(request(None, body))
+                .wait(),
+        ) {
+            ParseError::UnsupportedContentType { .. } => {}
+            e => panic!("Unexpected error {:?}", e),
+        }
+        let e = parse_error(parser.parse::<Order>(request(Some("text/plain"), body)).wait());
+        assert_eq!(e.code(), StatusCode::UnsupportedMediaType);
+
+        let e = parser
+            .parse::<Order>(request(None, r#"{"items": [], "x": 1}"#))
+            .wait()
+            .err()
+            .unwrap();
+        assert_eq!(e.downcast_ref::<BodyTooLarge>().map(|e| e.limit), Some(16));
+
+        match parse_error(parser.parse::<Option<Order>>(request(None, "")).wait()) {
+            ParseError::EmptyBody => {}
+            e => panic!("Unexpected error {:?}", e),
+        }
+        assert_eq!(
+            parser
+                .with_empty_as_null()
+                .parse::<Option<Order>>(request(None, ""))
+                .wait()
+                .unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn test_form() {
+        let parser = BodyParser::new().with_formats(vec![BodyFormat::Json, BodyFormat::Form]);
+        let form = |body| request(Some("application/x-www-form-urlencoded"), body);
+
+        assert_eq!(
+            parser.parse::<Item>(form("name=T+shirt%21&price=10&kind=physical")).wait().unwrap(),
+            Item {
+                name: "T shirt!".to_string(),
+                price: 10,
+                kind: Some(Kind::Physical),
+            }
+        );
+
+        match parse_error(parser.parse::<Item>(form("name=a&price=ten")).wait()) {
+            ParseError::InvalidForm { path, .. } => assert_eq!(path, ".price"),
+            e => panic!("Unexpected error {:?}", e),
+        }
+        match parse_error(parser.parse::<Item>(form("name=a&price=1&kind=virtual")).wait()) {
+            ParseError::InvalidForm { path, .. } => assert_eq!(path, ".kind"),
+            e => panic!("Unexpected error {:?}", e),
+        }
+        match parse_error(parser.parse::<Item>(form("name=a")).wait()) {
+            ParseError::InvalidForm { path, message } => {
+                assert_eq!(path, ".");
+                assert!(message.contains("price"));
+            }
+            e => panic!("Unexpected error {:?}", e),
+        }
+
+        let e = parse_error(BodyParser::new().parse::<Item>(form("name=a&price=1")).wait());
+        assert_eq!(e.code(), StatusCode::UnsupportedMediaType);
+    }
+}
@@ -1,4 +1,6 @@
 use std;
+use std::fmt;
+use std::io;
 use std::str;
 use std::sync::Arc;
 
@@ -7,19 +9,28 @@ use failure;
 use failure::Fail;
 use futures::future::{self, Either};
 use futures::prelude::*;
+use futures::sync::mpsc::SendError;
 use hyper;
-use hyper::header::{AccessControlAllowHeaders, AccessControlAllowMethods, AccessControlRequestHeaders, ContentLength, ContentType};
+use hyper::header::{
+    q, Accept, AccessControlAllowHeaders, AccessControlAllowMethods, AccessControlRequestHeaders, Charset, ContentDisposition,
+    ContentLength, ContentType, DispositionParam, DispositionType, Header, Location, Quality,
+};
 use hyper::server::Service;
 use hyper::Method::{Get, Options, Post};
 use hyper::{mime, Error, Headers, StatusCode};
+use hyper::mime::Mime;
 use hyper::{Request, Response};
+use serde::ser::Serialize;
 use serde_json;
+use tokio_core::reactor::Handle;
 
 use log::{self, Level};
 
 use request_util::{get_correlation_token, try_read_body};
 
 use errors::*;
+pub use middleware::{Flow, Middleware, MiddlewareFuture, RequestHead};
+use middleware::{run_chain, ErrorRenderer, Handler};
 use system::{SystemService, SystemServiceImpl};
 
 pub type ControllerFuture = Box<Future<Item = String, Error = failure::Error>>;
@@ -29,13 +40,260 @@ pub trait Controller {
     fn call(&self, request: Request) -> ControllerFuture;
 }
 
+pub type ResponseFuture = Box<Future<Item = ControllerResponse, Error = failure::Error>>;
+
+/// Controller that sets status, headers and body of responses itself.
+/// Every `Controller` is a `ResponseController` responding with 200 and JSON.
+pub trait ResponseController {
+    fn call(&self, request: Request) -> ResponseFuture;
+}
+
+impl<T> ResponseController for T
+where
+    T: Controller + ?Sized,
+{
+    fn call(&self, request: Request) -> ResponseFuture {
+        Box::new(Controller::call(self, request).map(ControllerResponse::from))
+    }
+}
+
+/// Bytes logged of request and response bodies in debug mode
+const DEBUG_BODY_LOG_LIMIT: usize = 4096;
+
+/// Bytes of a streamed body buffered when the application has no reactor handle to stream it on
+pub const STREAM_BUFFER_LIMIT: usize = 8 * 1024 * 1024;
+
+pub type BodyStream = Box<Stream<Item = Vec<u8>, Error = failure::Error>>;
+
+pub enum ResponseBody {
+    Bytes(Vec<u8>),
+    /// Sent with chunked transfer encoding as chunks arrive.
+    /// An error after the first chunk aborts the response, since the status has already been sent.
+    Stream(BodyStream),
+}
+
+impl fmt::Debug for ResponseBody {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ResponseBody::Bytes(ref bytes) => write!(f, "{}", body_log(bytes)),
+            ResponseBody::Stream(_) => write!(f, "`streamed body`"),
+        }
+    }
+}
+
+/// Body for debug logs, truncated to `DEBUG_BODY_LOG_LIMIT` bytes
+fn body_log(bytes: &[u8]) -> String {
+    let preview = &bytes[..bytes.len().min(DEBUG_BODY_LOG_LIMIT)];
+    let text = match str::from_utf8(preview) {
+        Ok(text) => text,
+        // Truncation may split a character
+        Err(ref e) if e.error_len().is_none() && preview.len() < bytes.len() => str::from_utf8(&preview[..e.valid_up_to()]).unwrap_or_default(),
+        Err(_) => return format!("`{} bytes of binary data`", bytes.len()),
+    };
+
+    if text.len() < bytes.len() {
+        format!("{}... `{} bytes total`", text, bytes.len())
+    } else {
+        text.to_string()
+    }
+}
+
+/// Successful response of a `ResponseController`. Errors are still reported as JSON `ErrorMessage`.
+#[derive(Debug)]
+pub struct ControllerResponse {
+    pub status: StatusCode,
+    pub headers: Headers,
+    pub body: ResponseBody,
+}
+
+impl ControllerResponse {
+    /// Response with an empty body
+    pub fn new(status: StatusCode) -> Self {
+        ControllerResponse {
+            status,
+            headers: Headers::new(),
+            body: ResponseBody::Bytes(vec![]),
+        }
+    }
+
+    pub fn ok<B: Into<Vec<u8>>>(content_type: Mime, body: B) -> Self {
+        Self::new(StatusCode::Ok).with_body(content_type, body)
+    }
+
+    /// 200 with the value serialized to JSON
+    pub fn json<T: Serialize>(value: &T) -> Result<Self, failure::Error> {
+        Ok(Self::ok(mime::APPLICATION_JSON, serde_json::to_vec(value)?))
+    }
+
+    /// 201 with `Location` of the created resource
+    pub fn created(location: &str) -> Self {
+        Self::new(StatusCode::Created).with_header(Location::new(location.to_string()))
+    }
+
+    pub fn no_content() -> Self {
+        Self::new(StatusCode::NoContent)
+    }
+
+    /// 303 See Other, so that clients follow with a GET regardless of the request method
+    pub fn redirect(location: &str) -> Self {
+        Self::new(StatusCode::SeeOther).with_header(Location::new(location.to_string()))
+    }
+
+    pub fn with_status(self, status: StatusCode) -> Self {
+        ControllerResponse { status, ..self }
+    }
+
+    pub fn with_header<H: Header>(mut self, header: H) -> Self {
+        self.headers.set(header);
+        self
+    }
+
+    pub fn with_body<B: Into<Vec<u8>>>(self, content_type: Mime, body: B) -> Self {
+        ControllerResponse {
+            body: ResponseBody::Bytes(body.into()),
+            ..self.with_header(ContentType(content_type))
+        }
+    }
+
+    /// Streams the body if the application has a reactor handle, see `Application::with_handle`
+    pub fn with_stream<S>(self, content_type: Mime, stream: S) -> Self
+    where
+        S: Stream<Item = Vec<u8>, Error = failure::Error> + 'static,
+    {
+        ControllerResponse {
+            body: ResponseBody::Stream(Box::new(stream)),
+            ..self.with_header(ContentType(content_type))
+        }
+    }
+
+    /// Names the file browsers save the body to, e.g. for CSV exports
+    pub fn with_attachment(self, filename: &str) -> Self {
+        self.with_header(ContentDisposition {
+            disposition: DispositionType::Attachment,
+            parameters: vec![DispositionParam::Filename(
+                Charset::Ext("UTF-8".to_string()),
+                None,
+                filename.as_bytes().to_vec(),
+            )],
+        })
+    }
+
+    /// Streamed bodies are buffered without `handle` to spawn the stream on.
+    /// Buffering fails once the body exceeds `STREAM_BUFFER_LIMIT`, since the status has not been sent yet.
+    fn into_response(self, handle: Option<&Handle>) -> Box<Future<Item = Response, Error = failure::Error>> {
+        let mut resp = Response::new().with_status(self.status);
+        *resp.headers_mut() = self.headers;
+        if self.status == StatusCode::NoContent || self.status == StatusCode::NotModified {
+            return Box::new(future::ok(resp));
+        }
+
+        match (self.body, handle) {
+            (ResponseBody::Bytes(bytes), _) => {
+                resp.headers_mut().set(ContentLength(bytes.len() as u64));
+                resp.set_body(bytes);
+                Box::new(future::ok(resp))
+            }
+            (ResponseBody::Stream(stream), Some(handle)) => {
+                let (sender, body) = hyper::Body::pair();
+                let chunks = stream.then(|res| {
+                    Ok::<_, SendError<_>>(
+                        res.map(hyper::Chunk::from)
+                            .map_err(|e| hyper::Error::Io(io::Error::new(io::ErrorKind::Other, e.to_string()))),
+                    )
+                });
+                handle.spawn(sender.send_all(chunks).then(|res| {
+                    if res.is_err() {
+                        debug!("Client closed connection before streamed body was sent");
+                    }
+                    Ok(())
+                }));
+                resp.set_body(body);
+                Box::new(future::ok(resp))
+            }
+            (ResponseBody::Stream(stream), None) => {
+                warn!("Buffering streamed body, since the application has no reactor handle, see `Application::with_handle`");
+                Box::new(
+                    stream
+                        .fold(vec![], |mut bytes, chunk| {
+                            bytes.extend(chunk);
+                            if bytes.len() > STREAM_BUFFER_LIMIT {
+                                Err(format_err!(
+                                    "Streamed body exceeds {} bytes buffered without a reactor handle",
+                                    STREAM_BUFFER_LIMIT
+                                ))
+                            } else {
+                                Ok(bytes)
+                            }
+                        })
+                        .map(move |bytes| {
+                            resp.headers_mut().set(ContentLength(bytes.len() as u64));
+                            resp.with_body(bytes)
+                        }),
+                )
+            }
+        }
+    }
+}
+
+/// JSON body of a `Controller`
+impl From<String> for ControllerResponse {
+    fn from(body: String) -> Self {
+        Self::ok(mime::APPLICATION_JSON, body)
+    }
+}
+
+/// Picks the content type from `available` that the request accepts with the highest quality.
+/// Earlier types win ties, and the first type is picked if the request has no `Accept` header.
+/// Returns `None` if none are acceptable, which calls for 406 Not Acceptable.
+pub fn negotiate_content_type(req: &Request, available: &[Mime]) -> Option<Mime> {
+    let accept = match req.headers().get::<Accept>() {
+        Some(accept) => accept,
+        None => return available.first().cloned(),
+    };
+
+    let quality = |mime: &Mime| {
+        accept
+            .iter()
+            .filter_map(|item| {
+                let range = &item.item;
+                let specificity = if range.type_() == mime::STAR {
+                    0
+                } else if range.type_() != mime.type_() {
+                    return None;
+                } else if range.subtype() == mime::STAR {
+                    1
+                } else if range.subtype() != mime.subtype() {
+                    return None;
+                } else {
+                    2
+                };
+                Some((specificity, item.quality))
+            })
+            .max()
+            .map(|(_, quality)| quality)
+            .unwrap_or_else(|| q(0))
+    };
+
+    let mut best: Option<(&Mime, Quality)> = None;
+    for mime in available {
+        let quality = quality(mime);
+        if quality > q(0) && best.map(|(_, best_quality)| quality > best_quality).unwrap_or(true) {
+            best = Some((mime, quality));
+        }
+    }
+
+    best.map(|(mime, _)| mime.clone())
+}
+
 pub type ServerFuture = Box<Future<Item = Response, Error = hyper::Error>>;
 
 /// Batteries-included Service for Hyper HTTP server. Feed it your Controller and it'll adapt it for Hyper.
 pub struct Application<E: Fail + Codeable + PayloadCarrier> {
-    pub controller: Arc<dyn Controller>,
+    pub controller: Arc<dyn ResponseController>,
     pub system_service: Box<SystemService>,
     pub middleware: Arc<Fn(Response) -> Response>,
+    pub middlewares: Arc<Vec<Arc<Middleware>>>,
+    pub handle: Option<Handle>,
     _error_type: std::marker::PhantomData<E>,
 }
 
@@ -51,104 +309,39 @@ where
     fn call(&self, req: Request) -> ServerFuture {
         let call_start = Local::now();
 
-        let correlation_token = get_correlation_token(&req);
-
-        Box::new(
-            match *req.method() {
-                Options => {
-                    let req_headers = req.headers().clone();
-                    let acah = req_headers.get::<AccessControlRequestHeaders>();
-
-                    let mut resp = Response::new();
-                    let mut new_headers = Headers::new();
-                    new_headers.set(AccessControlAllowMethods(vec![Get, Post, Options]));
-                    if let Some(a) = acah {
-                        new_headers.set(AccessControlAllowHeaders(a.to_vec()));
-                    };
-                    new_headers.set(ContentType(mime::TEXT_HTML));
-                    std::mem::replace(resp.headers_mut(), new_headers);
-
-                    Box::new(future::ok(resp)) as ServerFuture
-                }
-                _ => {
-                    let token = correlation_token.clone();
-
-                    Box::new(
-                        match req.uri().path() {
-                            "/healthcheck" => {
-                                Box::new(self.system_service.healthcheck().then(|res| {
-                                    let response = match res {
-                                        Ok(data) => Self::response_with_json(data.clone()),
-                                        Err(err) => Self::response_with_error(&err),
-                                    };
-
-                                    future::ok(response)
-                                })) as ServerFuture
-                            },
-                            _ => {
-                                let controller = self.controller.clone();
-                                let level = log::max_level();
-
-                                let fut = if level == Level::Debug || level == Level::Trace {
-                                    let (method, uri, http_version, headers, body) = req.deconstruct();
-                                    Either::A(
-                                        try_read_body(body)
-                                            .map_err(From::from)
-                                            .and_then(move |bytes| {
-                                                {
-                                                    let body_log = match str::from_utf8(&bytes) {
-                                                        Ok(data) => data,
-                                                        Err(_) => "`can not parse body to string`",
-                                                    };
-                                                    debug!(
-                                                        "Server received Request, method: {}, url: {}, headers: {:#?}, body: {}, correlation token: {}",
-                                                        method, uri, headers, body_log, token
-                                                    );
-                                                }
-
-                                                let mut req = Request::new(method, uri);
-                                                req.set_body(bytes);
-                                                req.set_version(http_version);
-                                                std::mem::replace(req.headers_mut(), headers);
-
-                                                Ok(req)
-                                            }).and_then(move |req| controller.call(req)),
-                                    )
-                                } else {
-                                    Either::B(self.controller.call(req))
-                                };
-
-                                Box::new(fut.then({
-                                let token = correlation_token.clone();
-
-                                move |res| {
-                                let (response, body) = match res {
-                                    Ok(data) => (Self::response_with_json(data.clone()), data),
-                                    Err(err) => (Self::response_with_error(&err), Self::error_to_body(&err)),
-                                };
-
-                                let dt = Local::now() - call_start;
-                                debug!(
-                                    "Server send Response, status: {}, headers: {:#?}, body: {:?}, elapsed time = {}.{:03}, correlation token: {}",
-                                    response.status().as_u16(),
-                                    response.headers(),
-                                    body,
-                                    dt.num_seconds(),
-                                    dt.num_milliseconds(),
-                                    token
-                                );
-
-                                future::ok(response)
-                            }
-                        }))
-                        }
-                    }) as ServerFuture
-                }
-            }.map({
-                let middleware = self.middleware.clone();
-                move |resp| middleware(resp)
-            }),
-        )
+        let fut = if *req.method() != Options && req.uri().path() == "/healthcheck" {
+            Box::new(self.system_service.healthcheck().then(|res| {
+                let response = match res {
+                    Ok(data) => Self::response_with_json(data.clone()),
+                    Err(err) => Self::response_with_error(&err),
+                };
+
+                future::ok(response)
+            })) as ServerFuture
+        } else {
+            let controller = self.controller.clone();
+            let handle = self.handle.clone();
+            let handler: Handler = Arc::new(move |req: Request| match *req.method() {
+                Options => Box::new(future::ok(Self::options_response(&req))) as MiddlewareFuture<Response>,
+                _ => Box::new(Self::call_controller(controller.clone(), handle.clone(), req, call_start).from_err()),
+            });
+            let head = Arc::new(RequestHead::from(&req));
+            let render_error: ErrorRenderer = Arc::new(|err| Self::response_with_error(err));
+
+            Box::new(
+                run_chain(self.middlewares.clone(), 0, head, req, handler, render_error).then(|res| {
+                    future::ok(match res {
+                        Ok(response) => response,
+                        Err(err) => Self::response_with_error(&err),
+                    })
+                }),
+            )
+        };
+
+        Box::new(fut.map({
+            let middleware = self.middleware.clone();
+            move |resp| middleware(resp)
+        }))
     }
 }
 
@@ -158,11 +351,13 @@ where
 {
     pub fn new<T>(controller: T) -> Self
     where
-        T: Controller + 'static,
+        T: ResponseController + 'static,
     {
         Self {
             controller: Arc::new(controller),
             middleware: Arc::new(|resp| resp),
+            middlewares: Arc::new(vec![]),
+            handle: None,
             system_service: Box::new(SystemServiceImpl::default()),
             _error_type: Default::default(),
         }
@@ -171,7 +366,7 @@ where
     /// Replaces controller in the application
     pub fn with_controller<T>(mut self, controller: T) -> Self
     where
-        T: Controller + 'static,
+        T: ResponseController + 'static,
     {
         self.controller = Arc::new(controller);
         self
@@ -195,6 +390,113 @@ where
         self
     }
 
+    /// Reactor handle the server runs on, needed to stream response bodies
+    pub fn with_handle(mut self, handle: Handle) -> Self {
+        self.handle = Some(handle);
+        self
+    }
+
+    /// Adds middleware to the end of the chain. Requests pass middlewares in the order they were added,
+    /// responses in reverse order. Healthchecks bypass the chain.
+    pub fn with_request_middleware<M>(mut self, middleware: M) -> Self
+    where
+        M: Middleware + 'static,
+    {
+        Arc::make_mut(&mut self.middlewares).push(Arc::new(middleware));
+        self
+    }
+
+    /// Responds to OPTIONS requests not answered by middlewares, e.g. `cors::CorsPolicy`
+    fn options_response(req: &Request) -> Response {
+        let mut resp = Response::new();
+        let mut new_headers = Headers::new();
+        new_headers.set(AccessControlAllowMethods(vec![Get, Post, Options]));
+        if let Some(a) = req.headers().get::<AccessControlRequestHeaders>() {
+            new_headers.set(AccessControlAllowHeaders(a.to_vec()));
+        };
+        new_headers.set(ContentType(mime::TEXT_HTML));
+        std::mem::replace(resp.headers_mut(), new_headers);
+
+        resp
+    }
+
+    /// Passes request to controller, logging request and response in debug mode.
+    /// Request bodies are only logged if their `Content-Length` is within `DEBUG_BODY_LOG_LIMIT`, so that streams are not buffered.
+    fn call_controller(
+        controller: Arc<dyn ResponseController>,
+        handle: Option<Handle>,
+        req: Request,
+        call_start: DateTime<Local>,
+    ) -> ServerFuture {
+        let correlation_token = get_correlation_token(&req);
+        let level = log::max_level();
+        let content_length = req.headers().get::<ContentLength>().map(|length| length.0);
+
+        let fut = match content_length {
+            Some(length) if (level == Level::Debug || level == Level::Trace) && length <= DEBUG_BODY_LOG_LIMIT as u64 => {
+                let token = correlation_token.clone();
+                let (method, uri, http_version, headers, body) = req.deconstruct();
+                Either::A(
+                    try_read_body(body)
+                        .map_err(From::from)
+                        .and_then(move |bytes| {
+                            debug!(
+                                "Server received Request, method: {}, url: {}, headers: {:#?}, body: {}, correlation token: {}",
+                                method,
+                                uri,
+                                headers,
+                                body_log(&bytes),
+                                token
+                            );
+
+                            let mut req = Request::new(method, uri);
+                            req.set_body(bytes);
+                            req.set_version(http_version);
+                            std::mem::replace(req.headers_mut(), headers);
+
+                            Ok(req)
+                        }).and_then(move |req| controller.call(req)),
+                )
+            }
+            _ => {
+                debug!(
+                    "Server received Request, method: {}, url: {}, headers: {:#?}, body: `{}`, correlation token: {}",
+                    req.method(),
+                    req.uri(),
+                    req.headers(),
+                    content_length.map_or("not logged".to_string(), |length| format!("{} bytes, not logged", length)),
+                    correlation_token
+                );
+                Either::B(controller.call(req))
+            }
+        };
+
+        Box::new(
+            fut.and_then(move |data| {
+                let body = format!("{:?}", data.body);
+                data.into_response(handle.as_ref()).map(|response| (response, body))
+            }).then(move |res| {
+                let (response, body) = match res {
+                    Ok(data) => data,
+                    Err(err) => (Self::response_with_error(&err), Self::error_to_body(&err)),
+                };
+
+                let dt = Local::now() - call_start;
+                debug!(
+                    "Server send Response, status: {}, headers: {:#?}, body: {:?}, elapsed time = {}.{:03}, correlation token: {}",
+                    response.status().as_u16(),
+                    response.headers(),
+                    body,
+                    dt.num_seconds(),
+                    dt.num_milliseconds(),
+                    correlation_token
+                );
+
+                future::ok(response)
+            }),
+        )
+    }
+
     /// Responds with success, logs response body
     fn response_with_json(body: String) -> Response {
         Self::response_with_body(body).with_status(StatusCode::Ok)
@@ -222,3 +524,200 @@ where
             .with_body(body)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cors::CorsPolicy;
+    use futures::stream;
+    use hyper::header::AccessControlAllowOrigin;
+    use request_util::{read_body, read_body_limited};
+    use serde_json::Value;
+    use tokio_core::reactor::Core;
+
+    #[derive(Debug, Fail)]
+    #[fail(display = "Test error")]
+    struct TestError;
+
+    impl Codeable for TestError {
+        fn code(&self) -> StatusCode {
+            StatusCode::BadRequest
+        }
+    }
+
+    impl PayloadCarrier for TestError {
+        fn payload(&self) -> Option<Value> {
+            None
+        }
+    }
+
+    struct Export;
+
+    impl ResponseController for Export {
+        fn call(&self, req: Request) -> ResponseFuture {
+            let csv = mime::TEXT_CSV;
+            Box::new(future::ok(
+                match negotiate_content_type(&req, &[mime::APPLICATION_JSON, csv.clone()]) {
+                    Some(ref content_type) if *content_type == csv => ControllerResponse::ok(csv, "id\n1\n").with_attachment("export.csv"),
+                    Some(_) => ControllerResponse::from("[1]".to_string()),
+                    None => ControllerResponse::new(StatusCode::NotAcceptable),
+                },
+            ))
+        }
+    }
+
+    struct Legacy;
+
+    impl Controller for Legacy {
+        fn call(&self, _: Request) -> ControllerFuture {
+            Box::new(future::ok("{}".to_string()))
+        }
+    }
+
+    fn request(accept: &str) -> Request {
+        let mut req = Request::new(Get, "/export".parse().unwrap());
+        if !accept.is_empty() {
+            req.headers_mut().set_raw("Accept", accept.to_string());
+        }
+        req
+    }
+
+    fn content_type(resp: &Response) -> Option<String> {
+        resp.headers().get::<ContentType>().map(|content_type| content_type.to_string())
+    }
+
+    #[test]
+    fn test_negotiate_content_type() {
+        let available = [mime::APPLICATION_JSON, mime::TEXT_CSV];
+        let negotiate = |accept| negotiate_content_type(&request(accept), &available);
+
+        assert_eq!(negotiate(""), Some(mime::APPLICATION_JSON));
+        assert_eq!(negotiate("*/*"), Some(mime::APPLICATION_JSON));
+        assert_eq!(negotiate("text/*"), Some(mime::TEXT_CSV));
+        assert_eq!(negotiate("application/json;q=0.5, text/csv"), Some(mime::TEXT_CSV));
+        assert_eq!(negotiate("*/*;q=0.1, text/csv;q=0"), Some(mime::APPLICATION_JSON));
+        assert_eq!(negotiate("application/pdf"), None);
+    }
+
+    #[test]
+    fn test_response_controller() {
+        let app = Application::<TestError>::new(Export);
+
+        let resp = app.call(request("text/csv")).wait().unwrap();
+        assert_eq!(resp.status(), StatusCode::Ok);
+        assert_eq!(content_type(&resp), Some("text/csv".to_string()));
+        assert!(resp.headers().has::<ContentDisposition>());
+        assert_eq!(resp.headers().get::<ContentLength>(), Some(&ContentLength(5)));
+
+        let resp = app.call(request("application/pdf")).wait().unwrap();
+        assert_eq!(resp.status(), StatusCode::NotAcceptable);
+
+        let resp = Application::<TestError>::new(Legacy).call(request("")).wait().unwrap();
+        assert_eq!(resp.status(), StatusCode::Ok);
+        assert_eq!(content_type(&resp), Some("application/json".to_string()));
+    }
+
+    struct Unavailable;
+
+    impl Middleware for Unavailable {
+        fn before(&self, _req: Request) -> MiddlewareFuture<Flow> {
+            Box::new(future::err(format_err!("Backend is unavailable")))
+        }
+    }
+
+    #[test]
+    fn test_middleware_failure() {
+        let app = Application::<TestError>::new(Export).with_request_middleware(Unavailable);
+
+        let resp = app.call(request("")).wait().unwrap();
+        assert_eq!(resp.status(), StatusCode::InternalServerError);
+        let error = serde_json::from_str::<ErrorMessage>(&read_body(resp.body()).wait().unwrap()).unwrap();
+        assert_eq!(error.description, "Backend is unavailable");
+
+        // Error responses still pass through the middlewares before the failing one
+        let app = Application::<TestError>::new(Export)
+            .with_request_middleware(CorsPolicy::default())
+            .with_request_middleware(Unavailable);
+        let mut req = request("");
+        req.headers_mut().set_raw("Origin", "https://storiqa.com");
+
+        let resp = app.call(req).wait().unwrap();
+        assert_eq!(resp.status(), StatusCode::InternalServerError);
+        assert_eq!(resp.headers().get::<AccessControlAllowOrigin>(), Some(&AccessControlAllowOrigin::Any));
+    }
+
+    #[test]
+    fn test_into_response() {
+        let resp = ControllerResponse::created("/users/1").into_response(None).wait().unwrap();
+        assert_eq!(resp.status(), StatusCode::Created);
+        assert_eq!(resp.headers().get::<Location>().map(|location| &**location), Some("/users/1"));
+
+        let resp = ControllerResponse::no_content().into_response(None).wait().unwrap();
+        assert_eq!(resp.status(), StatusCode::NoContent);
+        assert!(!resp.headers().has::<ContentLength>());
+    }
+
+    struct Upload;
+
+    impl ResponseController for Upload {
+        fn call(&self, req: Request) -> ResponseFuture {
+            Box::new(read_body_limited(req.body(), 4).map(|body| {
+                let chunks = body.into_iter().map(|byte| Ok(vec![byte])).collect::<Vec<_>>();
+                ControllerResponse::new(StatusCode::Ok).with_stream(mime::TEXT_PLAIN, stream::iter_result(chunks))
+            }))
+        }
+    }
+
+    fn upload(body: &'static str) -> Request {
+        let mut req = Request::new(Post, "/upload".parse().unwrap());
+        req.set_body(body);
+        req
+    }
+
+    #[test]
+    fn test_streaming() {
+        let app = Application::<TestError>::new(Upload);
+        let resp = app.call(upload("abcd")).wait().unwrap();
+        assert_eq!(resp.headers().get::<ContentLength>(), Some(&ContentLength(4)));
+        assert_eq!(read_body(resp.body()).wait().unwrap(), "abcd");
+
+        let mut core = Core::new().unwrap();
+        let app = app.with_handle(core.handle());
+        let resp = core.run(app.call(upload("abc"))).unwrap();
+        assert!(!resp.headers().has::<ContentLength>());
+        assert_eq!(core.run(read_body(resp.body())).unwrap(), "abc");
+
+        let resp = app.call(upload("abcde")).wait().unwrap();
+        assert_eq!(resp.status(), StatusCode::PayloadTooLarge);
+        let error = serde_json::from_str::<ErrorMessage>(&read_body(resp.body()).wait().unwrap()).unwrap();
+        assert_eq!(error.payload, Some(serde_json::from_str("{\"limit\": 4}").unwrap()));
+    }
+
+    struct Endless;
+
+    impl ResponseController for Endless {
+        fn call(&self, _req: Request) -> ResponseFuture {
+            let chunks = stream::repeat(vec![0; 1024 * 1024]);
+            Box::new(future::ok(
+                ControllerResponse::new(StatusCode::Ok).with_stream(mime::TEXT_PLAIN, chunks),
+            ))
+        }
+    }
+
+    #[test]
+    fn test_stream_buffer_limit() {
+        let app = Application::<TestError>::new(Endless);
+        let resp = app.call(Request::new(Get, "/export".parse().unwrap())).wait().unwrap();
+        assert_eq!(resp.status(), StatusCode::InternalServerError);
+    }
+
+    #[test]
+    fn test_body_log() {
+        assert_eq!(body_log(b"{}"), "{}");
+        assert_eq!(body_log(&[0xff, 0xfe]), "`2 bytes of binary data`");
+
+        let mut long = "a".repeat(DEBUG_BODY_LOG_LIMIT - 1);
+        long.push('ж');
+        assert_eq!(body_log(long.as_bytes()), format!("{}... `{} bytes total`", "a".repeat(DEBUG_BODY_LOG_LIMIT - 1), DEBUG_BODY_LOG_LIMIT + 1));
+    }
+}
@@ -0,0 +1,300 @@
+//! Cross-origin resource sharing.
+//!
+//! `CorsPolicy` answers preflight requests itself and adds CORS headers to responses to requests from allowed origins.
+//! Responses to other origins get no CORS headers, so browsers do not expose them to the calling page.
+use std::str;
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::future;
+use hyper::header::{
+    AccessControlAllowCredentials, AccessControlAllowMethods, AccessControlAllowOrigin, AccessControlMaxAge, AccessControlRequestHeaders,
+    AccessControlRequestMethod, ContentLength, Headers,
+};
+use hyper::{Method, Request, Response, StatusCode};
+
+use middleware::{Flow, Middleware, MiddlewareFuture, RequestHead};
+
+#[derive(Clone, Debug, Fail)]
+pub enum CorsError {
+    #[fail(display = "CORS credentials cannot be allowed for any origin")]
+    CredentialsForAnyOrigin,
+}
+
+#[derive(Clone)]
+pub enum AllowedOrigins {
+    Any,
+    /// Exact origins, e.g. `https://storiqa.com`
+    List(Vec<String>),
+    Predicate(Arc<Fn(&str) -> bool + Send + Sync>),
+}
+
+/// CORS middleware. Add it before other middlewares, so that their responses get CORS headers too.
+#[derive(Clone)]
+pub struct CorsPolicy {
+    origins: AllowedOrigins,
+    methods: Vec<Method>,
+    allowed_headers: Option<Vec<String>>,
+    exposed_headers: Vec<String>,
+    credentials: bool,
+    max_age: Option<u32>,
+}
+
+impl Default for CorsPolicy {
+    fn default() -> Self {
+        Self::new(AllowedOrigins::Any)
+    }
+}
+
+impl CorsPolicy {
+    /// Allows GET, POST, PUT, DELETE and OPTIONS with any request headers, without credentials
+    pub fn new(origins: AllowedOrigins) -> Self {
+        CorsPolicy {
+            origins,
+            methods: vec![Method::Get, Method::Post, Method::Put, Method::Delete, Method::Options],
+            allowed_headers: None,
+            exposed_headers: vec![],
+            credentials: false,
+            max_age: None,
+        }
+    }
+
+    pub fn with_methods(self, methods: Vec<Method>) -> Self {
+        CorsPolicy { methods, ..self }
+    }
+
+    /// Restricts request headers to `headers`. By default any requested headers are allowed.
+    pub fn with_allowed_headers(self, headers: Vec<String>) -> Self {
+        CorsPolicy {
+            allowed_headers: Some(headers.into_iter().map(|header| header.to_lowercase()).collect()),
+            ..self
+        }
+    }
+
+    /// Response headers readable by the calling page besides the simple ones
+    pub fn with_exposed_headers(self, headers: Vec<String>) -> Self {
+        CorsPolicy {
+            exposed_headers: headers,
+            ..self
+        }
+    }
+
+    /// Allows cookies and `Authorization`. Origins are then echoed instead of `*`, as browsers require.
+    ///
+    /// Fails with `AllowedOrigins::Any`, which would let any site make requests on behalf of the user.
+    pub fn with_credentials(self) -> Result<Self, CorsError> {
+        if let AllowedOrigins::Any = self.origins {
+            return Err(CorsError::CredentialsForAnyOrigin);
+        }
+        Ok(CorsPolicy { credentials: true, ..self })
+    }
+
+    /// How long browsers may cache preflight responses, up to `u32::MAX` seconds
+    pub fn with_max_age(self, max_age: Duration) -> Self {
+        CorsPolicy {
+            max_age: Some(max_age.as_secs().min(u64::from(u32::MAX)) as u32),
+            ..self
+        }
+    }
+
+    fn allow_origin(&self, origin: &str) -> Option<AccessControlAllowOrigin> {
+        let allowed = match self.origins {
+            AllowedOrigins::Any => return Some(AccessControlAllowOrigin::Any),
+            AllowedOrigins::List(ref origins) => origins.iter().any(|allowed| allowed == origin),
+            AllowedOrigins::Predicate(ref predicate) => predicate(origin),
+        };
+
+        if allowed {
+            Some(AccessControlAllowOrigin::Value(origin.to_string()))
+        } else {
+            None
+        }
+    }
+
+    /// Sets headers common to preflight and actual responses if `origin` is allowed
+    fn set_origin_headers(&self, origin: &str, headers: &mut Headers) -> bool {
+        let allow_origin = match self.allow_origin(origin) {
+            Some(allow_origin) => allow_origin,
+            None => {
+                headers.append_raw("Vary", "Origin");
+                return false;
+            }
+        };
+
+        if allow_origin != AccessControlAllowOrigin::Any {
+            headers.append_raw("Vary", "Origin");
+        }
+        headers.set(allow_origin);
+        if self.credentials {
+            headers.set(AccessControlAllowCredentials);
+        }
+
+        true
+    }
+
+    fn preflight_response(&self, origin: &str, req: &Request) -> Response {
+        let mut resp = Response::new().with_status(StatusCode::NoContent).with_header(ContentLength(0));
+
+        let method_allowed = req
+            .headers()
+            .get::<AccessControlRequestMethod>()
+            .map(|method| self.methods.contains(&method.0))
+            .unwrap_or(false);
+        let requested_headers = req
+            .headers()
+            .get::<AccessControlRequestHeaders>()
+            .map(|headers| headers.iter().map(|header| header.to_lowercase()).collect::<Vec<_>>())
+            .unwrap_or_default();
+        let headers_allowed = match self.allowed_headers {
+            Some(ref allowed) => requested_headers.iter().all(|header| allowed.contains(header)),
+            None => true,
+        };
+
+        if !method_allowed || !headers_allowed || !self.set_origin_headers(origin, resp.headers_mut()) {
+            return resp;
+        }
+
+        let headers = resp.headers_mut();
+        headers.set(AccessControlAllowMethods(self.methods.clone()));
+        let allowed_headers = self.allowed_headers.as_ref().unwrap_or(&requested_headers);
+        if !allowed_headers.is_empty() {
+            headers.set_raw("Access-Control-Allow-Headers", allowed_headers.join(", "));
+        }
+        if let Some(max_age) = self.max_age {
+            headers.set(AccessControlMaxAge(max_age));
+        }
+
+        resp
+    }
+}
+
+fn origin(headers: &Headers) -> Option<&str> {
+    headers
+        .get_raw("Origin")
+        .and_then(|raw| raw.one())
+        .and_then(|value| str::from_utf8(value).ok())
+}
+
+impl Middleware for CorsPolicy {
+    fn before(&self, req: Request) -> MiddlewareFuture<Flow> {
+        let preflight = *req.method() == Method::Options && req.headers().has::<AccessControlRequestMethod>();
+        let flow = match origin(req.headers()) {
+            Some(origin) if preflight => Flow::Respond(self.preflight_response(origin, &req)),
+            _ => Flow::Continue(req),
+        };
+
+        Box::new(future::ok(flow))
+    }
+
+    fn after(&self, head: &RequestHead, mut resp: Response) -> MiddlewareFuture<Response> {
+        let preflight = head.method == Method::Options && head.headers.has::<AccessControlRequestMethod>();
+        if let Some(origin) = origin(&head.headers) {
+            if !preflight && self.set_origin_headers(origin, resp.headers_mut()) && !self.exposed_headers.is_empty() {
+                resp.headers_mut()
+                    .set_raw("Access-Control-Expose-Headers", self.exposed_headers.join(", "));
+            }
+        }
+
+        Box::new(future::ok(resp))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::Future;
+
+    fn preflight(policy: &CorsPolicy, origin: &str, method: Method, headers: &str) -> Response {
+        let mut req = Request::new(Method::Options, "/roles/1".parse().unwrap());
+        req.headers_mut().set_raw("Origin", origin.to_string());
+        req.headers_mut().set(AccessControlRequestMethod(method));
+        if !headers.is_empty() {
+            req.headers_mut().set_raw("Access-Control-Request-Headers", headers.to_string());
+        }
+
+        match policy.before(req).wait().unwrap() {
+            Flow::Respond(resp) => resp,
+            Flow::Continue(_) => panic!("Preflight request was passed on"),
+        }
+    }
+
+    fn actual(policy: &CorsPolicy, origin: &str) -> Response {
+        let mut req = Request::new(Method::Get, "/roles/1".parse().unwrap());
+        req.headers_mut().set_raw("Origin", origin.to_string());
+        let head = RequestHead::from(&req);
+
+        match policy.before(req).wait().unwrap() {
+            Flow::Continue(_) => policy.after(&head, Response::new()).wait().unwrap(),
+            Flow::Respond(_) => panic!("Request was not passed on"),
+        }
+    }
+
+    #[test]
+    fn test_preflight() {
+        let policy = CorsPolicy::new(AllowedOrigins::List(vec!["https://storiqa.com".to_string()]))
+            .with_allowed_headers(vec!["Authorization".to_string(), "Content-Type".to_string()])
+            .with_credentials()
+            .unwrap()
+            .with_max_age(Duration::from_secs(600));
+
+        let resp = preflight(&policy, "https://storiqa.com", Method::Delete, "content-type");
+        assert_eq!(resp.status(), StatusCode::NoContent);
+        assert_eq!(
+            resp.headers().get::<AccessControlAllowOrigin>(),
+            Some(&AccessControlAllowOrigin::Value("https://storiqa.com".to_string()))
+        );
+        assert!(resp.headers().has::<AccessControlAllowCredentials>());
+        assert_eq!(resp.headers().get::<AccessControlMaxAge>(), Some(&AccessControlMaxAge(600)));
+        assert_eq!(
+            resp.headers().get_raw("Access-Control-Allow-Headers").unwrap(),
+            "authorization, content-type"
+        );
+        assert!(resp.headers().get::<AccessControlAllowMethods>().unwrap().contains(&Method::Delete));
+
+        let denied = vec![
+            preflight(&policy, "https://evil.com", Method::Delete, ""),
+            preflight(&policy, "https://storiqa.com", Method::Patch, ""),
+            preflight(&policy, "https://storiqa.com", Method::Get, "x-custom"),
+        ];
+        for resp in denied {
+            assert!(!resp.headers().has::<AccessControlAllowOrigin>());
+            assert!(!resp.headers().has::<AccessControlAllowMethods>());
+        }
+    }
+
+    #[test]
+    fn test_actual_request() {
+        let policy = CorsPolicy::default().with_exposed_headers(vec!["Retry-After".to_string()]);
+        let resp = actual(&policy, "https://storiqa.com");
+        assert_eq!(
+            resp.headers().get::<AccessControlAllowOrigin>(),
+            Some(&AccessControlAllowOrigin::Any)
+        );
+        assert_eq!(resp.headers().get_raw("Access-Control-Expose-Headers").unwrap(), "Retry-After");
+        assert!(!resp.headers().has::<AccessControlAllowCredentials>());
+
+        let policy = CorsPolicy::new(AllowedOrigins::Predicate(Arc::new(|origin| origin.ends_with(".storiqa.com"))));
+        let resp = actual(&policy, "https://admin.storiqa.com");
+        assert_eq!(
+            resp.headers().get::<AccessControlAllowOrigin>(),
+            Some(&AccessControlAllowOrigin::Value("https://admin.storiqa.com".to_string()))
+        );
+        assert_eq!(resp.headers().get_raw("Vary").unwrap(), "Origin");
+
+        let resp = actual(&policy, "https://storiqa.com.evil.com");
+        assert!(!resp.headers().has::<AccessControlAllowOrigin>());
+    }
+
+    #[test]
+    fn test_credentials_for_any_origin() {
+        let err = CorsPolicy::default().with_credentials().err().unwrap();
+        assert_eq!(err.to_string(), "CORS credentials cannot be allowed for any origin");
+    }
+
+    #[test]
+    fn test_max_age_overflow() {
+        let policy = CorsPolicy::default().with_max_age(Duration::from_secs(u64::from(u32::MAX) + 1));
+        let resp = preflight(&policy, "https://storiqa.com", Method::Get, "");
+        assert_eq!(resp.headers().get::<AccessControlMaxAge>(), Some(&AccessControlMaxAge(u32::MAX)));
+    }
+}
@@ -1,7 +1,11 @@
 use failure::{Context, Error, Fail};
 use hyper::StatusCode;
-use serde_json::Value;
+use serde_json::{self, Value};
 use std;
+#[cfg(feature = "acl")]
+use stq_acl::UnauthorizedError;
+
+use request_util::{BodyTooLarge, ParseError};
 
 pub trait Codeable {
     fn code(&self) -> StatusCode;
@@ -11,6 +15,92 @@ pub trait PayloadCarrier {
     fn payload(&self) -> Option<Value>;
 }
 
+#[cfg(feature = "acl")]
+impl Codeable for UnauthorizedError {
+    fn code(&self) -> StatusCode {
+        StatusCode::Forbidden
+    }
+}
+
+/// Explanation of the denial, if the ACL engine provided one
+#[cfg(feature = "acl")]
+impl PayloadCarrier for UnauthorizedError {
+    fn payload(&self) -> Option<Value> {
+        self.explanation.as_ref().and_then(|explanation| serde_json::to_value(explanation).ok())
+    }
+}
+
+impl Codeable for BodyTooLarge {
+    fn code(&self) -> StatusCode {
+        StatusCode::PayloadTooLarge
+    }
+}
+
+impl PayloadCarrier for BodyTooLarge {
+    fn payload(&self) -> Option<Value> {
+        let mut payload = serde_json::Map::new();
+        payload.insert("limit".to_string(), self.limit.into());
+        Some(payload.into())
+    }
+}
+
+impl Codeable for ParseError {
+    fn code(&self) -> StatusCode {
+        match *self {
+            ParseError::ConvertError => StatusCode::UnprocessableEntity,
+            ParseError::UnsupportedContentType { .. } => StatusCode::UnsupportedMediaType,
+            _ => StatusCode::BadRequest,
+        }
+    }
+}
+
+/// Location of the invalid value
+impl PayloadCarrier for ParseError {
+    fn payload(&self) -> Option<Value> {
+        let mut payload = serde_json::Map::new();
+        match *self {
+            ParseError::InvalidJson {
+                ref path, line, column, ..
+            } => {
+                payload.insert("path".to_string(), path.clone().into());
+                payload.insert("line".to_string(), line.into());
+                payload.insert("column".to_string(), column.into());
+            }
+            ParseError::InvalidForm { ref path, .. } => {
+                payload.insert("path".to_string(), path.clone().into());
+            }
+            _ => return None,
+        }
+        Some(payload.into())
+    }
+}
+
+/// Code and payload of the first error of type `T` in the chain
+fn code_and_payload<T>(e: &Error) -> Option<(u16, Option<Value>)>
+where
+    T: Fail + Codeable + PayloadCarrier,
+{
+    e.iter_chain()
+        .filter_map(|cause| {
+            cause
+                .downcast_ref::<Context<T>>()
+                .map(|ctx| ctx.get_context())
+                .or_else(|| cause.downcast_ref::<T>())
+        })
+        .next()
+        .map(|e| (e.code().as_u16(), e.payload()))
+}
+
+#[cfg(feature = "acl")]
+fn acl_code_and_payload(e: &Error) -> Option<(u16, Option<Value>)> {
+    code_and_payload::<UnauthorizedError>(e)
+}
+
+#[cfg(not(feature = "acl"))]
+fn acl_code_and_payload(_e: &Error) -> Option<(u16, Option<Value>)> {
+    None
+}
+
 pub struct ErrorMessageWrapper<E: Fail + Codeable> {
     pub inner: ErrorMessage,
     _type: std::marker::PhantomData<E>,
@@ -36,7 +126,7 @@ where
             acc
         });
 
-        let mut code = 500;
+        let mut code = None;
         let mut payload = None;
 
         for cause in e.iter_chain() {
@@ -47,12 +137,28 @@ where
             };
 
             if let Some(e) = real_err {
-                code = e.code().as_u16();
+                code = Some(e.code().as_u16());
                 payload = e.payload();
                 break;
             }
         }
 
+        // Errors of request handling and, with the `acl` feature, ACL denials describe themselves
+        // unless the application error does
+        let fallback = code_and_payload::<BodyTooLarge>(e)
+            .or_else(|| code_and_payload::<ParseError>(e))
+            .or_else(|| acl_code_and_payload(e));
+        if let Some((fallback_code, fallback_payload)) = fallback {
+            if code.is_none() {
+                code = Some(fallback_code);
+            }
+            if payload.is_none() {
+                payload = fallback_payload;
+            }
+        }
+
+        let code = code.unwrap_or(500);
+
         Self {
             inner: ErrorMessage {
                 code,
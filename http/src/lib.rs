@@ -1,6 +1,7 @@
 #[macro_use]
 extern crate failure;
 extern crate futures;
+extern crate futures_cpupool;
 #[macro_use]
 extern crate hyper;
 extern crate hyper_tls;
@@ -8,17 +9,26 @@ extern crate hyper_tls;
 extern crate juniper;
 #[macro_use]
 extern crate log;
+#[macro_use]
 extern crate serde;
 #[macro_use]
 extern crate serde_derive;
 extern crate chrono;
 extern crate serde_json;
+#[cfg(feature = "acl")]
+extern crate stq_acl;
+extern crate stq_cache;
 extern crate tokio_core;
+extern crate url;
 extern crate validator;
 
+pub mod body_parser;
 pub mod client;
 pub mod controller;
+pub mod cors;
 pub mod errors;
+pub mod middleware;
 pub mod query_util;
+pub mod rate_limit;
 pub mod request_util;
 pub mod system;
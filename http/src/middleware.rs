@@ -0,0 +1,238 @@
+//! Request middleware for `controller::Application`.
+//!
+//! Middlewares are called in the order they were added: `before` hooks on the way to the controller,
+//! `after` hooks in reverse order on the way back. A middleware that responds in `before` stops the request,
+//! and its response only passes through the `after` hooks of the middlewares that have already seen the request.
+//! A middleware or handler that fails stops the request too: the error is turned into a response,
+//! which passes through the `after` hooks of the middlewares that have already seen the request, e.g. to get CORS headers.
+use failure;
+use futures::future;
+use futures::prelude::*;
+use hyper::{Headers, HttpVersion, Method, Request, Response, Uri};
+use std::sync::Arc;
+
+pub type MiddlewareFuture<T> = Box<Future<Item = T, Error = failure::Error>>;
+
+pub enum Flow {
+    /// Passes the request on
+    Continue(Request),
+    /// Responds without calling the rest of the chain
+    Respond(Response),
+}
+
+/// Request line and headers of the original request, for `after` hooks
+#[derive(Clone, Debug)]
+pub struct RequestHead {
+    pub method: Method,
+    pub uri: Uri,
+    pub version: HttpVersion,
+    pub headers: Headers,
+}
+
+impl<'a> From<&'a Request> for RequestHead {
+    fn from(req: &'a Request) -> Self {
+        RequestHead {
+            method: req.method().clone(),
+            uri: req.uri().clone(),
+            version: req.version(),
+            headers: req.headers().clone(),
+        }
+    }
+}
+
+pub trait Middleware {
+    fn before(&self, req: Request) -> MiddlewareFuture<Flow> {
+        Box::new(future::ok(Flow::Continue(req)))
+    }
+
+    fn after(&self, _head: &RequestHead, resp: Response) -> MiddlewareFuture<Response> {
+        Box::new(future::ok(resp))
+    }
+}
+
+pub type Handler = Arc<Fn(Request) -> MiddlewareFuture<Response>>;
+
+/// Turns errors of middlewares and the handler into responses
+pub type ErrorRenderer = Arc<Fn(&failure::Error) -> Response>;
+
+/// Runs `req` through `middlewares` starting from `index`, with `handler` at the end of the chain.
+/// Fails only if the middleware at `index` fails, since errors of the rest of the chain are rendered with `render_error`.
+pub fn run_chain(
+    middlewares: Arc<Vec<Arc<Middleware>>>,
+    index: usize,
+    head: Arc<RequestHead>,
+    req: Request,
+    handler: Handler,
+    render_error: ErrorRenderer,
+) -> MiddlewareFuture<Response> {
+    let middleware = match middlewares.get(index) {
+        Some(middleware) => middleware.clone(),
+        None => return handler(req),
+    };
+
+    Box::new(
+        middleware
+            .before(req)
+            .and_then({
+                let head = head.clone();
+                move |flow| match flow {
+                    Flow::Continue(req) => Box::new(
+                        run_chain(middlewares, index + 1, head, req, handler, render_error.clone())
+                            .or_else(move |e| future::ok(render_error(&e))),
+                    ) as MiddlewareFuture<Response>,
+                    Flow::Respond(resp) => Box::new(future::ok(resp)),
+                }
+            })
+            .and_then(move |resp| middleware.after(&head, resp)),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::StatusCode;
+    use std::sync::Mutex;
+
+    fn render_error() -> ErrorRenderer {
+        Arc::new(|e| {
+            Response::new()
+                .with_status(StatusCode::InternalServerError)
+                .with_body(e.to_string())
+        })
+    }
+
+    type Log = Arc<Mutex<Vec<String>>>;
+
+    struct Recorder {
+        name: &'static str,
+        log: Log,
+        respond: bool,
+    }
+
+    impl Middleware for Recorder {
+        fn before(&self, req: Request) -> MiddlewareFuture<Flow> {
+            self.log.lock().unwrap().push(format!("before {}", self.name));
+            Box::new(future::ok(if self.respond {
+                Flow::Respond(Response::new().with_status(StatusCode::Forbidden))
+            } else {
+                Flow::Continue(req)
+            }))
+        }
+
+        fn after(&self, head: &RequestHead, resp: Response) -> MiddlewareFuture<Response> {
+            self.log.lock().unwrap().push(format!("after {} {}", self.name, head.uri.path()));
+            Box::new(future::ok(resp))
+        }
+    }
+
+    fn run(respond: &[bool], log: &Log) -> Response {
+        let middlewares = respond
+            .iter()
+            .zip(&["a", "b", "c"])
+            .map(|(respond, name)| {
+                Arc::new(Recorder {
+                    name,
+                    log: log.clone(),
+                    respond: *respond,
+                }) as Arc<Middleware>
+            })
+            .collect::<Vec<_>>();
+        let handler: Handler = {
+            let log = log.clone();
+            Arc::new(move |_| {
+                log.lock().unwrap().push("handler".to_string());
+                Box::new(future::ok(Response::new()))
+            })
+        };
+
+        let req = Request::new(Method::Get, "/users".parse().unwrap());
+        let head = Arc::new(RequestHead::from(&req));
+        run_chain(Arc::new(middlewares), 0, head, req, handler, render_error())
+            .wait()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_order() {
+        let log = Log::default();
+        let resp = run(&[false, false], &log);
+
+        assert_eq!(resp.status(), StatusCode::Ok);
+        assert_eq!(
+            *log.lock().unwrap(),
+            vec!["before a", "before b", "handler", "after b /users", "after a /users"]
+        );
+    }
+
+    #[test]
+    fn test_early_response() {
+        let log = Log::default();
+        let resp = run(&[false, true, false], &log);
+
+        assert_eq!(resp.status(), StatusCode::Forbidden);
+        assert_eq!(*log.lock().unwrap(), vec!["before a", "before b", "after b /users", "after a /users"]);
+    }
+
+    struct Failing;
+
+    impl Middleware for Failing {
+        fn before(&self, _req: Request) -> MiddlewareFuture<Flow> {
+            Box::new(future::err(format_err!("Backend is unavailable")))
+        }
+    }
+
+    fn recorder(name: &'static str, log: &Log) -> Arc<Middleware> {
+        Arc::new(Recorder {
+            name,
+            log: log.clone(),
+            respond: false,
+        })
+    }
+
+    #[test]
+    fn test_failure() {
+        let log = Log::default();
+        let middlewares: Vec<Arc<Middleware>> = vec![recorder("a", &log), Arc::new(Failing), recorder("b", &log)];
+        let handler: Handler = Arc::new(|_| panic!("Handler was called"));
+
+        let req = Request::new(Method::Get, "/users".parse().unwrap());
+        let head = Arc::new(RequestHead::from(&req));
+        let resp = run_chain(Arc::new(middlewares), 0, head, req, handler, render_error())
+            .wait()
+            .unwrap();
+
+        // Error response passes through the middlewares that have seen the request
+        assert_eq!(resp.status(), StatusCode::InternalServerError);
+        assert_eq!(*log.lock().unwrap(), vec!["before a", "after a /users"]);
+
+        // Failure of the first middleware is left to the caller
+        let req = Request::new(Method::Get, "/users".parse().unwrap());
+        let head = Arc::new(RequestHead::from(&req));
+        let handler: Handler = Arc::new(|_| panic!("Handler was called"));
+        let middlewares: Vec<Arc<Middleware>> = vec![Arc::new(Failing)];
+        let err = run_chain(Arc::new(middlewares), 0, head, req, handler, render_error())
+            .wait()
+            .err()
+            .unwrap();
+        assert_eq!(err.to_string(), "Backend is unavailable");
+    }
+
+    #[test]
+    fn test_handler_failure() {
+        let log = Log::default();
+        let middlewares = vec![recorder("a", &log), recorder("b", &log)];
+        let handler: Handler = Arc::new(|_| Box::new(future::err(format_err!("Controller failed"))));
+
+        let req = Request::new(Method::Get, "/users".parse().unwrap());
+        let head = Arc::new(RequestHead::from(&req));
+        let resp = run_chain(Arc::new(middlewares), 0, head, req, handler, render_error())
+            .wait()
+            .unwrap();
+
+        assert_eq!(resp.status(), StatusCode::InternalServerError);
+        assert_eq!(
+            *log.lock().unwrap(),
+            vec!["before a", "before b", "after b /users", "after a /users"]
+        );
+    }
+}
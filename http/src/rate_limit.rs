@@ -0,0 +1,451 @@
+//! Per-caller rate limiting with sliding window counters stored in a cache.
+//!
+//! Callers are identified by the `Authorization` header, then by `Session-Id`, then by IP address.
+//! Header values are hashed before being used in cache keys. Requests of a caller are counted in fixed windows,
+//! and the count of the previous window is weighted by how much of it still overlaps the sliding window.
+//! Rejected requests are counted too, so clients that keep retrying stay limited.
+//! `Retry-After` tells when a single retry would be allowed, provided the caller makes no other requests.
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::str;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use futures::future;
+use futures::Future;
+use futures_cpupool::CpuPool;
+use hyper::header::{ContentLength, ContentType, RetryAfter};
+use hyper::{mime, Request, Response, StatusCode};
+use serde_json;
+
+use stq_cache::cache::AtomicCache;
+
+use errors::ErrorMessage;
+use middleware::{Flow, Middleware, MiddlewareFuture};
+use request_util::SessionId;
+
+const DEFAULT_KEY_PREFIX: &str = "rate_limit";
+
+fn as_millis(d: Duration) -> u64 {
+    d.as_secs() * 1000 + u64::from(d.subsec_millis())
+}
+
+/// Tells if `path` is `prefix` or below it, matching whole segments, so that `/orders` does not match `/ordersX`
+fn matches_route(path: &str, prefix: &str) -> bool {
+    path.starts_with(prefix) && (path.len() == prefix.len() || prefix.ends_with('/') || path[prefix.len()..].starts_with('/'))
+}
+
+fn hash(s: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    s.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// At most `requests` per `window`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RateLimit {
+    pub requests: u64,
+    pub window: Duration,
+}
+
+impl RateLimit {
+    pub fn new(requests: u64, window: Duration) -> Self {
+        RateLimit { requests, window }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RateLimitDecision {
+    Allowed,
+    Limited { retry_after: Duration },
+}
+
+/// Window of a caller on a route at the time of a request
+#[derive(Clone, Debug)]
+struct Window {
+    /// Key of the counters, without the window index
+    key: String,
+    limit: u64,
+    /// Window length in milliseconds
+    length: u64,
+    index: u64,
+    /// Milliseconds since the start of the window
+    elapsed: u64,
+}
+
+impl Window {
+    fn counter_key(&self, index: u64) -> String {
+        format!("{}:{}", self.key, index)
+    }
+
+    /// Counts the request and decides on it, making blocking cache calls
+    fn check<C>(&self, cache: &C, ttl: Duration) -> RateLimitDecision
+    where
+        C: AtomicCache<String>,
+    {
+        let current = match cache.incr_by(&self.counter_key(self.index), 1, Some(ttl)) {
+            Ok(count) => count.max(0) as u64,
+            Err(e) => {
+                warn!("Failed to count request for rate limiting: {}", e);
+                return RateLimitDecision::Allowed;
+            }
+        };
+        let previous = match cache.get(&self.counter_key(self.index.saturating_sub(1))) {
+            Ok(count) => count.and_then(|count| count.parse::<u64>().ok()).unwrap_or(0),
+            Err(e) => {
+                warn!("Failed to read request count for rate limiting: {}", e);
+                0
+            }
+        };
+
+        if self.weighted(previous, current) <= self.limit {
+            RateLimitDecision::Allowed
+        } else {
+            RateLimitDecision::Limited {
+                retry_after: Duration::from_millis(self.retry_after(previous, current)),
+            }
+        }
+    }
+
+    fn weighted(&self, previous: u64, current: u64) -> u64 {
+        previous * (self.length - self.elapsed) / self.length + current
+    }
+
+    /// Milliseconds until one more request would be allowed, if no other requests are made
+    fn retry_after(&self, previous: u64, current: u64) -> u64 {
+        let (limit, length, remaining) = (self.limit, self.length, self.length - self.elapsed);
+
+        // Later in this window the previous one weighs less, but the retry adds to `current`.
+        // It is allowed once at most `max_left` milliseconds of the window are left.
+        if current < limit && previous > 0 {
+            let max_left = ((limit - current) * length - 1) / previous;
+            if max_left > 0 && remaining > max_left {
+                return remaining - max_left;
+            }
+        }
+
+        // In the next window, requests of this one are the previous ones and the retry is the only current one
+        if limit == 0 {
+            return remaining;
+        }
+        let max_left = (limit * length - 1) / current.max(1);
+        remaining + length.saturating_sub(max_left)
+    }
+}
+
+/// 429 response with `Retry-After` and an `ErrorMessage` body
+pub fn too_many_requests(retry_after: Duration) -> Response {
+    let secs = retry_after.as_secs() + if retry_after.subsec_nanos() > 0 { 1 } else { 0 };
+    let mut payload = serde_json::Map::new();
+    payload.insert("retry_after".to_string(), secs.into());
+    let body = serde_json::to_string(&ErrorMessage {
+        code: StatusCode::TooManyRequests.as_u16(),
+        description: "Too many requests".to_string(),
+        payload: Some(payload.into()),
+    })
+    .unwrap_or_default();
+
+    Response::new()
+        .with_status(StatusCode::TooManyRequests)
+        .with_header(RetryAfter::Delay(Duration::from_secs(secs)))
+        .with_header(ContentLength(body.len() as u64))
+        .with_header(ContentType(mime::APPLICATION_JSON))
+        .with_body(body)
+}
+
+/// Rate limiter over a cache shared by all instances of the service, e.g. `RedisCache`.
+/// Requests of unidentifiable callers and requests failing on the cache are let through.
+///
+/// As a middleware, it calls the cache on a thread pool, so that the reactor is not blocked.
+#[derive(Clone, Debug)]
+pub struct RateLimiter<C> {
+    cache: Arc<C>,
+    pool: CpuPool,
+    key_prefix: String,
+    default_limit: Option<RateLimit>,
+    routes: Vec<(String, RateLimit)>,
+    forwarded_for: bool,
+}
+
+impl<C> RateLimiter<C>
+where
+    C: AtomicCache<String>,
+{
+    /// Limits every request to `default_limit` unless a route limit applies.
+    /// Middleware checks call the cache on `pool`, which can be shared with other blocking work of the service.
+    pub fn new(cache: C, pool: CpuPool, default_limit: RateLimit) -> Self {
+        RateLimiter {
+            cache: Arc::new(cache),
+            pool,
+            key_prefix: DEFAULT_KEY_PREFIX.to_string(),
+            default_limit: Some(default_limit),
+            routes: vec![],
+            forwarded_for: false,
+        }
+    }
+
+    /// Limits only requests to routes with a limit
+    pub fn without_default_limit(self) -> Self {
+        RateLimiter {
+            default_limit: None,
+            ..self
+        }
+    }
+
+    /// Limits requests to `path_prefix` and the paths below it, e.g. `/orders` and `/orders/1` but not `/orders_archive`.
+    /// The first matching route applies, and all paths of a route share the counters of a caller.
+    pub fn with_route_limit(mut self, path_prefix: &str, limit: RateLimit) -> Self {
+        self.routes.push((path_prefix.to_string(), limit));
+        self
+    }
+
+    pub fn with_key_prefix(self, key_prefix: String) -> Self {
+        RateLimiter { key_prefix, ..self }
+    }
+
+    /// Identifies anonymous callers by the first address in `X-Forwarded-For` instead of the peer address.
+    /// Only for services behind a proxy that sets the header, since clients can forge it.
+    pub fn with_forwarded_for(self) -> Self {
+        RateLimiter {
+            forwarded_for: true,
+            ..self
+        }
+    }
+
+    fn caller_key(&self, req: &Request) -> Option<String> {
+        if let Some(authorization) = req.headers().get_raw("Authorization").and_then(|raw| raw.one()) {
+            return Some(format!("auth:{:x}", hash(authorization)));
+        }
+
+        if let Some(session_id) = req.headers().get::<SessionId>() {
+            return Some(format!("session:{:x}", hash(session_id.0.as_bytes())));
+        }
+
+        let forwarded_for = if self.forwarded_for {
+            req.headers()
+                .get_raw("X-Forwarded-For")
+                .and_then(|raw| raw.one())
+                .and_then(|value| str::from_utf8(value).ok())
+                .and_then(|value| value.split(',').next())
+                .map(|ip| ip.trim().to_string())
+                .filter(|ip| !ip.is_empty())
+        } else {
+            None
+        };
+
+        #[allow(deprecated)]
+        let remote_addr = req.remote_addr().map(|addr| addr.ip().to_string());
+
+        forwarded_for.or(remote_addr).map(|ip| format!("ip:{}", ip))
+    }
+
+    fn route_limit(&self, path: &str) -> Option<(&str, RateLimit)> {
+        self.routes
+            .iter()
+            .find(|(prefix, _)| matches_route(path, prefix))
+            .map(|(prefix, limit)| (prefix.as_str(), *limit))
+            .or_else(|| self.default_limit.map(|limit| ("*", limit)))
+    }
+
+    /// Checks the request making blocking cache calls, so it should not be called on the reactor thread
+    pub fn check(&self, req: &Request) -> RateLimitDecision {
+        self.check_at(req, SystemTime::now())
+    }
+
+    fn check_at(&self, req: &Request, now: SystemTime) -> RateLimitDecision {
+        match self.window(req, now) {
+            Some((window, ttl)) => window.check(&*self.cache, ttl),
+            None => RateLimitDecision::Allowed,
+        }
+    }
+
+    /// Window of the caller with the TTL of its counters, if the request is limited
+    fn window(&self, req: &Request, now: SystemTime) -> Option<(Window, Duration)> {
+        let (route, limit) = self.route_limit(req.path())?;
+        let caller = self.caller_key(req)?;
+
+        let length = as_millis(limit.window).max(1);
+        let now = as_millis(now.duration_since(UNIX_EPOCH).unwrap_or_default());
+        let window = Window {
+            key: format!("{}:{}:{}", self.key_prefix, route, caller),
+            limit: limit.requests,
+            length,
+            index: now / length,
+            elapsed: now % length,
+        };
+        Some((window, limit.window * 2))
+    }
+}
+
+/// Rejects requests over the limit with 429 Too Many Requests
+impl<C> Middleware for RateLimiter<C>
+where
+    C: AtomicCache<String> + Send + Sync + 'static,
+{
+    fn before(&self, req: Request) -> MiddlewareFuture<Flow> {
+        let (window, ttl) = match self.window(&req, SystemTime::now()) {
+            Some(window) => window,
+            None => return Box::new(future::ok(Flow::Continue(req))),
+        };
+
+        let cache = self.cache.clone();
+        Box::new(
+            self.pool
+                .spawn_fn(move || Ok(window.check(&*cache, ttl)))
+                .map(move |decision| match decision {
+                    RateLimitDecision::Allowed => Flow::Continue(req),
+                    RateLimitDecision::Limited { retry_after } => Flow::Respond(too_many_requests(retry_after)),
+                }),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::header::Authorization;
+    use hyper::Method;
+    use stq_cache::cache::InMemoryCache;
+
+    fn request(path: &str, session_id: &str) -> Request {
+        let mut req = Request::new(Method::Get, path.parse().unwrap());
+        req.headers_mut().set(SessionId(session_id.to_string()));
+        req
+    }
+
+    fn limiter() -> RateLimiter<InMemoryCache<String>> {
+        RateLimiter::new(InMemoryCache::new(), CpuPool::new(1), RateLimit::new(2, Duration::from_secs(60)))
+    }
+
+    fn at(secs: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn test_limits_callers_separately() {
+        let limiter = limiter();
+
+        assert_eq!(limiter.check_at(&request("/users", "1"), at(600)), RateLimitDecision::Allowed);
+        assert_eq!(limiter.check_at(&request("/stores", "1"), at(610)), RateLimitDecision::Allowed);
+        assert_ne!(limiter.check_at(&request("/users", "1"), at(620)), RateLimitDecision::Allowed);
+        assert_eq!(limiter.check_at(&request("/users", "2"), at(620)), RateLimitDecision::Allowed);
+
+        let mut authorized = request("/users", "1");
+        authorized.headers_mut().set(Authorization("token".to_string()));
+        assert_eq!(limiter.check_at(&authorized, at(620)), RateLimitDecision::Allowed);
+
+        // Unidentifiable callers are not limited
+        let anonymous = Request::new(Method::Get, "/users".parse().unwrap());
+        for _ in 0..3 {
+            assert_eq!(limiter.check_at(&anonymous, at(620)), RateLimitDecision::Allowed);
+        }
+    }
+
+    #[test]
+    fn test_sliding_window() {
+        let limiter = limiter();
+        limiter.check_at(&request("/users", "1"), at(600));
+        limiter.check_at(&request("/users", "1"), at(610));
+
+        // Half of the previous window still counts
+        assert_eq!(limiter.check_at(&request("/users", "1"), at(690)), RateLimitDecision::Allowed);
+        assert_ne!(limiter.check_at(&request("/users", "1"), at(690)), RateLimitDecision::Allowed);
+        assert_eq!(limiter.check_at(&request("/users", "1"), at(800)), RateLimitDecision::Allowed);
+    }
+
+    #[test]
+    fn test_route_limits() {
+        let limiter = limiter()
+            .without_default_limit()
+            .with_route_limit("/orders", RateLimit::new(1, Duration::from_secs(60)));
+
+        for _ in 0..3 {
+            assert_eq!(limiter.check_at(&request("/users", "1"), at(600)), RateLimitDecision::Allowed);
+        }
+        assert_eq!(limiter.check_at(&request("/orders/1", "1"), at(600)), RateLimitDecision::Allowed);
+        assert_ne!(limiter.check_at(&request("/orders/2", "1"), at(600)), RateLimitDecision::Allowed);
+        assert_ne!(limiter.check_at(&request("/orders", "1"), at(600)), RateLimitDecision::Allowed);
+
+        // Routes match whole path segments
+        for _ in 0..3 {
+            assert_eq!(limiter.check_at(&request("/ordersX", "1"), at(600)), RateLimitDecision::Allowed);
+        }
+    }
+
+    #[test]
+    fn test_matches_route() {
+        assert!(matches_route("/orders", "/orders"));
+        assert!(matches_route("/orders/1", "/orders"));
+        assert!(matches_route("/orders/1", "/orders/"));
+        assert!(matches_route("/orders/1", "/"));
+        assert!(!matches_route("/ordersX", "/orders"));
+        assert!(!matches_route("/order", "/orders"));
+    }
+
+    #[test]
+    fn test_too_many_requests() {
+        let response = too_many_requests(Duration::from_millis(1500));
+        assert_eq!(response.status(), StatusCode::TooManyRequests);
+        assert_eq!(
+            response.headers().get::<RetryAfter>(),
+            Some(&RetryAfter::Delay(Duration::from_secs(2)))
+        );
+    }
+
+    fn limited_for(decision: RateLimitDecision) -> Duration {
+        match decision {
+            RateLimitDecision::Limited { retry_after } => retry_after,
+            RateLimitDecision::Allowed => panic!("Request was allowed"),
+        }
+    }
+
+    #[test]
+    fn test_retry_after() {
+        // Retry falls into the next window
+        let next_window = limiter();
+        next_window.check_at(&request("/users", "1"), at(600));
+        next_window.check_at(&request("/users", "1"), at(610));
+        let retry_after = limited_for(next_window.check_at(&request("/users", "1"), at(620)));
+        assert_eq!(retry_after, Duration::from_millis(60_001));
+        assert_eq!(
+            next_window.check_at(&request("/users", "1"), at(620) + retry_after),
+            RateLimitDecision::Allowed
+        );
+
+        // Retry falls into the same window, once the previous one weighs less
+        let same_window = RateLimiter::new(InMemoryCache::new(), CpuPool::new(1), RateLimit::new(3, Duration::from_secs(60)));
+        for secs in &[600, 610, 620] {
+            same_window.check_at(&request("/users", "1"), at(*secs));
+        }
+        let retry_after = limited_for(same_window.check_at(&request("/users", "1"), at(660)));
+        assert_eq!(retry_after, Duration::from_millis(20_001));
+        assert_eq!(
+            same_window.check_at(&request("/users", "1"), at(660) + retry_after),
+            RateLimitDecision::Allowed
+        );
+
+        // Retrying earlier is rejected
+        let early = limiter();
+        early.check_at(&request("/users", "1"), at(600));
+        early.check_at(&request("/users", "1"), at(610));
+        let retry_after = limited_for(early.check_at(&request("/users", "1"), at(620)));
+        assert_ne!(
+            early.check_at(&request("/users", "1"), at(620) + retry_after - Duration::from_millis(1)),
+            RateLimitDecision::Allowed
+        );
+    }
+
+    #[test]
+    fn test_middleware() {
+        let limiter = RateLimiter::new(InMemoryCache::new(), CpuPool::new(1), RateLimit::new(1, Duration::from_secs(60)));
+
+        match limiter.before(request("/users", "1")).wait().unwrap() {
+            Flow::Continue(req) => assert_eq!(req.path(), "/users"),
+            Flow::Respond(_) => panic!("Request was rejected"),
+        }
+        match limiter.before(request("/users", "1")).wait().unwrap() {
+            Flow::Respond(resp) => assert_eq!(resp.status(), StatusCode::TooManyRequests),
+            Flow::Continue(_) => panic!("Request was let through"),
+        }
+    }
+}
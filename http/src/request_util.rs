@@ -22,6 +22,59 @@ pub enum ParseError {
     ReadError,
     #[fail(display = "Failed to convert received body")]
     ConvertError,
+    #[fail(display = "Unsupported content type \"{}\"", content_type)]
+    UnsupportedContentType { content_type: String },
+    #[fail(display = "Body is empty")]
+    EmptyBody,
+    #[fail(display = "Invalid JSON at {}: {}", path, message)]
+    InvalidJson {
+        path: String,
+        line: usize,
+        column: usize,
+        message: String,
+    },
+    #[fail(display = "Invalid form at {}: {}", path, message)]
+    InvalidForm { path: String, message: String },
+}
+
+/// Body is larger than the limit of the handler, reported as 413 Payload Too Large
+#[derive(Clone, Debug, Fail)]
+#[fail(display = "Body is larger than {} bytes", limit)]
+pub struct BodyTooLarge {
+    pub limit: usize,
+}
+
+/// Request body as a stream of chunks that fails with `BodyTooLarge` once more than `limit` bytes arrive,
+/// for handlers that process bodies without buffering them whole
+pub struct LimitedBody {
+    body: hyper::Body,
+    limit: usize,
+    read: usize,
+}
+
+impl LimitedBody {
+    pub fn new(body: hyper::Body, limit: usize) -> Self {
+        LimitedBody { body, limit, read: 0 }
+    }
+}
+
+impl Stream for LimitedBody {
+    type Item = hyper::Chunk;
+    type Error = failure::Error;
+
+    fn poll(&mut self) -> Poll<Option<hyper::Chunk>, failure::Error> {
+        match self.body.poll()? {
+            Async::NotReady => Ok(Async::NotReady),
+            Async::Ready(Some(chunk)) => {
+                self.read += chunk.len();
+                if self.read > self.limit {
+                    return Err(BodyTooLarge { limit: self.limit }.into());
+                }
+                Ok(Async::Ready(Some(chunk)))
+            }
+            Async::Ready(None) => Ok(Async::Ready(None)),
+        }
+    }
 }
 
 /// Transforms request body with the following pipeline:
@@ -33,6 +86,8 @@ pub enum ParseError {
 /// Fails with `error::Error::UnprocessableEntity` if step 1 fails.
 ///
 /// Fails with `error::Error::BadRequest` with message if step 2 fails.
+///
+/// Accepts bodies of any size and content type, see `body_parser::BodyParser` for limits and form bodies.
 pub fn parse_body<T>(body: hyper::Body) -> Box<Future<Item = T, Error = failure::Error>>
 where
     T: for<'a> Deserialize<'a> + 'static,
@@ -69,6 +124,14 @@ pub fn read_body(body: hyper::Body) -> Box<Future<Item = String, Error = hyper::
     )
 }
 
+/// Reads body of request, failing with `BodyTooLarge` without reading the rest once it exceeds `limit` bytes
+pub fn read_body_limited(body: hyper::Body, limit: usize) -> Box<Future<Item = Vec<u8>, Error = failure::Error>> {
+    Box::new(LimitedBody::new(body, limit).fold(Vec::new(), |mut acc, chunk| {
+        acc.extend_from_slice(&*chunk);
+        future::ok::<_, failure::Error>(acc)
+    }))
+}
+
 /// Try reads body of request and response in Future format
 pub fn try_read_body(body: hyper::Body) -> Box<Future<Item = Vec<u8>, Error = hyper::Error> + Send> {
     Box::new(body.fold(Vec::new(), |mut acc, chunk| {